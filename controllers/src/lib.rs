@@ -1,3 +1,4 @@
+mod server_ingest;
 mod session_end;
 mod session_start;
 
@@ -5,7 +6,8 @@ use std::convert::Infallible;
 use warp::{Filter, Rejection, Reply};
 
 use domain::{serde::Serialize, tracing, Service};
-use services::{SessionEndService, SessionStartService};
+use server_ingest::{server_event_filter, server_session_end_filter, server_session_start_filter};
+use services::{ApiKeyAuthService, SessionEndService, SessionEventService, SessionStartService};
 use session_end::session_end_filter;
 use session_start::session_start_filter;
 
@@ -17,25 +19,33 @@ pub(crate) fn warp_service<S: Service + Clone + Send + Sync>(
     warp::any().map(move || service.clone())
 }
 
-pub struct Controllers<SR, VR, UAP, GIR> {
+pub struct Controllers<SR, VR, UAP, GIR, ER, AKR> {
     session_start: SessionStartService<SR, VR, UAP, GIR>,
     session_end: SessionEndService<SR>,
+    session_event: SessionEventService<ER>,
+    api_key_auth: ApiKeyAuthService<AKR>,
 }
 
-impl<SR, VR, UAP, GIR> Controllers<SR, VR, UAP, GIR>
+impl<SR, VR, UAP, GIR, ER, AKR> Controllers<SR, VR, UAP, GIR, ER, AKR>
 where
     SR: domain::SessionsRepository + Clone + Send + Sync + 'static,
     VR: domain::VisitorsRepository + Clone + Send + Sync + 'static,
     UAP: domain::UserAgentParser + Clone + Send + Sync + 'static,
-    GIR: domain::GeoIpReader + Clone + Send + Sync + 'static,
+    GIR: domain::GeoIpReader<Location = domain::Location> + Clone + Send + Sync + 'static,
+    ER: domain::EventsRepository + Clone + Send + Sync + 'static,
+    AKR: domain::ApiKeysRepository + Clone + Send + Sync + 'static,
 {
     pub fn new(
         session_start: SessionStartService<SR, VR, UAP, GIR>,
         session_end: SessionEndService<SR>,
-    ) -> Controllers<SR, VR, UAP, GIR> {
+        session_event: SessionEventService<ER>,
+        api_key_auth: ApiKeyAuthService<AKR>,
+    ) -> Controllers<SR, VR, UAP, GIR, ER, AKR> {
         Controllers {
             session_start,
             session_end,
+            session_event,
+            api_key_auth,
         }
     }
 
@@ -55,6 +65,28 @@ where
             ])
             .allow_credentials(true);
 
+        // Authenticated with a tracking's API key. Only this binary serves
+        // them, the root `trantor` server doesn't mount them.
+        let server_session_start =
+            warp::path!("sessions" / "start")
+                .and(warp::post())
+                .and(server_session_start_filter(
+                    self.api_key_auth.clone(),
+                    self.session_start.clone(),
+                ));
+        let server_session_end =
+            warp::path!("sessions" / "end")
+                .and(warp::post())
+                .and(server_session_end_filter(
+                    self.api_key_auth.clone(),
+                    self.session_end.clone(),
+                ));
+        let server_event = warp::path!("events")
+            .and(warp::post())
+            .and(server_event_filter(self.api_key_auth, self.session_event));
+        let server_routes =
+            warp::path("server").and(server_session_start.or(server_session_end).or(server_event));
+
         let session_start = warp::path("start")
             .and(warp::path::end())
             .and(warp::post())
@@ -67,6 +99,7 @@ where
         let analytics_routes = warp::path("analytics").map(|| "OK");
 
         session_routes
+            .or(server_routes)
             .or(analytics_routes)
             .recover(recover)
             .with(cors)
@@ -97,6 +130,9 @@ async fn recover(err: Rejection) -> Result<impl Reply, Infallible> {
     } else if err.find::<warp::reject::MethodNotAllowed>().is_some() {
         code = StatusCode::METHOD_NOT_ALLOWED;
         message = "METHOD_NOT_ALLOWED".to_owned();
+    } else if err.find::<server_ingest::InvalidAuthorization>().is_some() {
+        code = StatusCode::UNAUTHORIZED;
+        message = "INVALID_AUTHORIZATION".to_owned();
     } else if let Some(err) = err.find::<warp::reject::MissingHeader>() {
         code = StatusCode::BAD_REQUEST;
         message = format!("MISSING_HEADER: {}", err.name());
@@ -0,0 +1,345 @@
+use domain::{
+    serde, ApiKeysRepository, EventProperties, EventsRepository, GeoIpReader, Location, Service,
+    SessionsRepository, UserAgentParser, VisitorsRepository,
+};
+use services::{
+    ApiKeyAuthError, ApiKeyAuthRequest, ApiKeyAuthService, SessionEndRequest, SessionEndService,
+    SessionEventError, SessionEventRequest, SessionEventService, SessionStartRequest,
+    SessionStartResponse, SessionStartService,
+};
+
+use crate::warp_service;
+use std::net::IpAddr;
+use warp::{
+    http::{Response, StatusCode},
+    Filter, Reply,
+};
+
+#[derive(serde::Deserialize)]
+#[serde(crate = "domain::serde")]
+struct ServerSessionStart {
+    visitor_id: Option<String>,
+    ip: IpAddr,
+    user_agent: String,
+    referer: Option<String>,
+    timestamp: f64,
+    title: String,
+    pathname: String,
+    referral: Option<String>,
+    source_name: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(crate = "domain::serde")]
+struct ServerSessionEnd {
+    session_id: String,
+    timestamp: f64,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(crate = "domain::serde")]
+struct ServerEvent {
+    session_id: String,
+    #[serde(rename = "type")]
+    event_type: String,
+    target: String,
+    properties: Option<EventProperties>,
+}
+
+#[derive(serde::Serialize)]
+#[serde(crate = "domain::serde")]
+struct ServerSessionStarted<'a> {
+    visitor_id: &'a str,
+    session_id: &'a str,
+}
+
+#[derive(Debug)]
+pub(crate) struct InvalidAuthorization;
+impl warp::reject::Reject for InvalidAuthorization {}
+
+fn extract_api_key(
+) -> impl warp::Filter<Extract = (ApiKeyAuthRequest,), Error = warp::Rejection> + Clone {
+    warp::header::<String>("authorization").and_then(|authorization: String| async move {
+        match authorization.strip_prefix("Bearer ") {
+            Some(api_key) if !api_key.is_empty() => Ok(ApiKeyAuthRequest::new(api_key.to_owned())),
+            _ => Err(warp::reject::custom(InvalidAuthorization)),
+        }
+    })
+}
+
+async fn authenticate<AKR>(
+    auth: &ApiKeyAuthService<AKR>,
+    request: ApiKeyAuthRequest,
+) -> Result<String, warp::reply::Response>
+where
+    AKR: ApiKeysRepository + Clone + Send + Sync,
+{
+    auth.execute(request)
+        .await
+        .map(|resp| resp.tracking_id().to_owned())
+        .map_err(make_api_key_error_response)
+}
+
+pub(crate) fn server_session_start_filter<AKR, SR, VR, UAP, GIR>(
+    auth: ApiKeyAuthService<AKR>,
+    service: SessionStartService<SR, VR, UAP, GIR>,
+) -> impl warp::Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone
+where
+    AKR: ApiKeysRepository + Clone + Send + Sync,
+    SR: SessionsRepository + Clone + Send + Sync,
+    VR: VisitorsRepository + Clone + Send + Sync,
+    UAP: UserAgentParser + Clone + Send + Sync,
+    GIR: GeoIpReader<Location = Location> + Clone + Send + Sync,
+{
+    warp_service(auth)
+        .and(warp_service(service))
+        .and(extract_api_key())
+        .and(warp::body::json::<ServerSessionStart>())
+        .and_then(server_session_start_handler)
+}
+
+async fn server_session_start_handler<AKR, SR, VR, UAP, GIR>(
+    auth: ApiKeyAuthService<AKR>,
+    service: SessionStartService<SR, VR, UAP, GIR>,
+    api_key: ApiKeyAuthRequest,
+    body: ServerSessionStart,
+) -> Result<warp::reply::Response, std::convert::Infallible>
+where
+    AKR: ApiKeysRepository + Clone + Send + Sync,
+    SR: SessionsRepository + Clone + Send + Sync,
+    VR: VisitorsRepository + Clone + Send + Sync,
+    UAP: UserAgentParser + Clone + Send + Sync,
+    GIR: GeoIpReader<Location = Location> + Clone + Send + Sync,
+{
+    let tracking_id = match authenticate(&auth, api_key).await {
+        Ok(tracking_id) => tracking_id,
+        Err(resp) => return Ok(resp),
+    };
+
+    let request = SessionStartRequest::new(
+        tracking_id,
+        body.visitor_id,
+        body.ip,
+        body.user_agent,
+        body.referer.unwrap_or_default(),
+        body.timestamp,
+        body.title,
+        body.pathname,
+        body.referral,
+        body.source_name,
+    );
+
+    Ok(match service.execute(request).await {
+        Ok(resp) => make_server_session_start_response(resp),
+        Err(_) => make_server_error_response(),
+    })
+}
+
+pub(crate) fn server_session_end_filter<AKR, SR>(
+    auth: ApiKeyAuthService<AKR>,
+    service: SessionEndService<SR>,
+) -> impl warp::Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone
+where
+    AKR: ApiKeysRepository + Clone + Send + Sync,
+    SR: SessionsRepository + Clone + Send + Sync,
+{
+    warp_service(auth)
+        .and(warp_service(service))
+        .and(extract_api_key())
+        .and(warp::body::json::<ServerSessionEnd>())
+        .and_then(server_session_end_handler)
+}
+
+async fn server_session_end_handler<AKR, SR>(
+    auth: ApiKeyAuthService<AKR>,
+    service: SessionEndService<SR>,
+    api_key: ApiKeyAuthRequest,
+    body: ServerSessionEnd,
+) -> Result<warp::reply::Response, std::convert::Infallible>
+where
+    AKR: ApiKeysRepository + Clone + Send + Sync,
+    SR: SessionsRepository + Clone + Send + Sync,
+{
+    let tracking_id = match authenticate(&auth, api_key).await {
+        Ok(tracking_id) => tracking_id,
+        Err(resp) => return Ok(resp),
+    };
+
+    let request = SessionEndRequest::new(tracking_id, body.session_id, body.timestamp);
+
+    Ok(match service.execute(request).await {
+        Ok(_) => make_server_empty_response(),
+        Err(_) => make_server_error_response(),
+    })
+}
+
+pub(crate) fn server_event_filter<AKR, ER>(
+    auth: ApiKeyAuthService<AKR>,
+    service: SessionEventService<ER>,
+) -> impl warp::Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone
+where
+    AKR: ApiKeysRepository + Clone + Send + Sync,
+    ER: EventsRepository + Clone + Send + Sync,
+{
+    warp_service(auth)
+        .and(warp_service(service))
+        .and(extract_api_key())
+        .and(warp::body::json::<ServerEvent>())
+        .and_then(server_event_handler)
+}
+
+async fn server_event_handler<AKR, ER>(
+    auth: ApiKeyAuthService<AKR>,
+    service: SessionEventService<ER>,
+    api_key: ApiKeyAuthRequest,
+    body: ServerEvent,
+) -> Result<warp::reply::Response, std::convert::Infallible>
+where
+    AKR: ApiKeysRepository + Clone + Send + Sync,
+    ER: EventsRepository + Clone + Send + Sync,
+{
+    let tracking_id = match authenticate(&auth, api_key).await {
+        Ok(tracking_id) => tracking_id,
+        Err(resp) => return Ok(resp),
+    };
+
+    let request = SessionEventRequest::new(
+        tracking_id,
+        body.session_id,
+        body.event_type,
+        body.target,
+        body.properties,
+    );
+
+    Ok(match service.execute(request).await {
+        Ok(_) => make_server_empty_response(),
+        Err(SessionEventError::InvalidProperties) => make_server_bad_request_response(),
+        Err(_) => make_server_error_response(),
+    })
+}
+
+fn make_server_session_start_response(resp: SessionStartResponse) -> warp::reply::Response {
+    warp::reply::json(&ServerSessionStarted {
+        visitor_id: resp.visitor_id(),
+        session_id: resp.session_id(),
+    })
+    .into_response()
+}
+
+fn make_server_empty_response() -> warp::reply::Response {
+    Response::builder()
+        .status(StatusCode::OK)
+        .body(warp::hyper::Body::empty())
+        .expect("failed to create server ingest response")
+}
+
+fn make_server_bad_request_response() -> warp::reply::Response {
+    Response::builder()
+        .status(StatusCode::BAD_REQUEST)
+        .body(warp::hyper::Body::empty())
+        .expect("failed to create server ingest bad request response")
+}
+
+fn make_server_error_response() -> warp::reply::Response {
+    Response::builder()
+        .status(StatusCode::INTERNAL_SERVER_ERROR)
+        .body(warp::hyper::Body::empty())
+        .expect("failed to create server ingest error response")
+}
+
+fn make_api_key_error_response(err: ApiKeyAuthError) -> warp::reply::Response {
+    let status = match err {
+        ApiKeyAuthError::InvalidApiKey => StatusCode::UNAUTHORIZED,
+        ApiKeyAuthError::ApiKeysRepository(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+
+    Response::builder()
+        .status(status)
+        .body(warp::hyper::Body::empty())
+        .expect("failed to create api key error response")
+}
+
+#[cfg(test)]
+mod tests {
+    use domain::serde_json::json;
+    use pg_repositories::{PgApiKeysRepository, PgEventsRepository};
+    use services::{ApiKeyAuthService, SessionEventService};
+    use sqlx::{Executor, PgPool};
+    use warp::{http::StatusCode, Filter};
+
+    use super::server_event_filter;
+
+    // A tracking with one session, an active key "active-key" and a revoked
+    // key "revoked-key".
+    async fn setup(pool: &PgPool) {
+        pool.execute(
+            r#"
+            INSERT INTO users (id, user_id, secret_code) VALUES (1, '01H4Y0000000000000000USER1', 'secret');
+            INSERT INTO trackings (id, tracking_id, name, owner_id) VALUES (1, '01H4Y000000000000000TRACK1', 'Blog', 1);
+            INSERT INTO visitors (id, visitor_id, referer, user_agent, user_agent_parsed, tracking_id)
+            VALUES (1, '01H4Y0000000000000VISITOR1', '', 'curl/8.0', '{}', 1);
+            INSERT INTO sessions (session_id, visitor_id, start_timestamp, title, pathname, tracking_id)
+            VALUES ('01H4Y0000000000000SESSION1', 1, '2023-06-01 09:15:00', 'Home', '/', 1);
+            INSERT INTO api_keys (key_id, name, key_prefix, key_hash, revoked_at, tracking_id) VALUES
+              ('01H4Y0000000000000APIKEY01', 'backend', 'active', encode(sha256('active-key'), 'hex'), NULL, 1),
+              ('01H4Y0000000000000APIKEY02', 'old backend', 'revoked', encode(sha256('revoked-key'), 'hex'), '2023-06-02 00:00:00', 1);
+        "#,
+        )
+        .await
+        .unwrap();
+    }
+
+    async fn post_event(pool: &PgPool, authorization: &str) -> StatusCode {
+        let filter = server_event_filter(
+            ApiKeyAuthService::new(PgApiKeysRepository::new(pool)),
+            SessionEventService::new(PgEventsRepository::new(pool)),
+        )
+        .recover(crate::recover);
+
+        warp::test::request()
+            .method("POST")
+            .header("authorization", authorization)
+            .json(&json!({
+                "session_id": "01H4Y0000000000000SESSION1",
+                "type": "click",
+                "target": "signup",
+            }))
+            .reply(&filter)
+            .await
+            .status()
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn an_active_key_is_accepted(pool: PgPool) {
+        setup(&pool).await;
+        assert_eq!(post_event(&pool, "Bearer active-key").await, StatusCode::OK);
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn a_key_without_the_bearer_scheme_is_unauthorized(pool: PgPool) {
+        setup(&pool).await;
+        assert_eq!(
+            post_event(&pool, "active-key").await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(post_event(&pool, "Bearer ").await, StatusCode::UNAUTHORIZED);
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn a_revoked_key_is_unauthorized(pool: PgPool) {
+        setup(&pool).await;
+        assert_eq!(
+            post_event(&pool, "Bearer revoked-key").await,
+            StatusCode::UNAUTHORIZED
+        );
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn an_unknown_key_is_unauthorized(pool: PgPool) {
+        setup(&pool).await;
+        assert_eq!(
+            post_event(&pool, "Bearer unknown-key").await,
+            StatusCode::UNAUTHORIZED
+        );
+    }
+}
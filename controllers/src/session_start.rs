@@ -1,5 +1,5 @@
 use domain::{
-    serde, GeoIpReader, Service, SessionsRepository, UserAgentParser, VisitorsRepository,
+    serde, GeoIpReader, Location, Service, SessionsRepository, UserAgentParser, VisitorsRepository,
 };
 use services::{SessionStartError, SessionStartRequest, SessionStartResponse, SessionStartService};
 
@@ -64,7 +64,7 @@ where
     SR: SessionsRepository + Clone + Send + Sync,
     VR: VisitorsRepository + Clone + Send + Sync,
     UAP: UserAgentParser + Clone + Send + Sync,
-    GIR: GeoIpReader + Clone + Send + Sync,
+    GIR: GeoIpReader<Location = Location> + Clone + Send + Sync,
 {
     warp_service(service)
         .and(extract_session_start_request())
@@ -79,7 +79,7 @@ where
     SR: SessionsRepository + Clone + Send + Sync,
     VR: VisitorsRepository + Clone + Send + Sync,
     UAP: UserAgentParser + Clone + Send + Sync,
-    GIR: GeoIpReader + Clone + Send + Sync,
+    GIR: GeoIpReader<Location = Location> + Clone + Send + Sync,
 {
     Ok(match service.execute(request).await {
         Ok(resp) => make_start_session_response(resp),
@@ -126,6 +126,7 @@ class LaunchControl {
       credentials: "include",
       headers: {
         "Content-Type": "application/json",
+        "x-tracking-id": this.trackingId,
       },
       body: JSON.stringify({
         timestamp: Date.now() / 1000, // seconds
@@ -161,9 +162,22 @@ class LaunchControl {
    * Sends a click event to the server.
    * @public
    * @param {string} buttonLabel - The label of the clicked button.
+   * @param {Object<string, string|number|boolean>} [properties] - Extra properties of the event.
+   * @returns {Promise<void>}
+   */
+  async sendClickEvent(buttonLabel, properties) {
+    await this.sendEvent("click", buttonLabel, properties);
+  }
+
+  /**
+   * Sends a custom event to the server.
+   * @public
+   * @param {string} type - The type of the event, i.e "signup".
+   * @param {string} target - The target of the event.
+   * @param {Object<string, string|number|boolean>} [properties] - Extra properties of the event.
    * @returns {Promise<void>}
    */
-  async sendClickEvent(buttonLabel) {
+  async sendEvent(type, target, properties) {
     await fetch(`${this.serverUrl}/session/event`, {
       method: "POST",
       keepalive: true,
@@ -173,8 +187,35 @@ class LaunchControl {
         "x-tracking-id": this.trackingId,
       },
       body: JSON.stringify({
-        type: "click",
-        target: buttonLabel,
+        type,
+        target,
+        properties,
+      }),
+    });
+  }
+
+  /**
+   * Attaches contact information to the current visitor.
+   * @public
+   * @param {Object} identity - What is known about the visitor.
+   * @param {string} [identity.email] - The email of the visitor.
+   * @param {string} [identity.name] - The name of the visitor.
+   * @param {Object<string, string|number|boolean>} [identity.traits] - Extra traits of the visitor, i.e. `{ plan: "pro" }`.
+   * @returns {Promise<void>}
+   */
+  async identify({ email, name, traits }) {
+    await fetch(`${this.serverUrl}/session/identify`, {
+      method: "POST",
+      keepalive: true,
+      credentials: "include",
+      headers: {
+        "Content-Type": "application/json",
+        "x-tracking-id": this.trackingId,
+      },
+      body: JSON.stringify({
+        email,
+        name,
+        traits,
       }),
     });
   }
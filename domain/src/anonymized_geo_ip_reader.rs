@@ -0,0 +1,93 @@
+use async_trait::async_trait;
+
+use crate::{anonymize_ip, GeoIpReader, GeoIpReaderError, GeoLocation};
+
+/// Wraps a `GeoIpReader` so that it never sees a full IP address when
+/// `anonymize_ip` is on, and never hands out a city when `skip_city` is on.
+#[derive(Clone)]
+pub struct AnonymizedGeoIpReader<GIR> {
+    reader: GIR,
+    anonymize_ip: bool,
+    skip_city: bool,
+}
+
+impl<GIR> AnonymizedGeoIpReader<GIR> {
+    pub fn new(reader: GIR, anonymize_ip: bool, skip_city: bool) -> Self {
+        Self {
+            reader,
+            anonymize_ip,
+            skip_city,
+        }
+    }
+}
+
+#[async_trait]
+impl<GIR> GeoIpReader for AnonymizedGeoIpReader<GIR>
+where
+    GIR: GeoIpReader + Sync + Send,
+{
+    type Location = GIR::Location;
+
+    async fn parse(&self, ip_addr: std::net::IpAddr) -> Result<Self::Location, GeoIpReaderError> {
+        let ip_addr = if self.anonymize_ip {
+            anonymize_ip(ip_addr)
+        } else {
+            ip_addr
+        };
+
+        let location = self.reader.parse(ip_addr).await?;
+        if self.skip_city {
+            Ok(location.without_city())
+        } else {
+            Ok(location)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use async_trait::async_trait;
+
+    use super::AnonymizedGeoIpReader;
+    use crate::{GeoIpReader, GeoIpReaderError, Location};
+
+    // Places every IP in the city named after it.
+    struct EchoReader;
+
+    #[async_trait]
+    impl GeoIpReader for EchoReader {
+        type Location = Location;
+
+        async fn parse(&self, ip_addr: IpAddr) -> Result<Location, GeoIpReaderError> {
+            Ok(Location::new(
+                Some("DE".to_owned()),
+                Some(ip_addr.to_string()),
+                Some("EU".to_owned()),
+            ))
+        }
+    }
+
+    async fn city(anonymize_ip: bool, skip_city: bool) -> Option<String> {
+        let reader = AnonymizedGeoIpReader::new(EchoReader, anonymize_ip, skip_city);
+        let location = reader
+            .parse("203.0.113.195".parse().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(location.country_code(), Some("DE"));
+        assert_eq!(location.continent_code(), Some("EU"));
+        location.city_name().map(str::to_owned)
+    }
+
+    #[tokio::test]
+    async fn the_reader_gets_the_ip_as_configured() {
+        assert_eq!(city(false, false).await.as_deref(), Some("203.0.113.195"));
+        assert_eq!(city(true, false).await.as_deref(), Some("203.0.113.0"));
+    }
+
+    #[tokio::test]
+    async fn the_city_is_dropped_when_skipped() {
+        assert_eq!(city(true, true).await, None);
+    }
+}
@@ -1,3 +1,5 @@
+use crate::GeoLocation;
+
 pub struct Session {
     session_id: String,
     tracking_id: String,
@@ -92,6 +94,52 @@ impl SessionEnd {
     }
 }
 
+pub struct Event {
+    tracking_id: String,
+    session_id: String,
+    event_type: String,
+    target: String,
+    properties: Option<serde_json::Value>,
+}
+
+impl Event {
+    pub fn new(
+        tracking_id: String,
+        session_id: String,
+        event_type: String,
+        target: String,
+        properties: Option<serde_json::Value>,
+    ) -> Self {
+        Self {
+            tracking_id,
+            session_id,
+            event_type,
+            target,
+            properties,
+        }
+    }
+
+    pub fn tracking_id(&self) -> &str {
+        &self.tracking_id
+    }
+
+    pub fn session_id(&self) -> &str {
+        &self.session_id
+    }
+
+    pub fn event_type(&self) -> &str {
+        &self.event_type
+    }
+
+    pub fn target(&self) -> &str {
+        &self.target
+    }
+
+    pub fn properties(&self) -> Option<&serde_json::Value> {
+        self.properties.as_ref()
+    }
+}
+
 pub struct Location {
     country_code: Option<String>,
     city_name: Option<String>,
@@ -124,6 +172,15 @@ impl Location {
     }
 }
 
+impl GeoLocation for Location {
+    fn without_city(self) -> Self {
+        Self {
+            city_name: None,
+            ..self
+        }
+    }
+}
+
 pub struct Visitor {
     visitor_id: String,
     tracking_id: String,
@@ -1,11 +1,15 @@
+mod anonymized_geo_ip_reader;
 mod entities;
 mod ports;
 mod utils;
 
+pub use anonymized_geo_ip_reader::AnonymizedGeoIpReader;
 pub use entities::*;
 pub use ports::*;
+pub use utils::{anonymize_ip, valid_event_properties, EventProperties};
 
 pub use async_trait;
 pub use serde;
+pub use serde_json;
 pub use thiserror;
 pub use tracing;
@@ -1,7 +1,7 @@
 use async_trait::async_trait;
 use thiserror::Error;
 
-use crate::{Location, Session, SessionEnd, UserAgent, Visitor};
+use crate::{Event, Session, SessionEnd, UserAgent, Visitor};
 
 #[async_trait]
 pub trait Service {
@@ -36,6 +36,29 @@ pub enum VisitorRepositoryError {
     Other,
 }
 
+#[async_trait]
+pub trait EventsRepository {
+    async fn create(&self, event: &Event) -> Result<(), EventRepositoryError>;
+}
+
+#[derive(Debug, Error)]
+pub enum EventRepositoryError {
+    #[error("error in events repository")]
+    Other,
+}
+
+#[async_trait]
+pub trait ApiKeysRepository {
+    /// Returns the public id of the tracking an active (not revoked) key belongs to.
+    async fn tracking_id(&self, api_key: &str) -> Result<Option<String>, ApiKeyRepositoryError>;
+}
+
+#[derive(Debug, Error)]
+pub enum ApiKeyRepositoryError {
+    #[error("error in api keys repository")]
+    Other,
+}
+
 #[async_trait]
 pub trait UserAgentParser {
     async fn parse(&self, user_agent: &str) -> Result<UserAgent, UserAgentParserError>;
@@ -49,7 +72,15 @@ pub enum UserAgentParserError {
 
 #[async_trait]
 pub trait GeoIpReader {
-    async fn parse(&self, ip_addr: std::net::IpAddr) -> Result<Location, GeoIpReaderError>;
+    type Location: GeoLocation + Send;
+
+    async fn parse(&self, ip_addr: std::net::IpAddr) -> Result<Self::Location, GeoIpReaderError>;
+}
+
+/// What a `GeoIpReader` finds for an IP.
+pub trait GeoLocation {
+    /// Drops everything more precise than the country and continent.
+    fn without_city(self) -> Self;
 }
 
 #[derive(Debug, Error)]
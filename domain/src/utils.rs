@@ -1,3 +1,115 @@
+use std::net::IpAddr;
+
+pub type EventProperties = serde_json::Map<String, serde_json::Value>;
+
+const MAX_EVENT_PROPERTIES: usize = 32;
+const MAX_PROPERTY_KEY_LENGTH: usize = 64;
+const MAX_PROPERTY_VALUE_LENGTH: usize = 255;
+
 pub(crate) fn generate_id() -> String {
     ulid::Ulid::new().to_string()
 }
+
+/// Truncates an IPv4 address to its /24 and an IPv6 address to its /48, which
+/// is still enough to tell the country apart.
+pub fn anonymize_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            IpAddr::from([a, b, c, 0])
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => anonymize_ip(IpAddr::V4(ip)),
+            None => {
+                let [a, b, c, ..] = ip.segments();
+                IpAddr::from([a, b, c, 0, 0, 0, 0, 0])
+            }
+        },
+    }
+}
+
+/// Properties are a flat object of at most 32 scalars, so that every value can
+/// be grouped by. Keys are at most 64 characters and strings at most 255.
+pub fn valid_event_properties(properties: &EventProperties) -> bool {
+    properties.len() <= MAX_EVENT_PROPERTIES
+        && properties.iter().all(|(key, value)| {
+            let valid_value = match value {
+                serde_json::Value::String(value) => {
+                    value.chars().count() <= MAX_PROPERTY_VALUE_LENGTH
+                }
+                serde_json::Value::Number(_) | serde_json::Value::Bool(_) => true,
+                serde_json::Value::Null
+                | serde_json::Value::Array(_)
+                | serde_json::Value::Object(_) => false,
+            };
+            !key.is_empty() && key.chars().count() <= MAX_PROPERTY_KEY_LENGTH && valid_value
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use serde_json::json;
+
+    use super::{anonymize_ip, valid_event_properties, EventProperties};
+
+    fn properties(value: serde_json::Value) -> EventProperties {
+        value.as_object().unwrap().clone()
+    }
+
+    fn anonymized(ip: &str) -> String {
+        anonymize_ip(ip.parse::<IpAddr>().unwrap()).to_string()
+    }
+
+    #[test]
+    fn ipv4_keeps_its_24() {
+        assert_eq!(anonymized("203.0.113.195"), "203.0.113.0");
+    }
+
+    #[test]
+    fn ipv6_keeps_its_48() {
+        assert_eq!(
+            anonymized("2001:db8:85a3:8d3:1319:8a2e:370:7348"),
+            "2001:db8:85a3::"
+        );
+    }
+
+    #[test]
+    fn mapped_ipv6_is_truncated_as_ipv4() {
+        assert_eq!(anonymized("::ffff:203.0.113.195"), "203.0.113.0");
+    }
+
+    #[test]
+    fn flat_scalars_are_valid_properties() {
+        assert!(valid_event_properties(&properties(
+            json!({ "plan": "pro", "seats": 3, "trial": false })
+        )));
+    }
+
+    #[test]
+    fn nested_and_null_properties_are_invalid() {
+        assert!(!valid_event_properties(&properties(
+            json!({ "plan": { "name": "pro" } })
+        )));
+        assert!(!valid_event_properties(&properties(
+            json!({ "seats": [1, 2] })
+        )));
+        assert!(!valid_event_properties(&properties(
+            json!({ "plan": null })
+        )));
+    }
+
+    #[test]
+    fn oversized_properties_are_invalid() {
+        let many = (0..33).map(|i| (i.to_string(), json!(i))).collect();
+        assert!(!valid_event_properties(&many));
+        assert!(!valid_event_properties(&properties(
+            json!({ "k".repeat(65): 1 })
+        )));
+        assert!(!valid_event_properties(&properties(
+            json!({ "plan": "p".repeat(256) })
+        )));
+        assert!(!valid_event_properties(&properties(json!({ "": 1 }))));
+    }
+}
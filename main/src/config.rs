@@ -6,6 +6,10 @@ pub(crate) struct Config {
     address: std::net::SocketAddr,
     database: String,
     maxminddb: String,
+    #[serde(default)]
+    anonymize_ip: bool,
+    #[serde(default)]
+    skip_city: bool,
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -45,4 +49,12 @@ impl Config {
     pub fn maxminddb_path(&self) -> &str {
         &self.maxminddb
     }
+
+    pub fn anonymize_ip(&self) -> bool {
+        self.anonymize_ip
+    }
+
+    pub fn skip_city(&self) -> bool {
+        self.skip_city
+    }
 }
@@ -4,8 +4,12 @@ use color_eyre::{
 };
 
 use controllers::{warp, Controllers};
-use pg_repositories::{sqlx::PgPool, PgSessionsRepository, PgVisitorsRepository};
-use services::{SessionEndService, SessionStartService};
+use domain::AnonymizedGeoIpReader;
+use pg_repositories::{
+    sqlx::PgPool, PgApiKeysRepository, PgEventsRepository, PgSessionsRepository,
+    PgVisitorsRepository,
+};
+use services::{ApiKeyAuthService, SessionEndService, SessionEventService, SessionStartService};
 
 mod ua_parser;
 use ua_parser::UAParser;
@@ -38,14 +42,27 @@ async fn main() -> Result<()> {
 
     let sessions = PgSessionsRepository::new(&pool);
     let visitors = PgVisitorsRepository::new(&pool);
+    let events = PgEventsRepository::new(&pool);
+    let api_keys = PgApiKeysRepository::new(&pool);
     let user_agent_parser = UAParser::new();
-    let geo_ip_reader = MaxmindGeoIpReader::new(config.maxminddb_path())?;
+    let geo_ip_reader = AnonymizedGeoIpReader::new(
+        MaxmindGeoIpReader::new(config.maxminddb_path())?,
+        config.anonymize_ip(),
+        config.skip_city(),
+    );
 
     let session_start_service =
         SessionStartService::new(sessions.clone(), visitors, user_agent_parser, geo_ip_reader);
     let session_end_service = SessionEndService::new(sessions);
+    let session_event_service = SessionEventService::new(events);
+    let api_key_auth_service = ApiKeyAuthService::new(api_keys);
 
-    let controllers = Controllers::new(session_start_service, session_end_service);
+    let controllers = Controllers::new(
+        session_start_service,
+        session_end_service,
+        session_event_service,
+        api_key_auth_service,
+    );
     let routes = controllers.routes();
 
     warp::serve(routes).run(config.address()).await;
@@ -56,6 +73,6 @@ async fn main() -> Result<()> {
 fn make_config() -> Result<Config> {
     let args: Vec<String> = std::env::args().collect();
     let config_path = &args.get(1).ok_or_else(|| eyre!("missing config path"))?;
-    let config = Config::from_file(&config_path)?;
+    let config = Config::from_file(config_path)?;
     Ok(config)
 }
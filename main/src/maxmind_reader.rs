@@ -19,6 +19,8 @@ impl MaxmindGeoIpReader {
 
 #[async_trait]
 impl GeoIpReader for MaxmindGeoIpReader {
+    type Location = Location;
+
     async fn parse(&self, ip_addr: std::net::IpAddr) -> Result<Location, GeoIpReaderError> {
         let location = self
             .reader
@@ -0,0 +1,2 @@
+ALTER TABLE events
+ADD COLUMN properties JSONB NULL;
\ No newline at end of file
@@ -0,0 +1,13 @@
+CREATE TABLE IF NOT EXISTS imported_stats (
+  id SERIAL PRIMARY KEY,
+  date DATE NOT NULL,
+  dimension VARCHAR(16) NOT NULL,
+  value TEXT NOT NULL,
+  visitors BIGINT NOT NULL DEFAULT 0,
+  sessions BIGINT NOT NULL DEFAULT 0,
+  page_views BIGINT NOT NULL DEFAULT 0,
+  imported_from VARCHAR(16) NOT NULL,
+  imported_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  tracking_id INTEGER NOT NULL REFERENCES trackings(id) ON DELETE CASCADE,
+  UNIQUE (tracking_id, dimension, value, date)
+);
\ No newline at end of file
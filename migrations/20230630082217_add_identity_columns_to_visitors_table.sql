@@ -0,0 +1,5 @@
+ALTER TABLE visitors
+ADD COLUMN email VARCHAR(320) NULL,
+  ADD COLUMN name VARCHAR(255) NULL,
+  ADD COLUMN traits JSONB NULL,
+  ADD COLUMN identified_at TIMESTAMP NULL;
\ No newline at end of file
@@ -0,0 +1,9 @@
+CREATE TABLE IF NOT EXISTS audit_log (
+  id SERIAL PRIMARY KEY,
+  action VARCHAR(64) NOT NULL,
+  subject_hash CHAR(64) NOT NULL,
+  details JSONB NULL,
+  created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  user_id INTEGER NOT NULL REFERENCES users(id),
+  tracking_id INTEGER NOT NULL
+);
\ No newline at end of file
@@ -0,0 +1,8 @@
+ALTER TABLE trackings
+ADD COLUMN cookieless BOOLEAN NOT NULL DEFAULT FALSE;
+
+CREATE TABLE IF NOT EXISTS daily_salts (
+  day DATE PRIMARY KEY,
+  salt CHAR(64) NOT NULL,
+  created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
\ No newline at end of file
@@ -0,0 +1,12 @@
+ALTER TABLE trackings
+ADD COLUMN opt_out_policy TEXT NOT NULL DEFAULT 'ignore' CHECK (opt_out_policy IN ('ignore', 'drop', 'anonymous'));
+
+CREATE TABLE IF NOT EXISTS opted_out_hits (
+  id SERIAL PRIMARY KEY,
+  date DATE NOT NULL,
+  pathname TEXT NOT NULL DEFAULT '',
+  suppressed BIGINT NOT NULL DEFAULT 0,
+  page_views BIGINT NOT NULL DEFAULT 0,
+  tracking_id INTEGER NOT NULL REFERENCES trackings(id) ON DELETE CASCADE,
+  UNIQUE (tracking_id, date, pathname)
+);
\ No newline at end of file
@@ -0,0 +1,3 @@
+ALTER TABLE trackings
+ADD COLUMN retention_days INTEGER NULL CHECK (retention_days > 0),
+ADD COLUMN retention_fold BOOLEAN NOT NULL DEFAULT TRUE;
\ No newline at end of file
@@ -0,0 +1,107 @@
+-- Hourly session and visitor counts per tracking and dimension, bucketed by
+-- the tracking's local time. Missing countries and referrals are kept as ''.
+CREATE TABLE IF NOT EXISTS rollups (
+  id SERIAL PRIMARY KEY,
+  hour TIMESTAMP NOT NULL,
+  dimension VARCHAR(16) NOT NULL,
+  value TEXT NOT NULL,
+  label TEXT NULL,
+  visitors BIGINT NOT NULL DEFAULT 0,
+  sessions BIGINT NOT NULL DEFAULT 0,
+  tracking_id INTEGER NOT NULL REFERENCES trackings(id) ON DELETE CASCADE,
+  UNIQUE (tracking_id, dimension, value, hour)
+);
+
+CREATE OR REPLACE FUNCTION rollup_hour(tracking INTEGER, instant TIMESTAMP) RETURNS TIMESTAMP AS $$
+  SELECT DATE_TRUNC('hour', instant AT TIME ZONE 'UTC' AT TIME ZONE trackings.timezone)
+  FROM trackings WHERE trackings.id = tracking
+$$ LANGUAGE SQL STABLE;
+
+CREATE OR REPLACE FUNCTION rebuild_rollups(tracking INTEGER) RETURNS VOID AS $$
+BEGIN
+  DELETE FROM rollups WHERE tracking_id = tracking;
+
+  INSERT INTO rollups (tracking_id, hour, dimension, value, label, sessions)
+  SELECT tracking, rollup_hour(tracking, start_timestamp), dimension, value, MAX(label), COUNT(*)
+  FROM sessions, LATERAL (VALUES
+    ('path', pathname, NULL),
+    ('country', COALESCE(location->'country'->>'iso_code', ''), location->'country'->'names'->>'en'),
+    ('referral', COALESCE(referral, ''), NULL)
+  ) AS dimensions (dimension, value, label)
+  WHERE sessions.tracking_id = tracking
+  GROUP BY 2, 3, 4;
+
+  INSERT INTO rollups (tracking_id, hour, dimension, value, visitors)
+  SELECT tracking, rollup_hour(tracking, created_at), 'device', user_agent_parsed->'device'->>'family', COUNT(*)
+  FROM visitors
+  WHERE visitors.tracking_id = tracking AND user_agent_parsed->'device'->>'family' IS NOT NULL
+  GROUP BY 2, 3, 4;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION rollup_sessions() RETURNS TRIGGER AS $$
+BEGIN
+  IF TG_OP = 'INSERT' THEN
+    INSERT INTO rollups (tracking_id, hour, dimension, value, label, sessions)
+    SELECT NEW.tracking_id, rollup_hour(NEW.tracking_id, NEW.start_timestamp), dimension, value, label, 1
+    FROM (VALUES
+      ('path', NEW.pathname, NULL),
+      ('country', COALESCE(NEW.location->'country'->>'iso_code', ''), NEW.location->'country'->'names'->>'en'),
+      ('referral', COALESCE(NEW.referral, ''), NULL)
+    ) AS dimensions (dimension, value, label)
+    ON CONFLICT (tracking_id, dimension, value, hour) DO UPDATE
+    SET sessions = rollups.sessions + 1,
+        label = COALESCE(EXCLUDED.label, rollups.label);
+    RETURN NEW;
+  END IF;
+
+  -- Only ever decremented, so that deleting a tracking can cascade.
+  UPDATE rollups SET sessions = sessions - 1
+  WHERE tracking_id = OLD.tracking_id
+    AND hour = rollup_hour(OLD.tracking_id, OLD.start_timestamp)
+    AND (dimension, value) IN (
+      ('path', OLD.pathname),
+      ('country', COALESCE(OLD.location->'country'->>'iso_code', '')),
+      ('referral', COALESCE(OLD.referral, ''))
+    );
+  RETURN OLD;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION rollup_visitors() RETURNS TRIGGER AS $$
+BEGIN
+  IF TG_OP = 'INSERT' THEN
+    INSERT INTO rollups (tracking_id, hour, dimension, value, visitors)
+    SELECT NEW.tracking_id, rollup_hour(NEW.tracking_id, NEW.created_at), 'device', NEW.user_agent_parsed->'device'->>'family', 1
+    WHERE NEW.user_agent_parsed->'device'->>'family' IS NOT NULL
+    ON CONFLICT (tracking_id, dimension, value, hour) DO UPDATE
+    SET visitors = rollups.visitors + 1;
+    RETURN NEW;
+  END IF;
+
+  UPDATE rollups SET visitors = visitors - 1
+  WHERE tracking_id = OLD.tracking_id
+    AND hour = rollup_hour(OLD.tracking_id, OLD.created_at)
+    AND dimension = 'device'
+    AND value = OLD.user_agent_parsed->'device'->>'family';
+  RETURN OLD;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION rollup_timezone() RETURNS TRIGGER AS $$
+BEGIN
+  PERFORM rebuild_rollups(NEW.id);
+  RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER sessions_rollup AFTER INSERT OR DELETE ON sessions
+FOR EACH ROW EXECUTE FUNCTION rollup_sessions();
+
+CREATE TRIGGER visitors_rollup AFTER INSERT OR DELETE ON visitors
+FOR EACH ROW EXECUTE FUNCTION rollup_visitors();
+
+CREATE TRIGGER trackings_rollup AFTER UPDATE OF timezone ON trackings
+FOR EACH ROW WHEN (OLD.timezone IS DISTINCT FROM NEW.timezone) EXECUTE FUNCTION rollup_timezone();
+
+SELECT rebuild_rollups(id) FROM trackings;
\ No newline at end of file
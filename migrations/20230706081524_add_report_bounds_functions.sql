@@ -0,0 +1,28 @@
+-- The local days and UTC instants a report covers. Without a range it covers
+-- the last 30 days of the tracking's local time.
+CREATE OR REPLACE FUNCTION report_bounds(tracking INTEGER, from_day DATE, to_day DATE)
+RETURNS TABLE (first_day DATE, after_last_day DATE, first_instant TIMESTAMP, after_last_instant TIMESTAMP, timezone TEXT) AS $$
+  SELECT local_days.first_day,
+    local_days.after_last_day,
+    (local_days.first_day::TIMESTAMP AT TIME ZONE trackings.timezone) AT TIME ZONE 'UTC',
+    (local_days.after_last_day::TIMESTAMP AT TIME ZONE trackings.timezone) AT TIME ZONE 'UTC',
+    trackings.timezone::TEXT
+  FROM trackings, LATERAL (
+    SELECT COALESCE(from_day, COALESCE(to_day, today) - 29) AS first_day,
+      COALESCE(to_day, today) + 1 AS after_last_day
+    FROM (SELECT (CURRENT_TIMESTAMP AT TIME ZONE trackings.timezone)::DATE AS today) local_today
+  ) local_days
+  WHERE trackings.id = tracking
+$$ LANGUAGE SQL STABLE;
+
+-- Every `unit` ('day', 'week' or 'month') bucket of a report, so that the
+-- ones without any sessions are still listed.
+CREATE OR REPLACE FUNCTION report_buckets(tracking INTEGER, from_day DATE, to_day DATE, unit TEXT)
+RETURNS SETOF TIMESTAMP AS $$
+  SELECT GENERATE_SERIES(
+    DATE_TRUNC(unit, bounds.first_day::TIMESTAMP),
+    DATE_TRUNC(unit, bounds.after_last_day::TIMESTAMP - INTERVAL '1 second'),
+    ('1 ' || unit)::INTERVAL
+  )
+  FROM report_bounds(tracking, from_day, to_day) bounds
+$$ LANGUAGE SQL STABLE;
\ No newline at end of file
@@ -0,0 +1,25 @@
+-- Whether a visitor or a session is part of a report's segment. A filter that
+-- is NULL matches everything.
+CREATE OR REPLACE FUNCTION visitor_matches(visitor visitors, device TEXT, os TEXT, browser TEXT, source TEXT, referer_host TEXT)
+RETURNS BOOLEAN AS $$
+  SELECT (device IS NULL OR visitor.user_agent_parsed->'device'->>'family' = device)
+    AND (os IS NULL OR visitor.user_agent_parsed->'os'->>'family' = os)
+    AND (browser IS NULL OR visitor.user_agent_parsed->'user_agent'->>'family' = browser)
+    AND (source IS NULL OR visitor.source_id = (SELECT id FROM sources WHERE sources.tracking_id = visitor.tracking_id AND sources.name = source))
+    AND (referer_host IS NULL OR LOWER(SUBSTRING(visitor.referer FROM '^[a-zA-Z][a-zA-Z0-9+.-]*://([^/?#:]+)')) = LOWER(referer_host))
+$$ LANGUAGE SQL STABLE;
+
+CREATE OR REPLACE FUNCTION session_matches(session sessions, country TEXT, referral TEXT, pathname TEXT)
+RETURNS BOOLEAN AS $$
+  SELECT (country IS NULL OR session.location->'country'->>'iso_code' = UPPER(country))
+    AND (referral IS NULL OR session.referral = referral)
+    AND (pathname IS NULL OR session.pathname = pathname)
+$$ LANGUAGE SQL STABLE;
+
+CREATE OR REPLACE FUNCTION segment_matches(
+  session sessions, visitor visitors,
+  country TEXT, device TEXT, os TEXT, browser TEXT, source TEXT, referral TEXT, referer_host TEXT, pathname TEXT
+) RETURNS BOOLEAN AS $$
+  SELECT session_matches(session, country, referral, pathname)
+    AND visitor_matches(visitor, device, os, browser, source, referer_host)
+$$ LANGUAGE SQL STABLE;
\ No newline at end of file
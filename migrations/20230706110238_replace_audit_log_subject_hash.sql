@@ -0,0 +1,9 @@
+-- The audit log keeps the ids of the visitors a request matched rather than a
+-- hash of what was asked for, since an email's hash can be guessed back. The
+-- entries of a deleted user are kept.
+ALTER TABLE audit_log
+DROP COLUMN subject_hash,
+ADD COLUMN visitor_ids TEXT[] NOT NULL DEFAULT '{}',
+ALTER COLUMN user_id DROP NOT NULL,
+DROP CONSTRAINT audit_log_user_id_fkey,
+ADD CONSTRAINT audit_log_user_id_fkey FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE SET NULL;
\ No newline at end of file
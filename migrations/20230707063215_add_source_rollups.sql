@@ -0,0 +1,98 @@
+-- Visitors and sessions per source, kept as value '' when there is none, so
+-- that the sources list and the weekday and hour histograms can be summed
+-- from `rollups` too. A visitor's source never changes, so a session's is
+-- looked up from its visitor.
+CREATE OR REPLACE FUNCTION rebuild_rollups(tracking INTEGER) RETURNS VOID AS $$
+BEGIN
+  DELETE FROM rollups WHERE tracking_id = tracking;
+
+  INSERT INTO rollups (tracking_id, hour, dimension, value, label, sessions)
+  SELECT tracking, rollup_hour(tracking, start_timestamp), dimension, value, MAX(label), COUNT(*)
+  FROM sessions JOIN visitors ON visitors.id = sessions.visitor_id, LATERAL (VALUES
+    ('path', pathname, NULL),
+    ('country', COALESCE(location->'country'->>'iso_code', ''), location->'country'->'names'->>'en'),
+    ('referral', COALESCE(referral, ''), NULL),
+    ('source', COALESCE(visitors.source_id::TEXT, ''), NULL)
+  ) AS dimensions (dimension, value, label)
+  WHERE sessions.tracking_id = tracking
+  GROUP BY 2, 3, 4;
+
+  INSERT INTO rollups (tracking_id, hour, dimension, value, visitors)
+  SELECT tracking, rollup_hour(tracking, created_at), dimension, value, COUNT(*)
+  FROM visitors, LATERAL (VALUES
+    ('device', user_agent_parsed->'device'->>'family'),
+    ('source', COALESCE(source_id::TEXT, ''))
+  ) AS dimensions (dimension, value)
+  WHERE visitors.tracking_id = tracking AND value IS NOT NULL
+  GROUP BY 2, 3, 4
+  ON CONFLICT (tracking_id, dimension, value, hour) DO UPDATE
+  SET visitors = EXCLUDED.visitors;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION rollup_sessions() RETURNS TRIGGER AS $$
+DECLARE
+  visitor_source TEXT;
+BEGIN
+  IF TG_OP = 'INSERT' THEN
+    SELECT COALESCE(source_id::TEXT, '') INTO visitor_source FROM visitors WHERE id = NEW.visitor_id;
+
+    INSERT INTO rollups (tracking_id, hour, dimension, value, label, sessions)
+    SELECT NEW.tracking_id, rollup_hour(NEW.tracking_id, NEW.start_timestamp), dimension, value, label, 1
+    FROM (VALUES
+      ('path', NEW.pathname, NULL),
+      ('country', COALESCE(NEW.location->'country'->>'iso_code', ''), NEW.location->'country'->'names'->>'en'),
+      ('referral', COALESCE(NEW.referral, ''), NULL),
+      ('source', visitor_source, NULL)
+    ) AS dimensions (dimension, value, label)
+    ON CONFLICT (tracking_id, dimension, value, hour) DO UPDATE
+    SET sessions = rollups.sessions + 1,
+        label = COALESCE(EXCLUDED.label, rollups.label);
+    RETURN NEW;
+  END IF;
+
+  -- The visitor is already gone when its deletion cascades, in which case
+  -- there is no source left to count the session against either.
+  SELECT COALESCE(source_id::TEXT, '') INTO visitor_source FROM visitors WHERE id = OLD.visitor_id;
+
+  -- Only ever decremented, so that deleting a tracking can cascade.
+  UPDATE rollups SET sessions = sessions - 1
+  WHERE tracking_id = OLD.tracking_id
+    AND hour = rollup_hour(OLD.tracking_id, OLD.start_timestamp)
+    AND (dimension, value) IN (
+      ('path', OLD.pathname),
+      ('country', COALESCE(OLD.location->'country'->>'iso_code', '')),
+      ('referral', COALESCE(OLD.referral, '')),
+      ('source', visitor_source)
+    );
+  RETURN OLD;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION rollup_visitors() RETURNS TRIGGER AS $$
+BEGIN
+  IF TG_OP = 'INSERT' THEN
+    INSERT INTO rollups (tracking_id, hour, dimension, value, visitors)
+    SELECT NEW.tracking_id, rollup_hour(NEW.tracking_id, NEW.created_at), dimension, value, 1
+    FROM (VALUES
+      ('device', NEW.user_agent_parsed->'device'->>'family'),
+      ('source', COALESCE(NEW.source_id::TEXT, ''))
+    ) AS dimensions (dimension, value)
+    WHERE value IS NOT NULL
+    ON CONFLICT (tracking_id, dimension, value, hour) DO UPDATE
+    SET visitors = rollups.visitors + 1;
+    RETURN NEW;
+  END IF;
+
+  UPDATE rollups SET visitors = visitors - 1
+  WHERE tracking_id = OLD.tracking_id
+    AND hour = rollup_hour(OLD.tracking_id, OLD.created_at)
+    AND (dimension, value) IN (
+      ('device', OLD.user_agent_parsed->'device'->>'family'),
+      ('source', COALESCE(OLD.source_id::TEXT, ''))
+    );
+  RETURN OLD;
+END;
+$$ LANGUAGE plpgsql;
+
+SELECT rebuild_rollups(id) FROM trackings;
\ No newline at end of file
@@ -0,0 +1,3 @@
+-- Lets a visitor's first session be looked up without scanning every session
+-- of the tracking.
+CREATE INDEX IF NOT EXISTS sessions_visitor_id_start_timestamp ON sessions (visitor_id, start_timestamp);
\ No newline at end of file
@@ -0,0 +1,78 @@
+-- Sessions keep their visitor's source, so that the source rollup can still be
+-- decremented once the visitor is gone, as when deleting a source cascades to
+-- its visitors and from them to their sessions.
+ALTER TABLE sessions ADD COLUMN IF NOT EXISTS source_id INTEGER NULL;
+
+UPDATE sessions SET source_id = visitors.source_id
+FROM visitors WHERE visitors.id = sessions.visitor_id;
+
+CREATE OR REPLACE FUNCTION session_source() RETURNS TRIGGER AS $$
+BEGIN
+  SELECT source_id INTO NEW.source_id FROM visitors WHERE id = NEW.visitor_id;
+  RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER sessions_source BEFORE INSERT ON sessions
+FOR EACH ROW EXECUTE FUNCTION session_source();
+
+CREATE OR REPLACE FUNCTION rebuild_rollups(tracking INTEGER) RETURNS VOID AS $$
+BEGIN
+  DELETE FROM rollups WHERE tracking_id = tracking;
+
+  INSERT INTO rollups (tracking_id, hour, dimension, value, label, sessions)
+  SELECT tracking, rollup_hour(tracking, start_timestamp), dimension, value, MAX(label), COUNT(*)
+  FROM sessions, LATERAL (VALUES
+    ('path', pathname, NULL),
+    ('country', COALESCE(location->'country'->>'iso_code', ''), location->'country'->'names'->>'en'),
+    ('referral', COALESCE(referral, ''), NULL),
+    ('source', COALESCE(source_id::TEXT, ''), NULL)
+  ) AS dimensions (dimension, value, label)
+  WHERE sessions.tracking_id = tracking
+  GROUP BY 2, 3, 4;
+
+  INSERT INTO rollups (tracking_id, hour, dimension, value, visitors)
+  SELECT tracking, rollup_hour(tracking, created_at), dimension, value, COUNT(*)
+  FROM visitors, LATERAL (VALUES
+    ('device', user_agent_parsed->'device'->>'family'),
+    ('source', COALESCE(source_id::TEXT, ''))
+  ) AS dimensions (dimension, value)
+  WHERE visitors.tracking_id = tracking AND value IS NOT NULL
+  GROUP BY 2, 3, 4
+  ON CONFLICT (tracking_id, dimension, value, hour) DO UPDATE
+  SET visitors = EXCLUDED.visitors;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION rollup_sessions() RETURNS TRIGGER AS $$
+BEGIN
+  IF TG_OP = 'INSERT' THEN
+    INSERT INTO rollups (tracking_id, hour, dimension, value, label, sessions)
+    SELECT NEW.tracking_id, rollup_hour(NEW.tracking_id, NEW.start_timestamp), dimension, value, label, 1
+    FROM (VALUES
+      ('path', NEW.pathname, NULL),
+      ('country', COALESCE(NEW.location->'country'->>'iso_code', ''), NEW.location->'country'->'names'->>'en'),
+      ('referral', COALESCE(NEW.referral, ''), NULL),
+      ('source', COALESCE(NEW.source_id::TEXT, ''), NULL)
+    ) AS dimensions (dimension, value, label)
+    ON CONFLICT (tracking_id, dimension, value, hour) DO UPDATE
+    SET sessions = rollups.sessions + 1,
+        label = COALESCE(EXCLUDED.label, rollups.label);
+    RETURN NEW;
+  END IF;
+
+  -- Only ever decremented, so that deleting a tracking can cascade.
+  UPDATE rollups SET sessions = sessions - 1
+  WHERE tracking_id = OLD.tracking_id
+    AND hour = rollup_hour(OLD.tracking_id, OLD.start_timestamp)
+    AND (dimension, value) IN (
+      ('path', OLD.pathname),
+      ('country', COALESCE(OLD.location->'country'->>'iso_code', '')),
+      ('referral', COALESCE(OLD.referral, '')),
+      ('source', COALESCE(OLD.source_id::TEXT, ''))
+    );
+  RETURN OLD;
+END;
+$$ LANGUAGE plpgsql;
+
+SELECT rebuild_rollups(id) FROM trackings;
\ No newline at end of file
@@ -0,0 +1,141 @@
+-- A purge that folds its sessions and visitors into `imported_stats` leaves
+-- their rollups in place, so the breakdowns summed from `rollups` keep their
+-- history. The purge sets `trantor.keep_rollups` for its own transaction.
+ALTER TABLE imported_stats ADD COLUMN IF NOT EXISTS events BIGINT NOT NULL DEFAULT 0;
+
+-- The rollups the sessions and visitors still stored would give in a timezone.
+CREATE OR REPLACE FUNCTION live_rollups(tracking INTEGER, zone TEXT)
+RETURNS TABLE (hour TIMESTAMP, dimension VARCHAR(16), value TEXT, label TEXT, visitors BIGINT, sessions BIGINT) AS $$
+  SELECT hour, dimension, value, MAX(label), SUM(visitors)::BIGINT, SUM(sessions)::BIGINT
+  FROM (
+    SELECT DATE_TRUNC('hour', start_timestamp AT TIME ZONE 'UTC' AT TIME ZONE zone) AS hour, dimension, value, label, 0 AS visitors, 1 AS sessions
+    FROM sessions, LATERAL (VALUES
+      ('path', pathname, NULL),
+      ('country', COALESCE(location->'country'->>'iso_code', ''), location->'country'->'names'->>'en'),
+      ('referral', COALESCE(referral, ''), NULL),
+      ('source', COALESCE(source_id::TEXT, ''), NULL)
+    ) AS dimensions (dimension, value, label)
+    WHERE sessions.tracking_id = tracking
+    UNION ALL
+    SELECT DATE_TRUNC('hour', created_at AT TIME ZONE 'UTC' AT TIME ZONE zone), dimension, value, NULL, 1, 0
+    FROM visitors, LATERAL (VALUES
+      ('device', user_agent_parsed->'device'->>'family'),
+      ('source', COALESCE(source_id::TEXT, ''))
+    ) AS dimensions (dimension, value)
+    WHERE visitors.tracking_id = tracking AND value IS NOT NULL
+  ) contributions
+  GROUP BY 1, 2, 3
+$$ LANGUAGE SQL STABLE;
+
+-- Starts over from the stored sessions and visitors, so the history of folded
+-- purges is lost.
+CREATE OR REPLACE FUNCTION rebuild_rollups(tracking INTEGER) RETURNS VOID AS $$
+BEGIN
+  DELETE FROM rollups WHERE tracking_id = tracking;
+
+  INSERT INTO rollups (tracking_id, hour, dimension, value, label, visitors, sessions)
+  SELECT tracking, live.hour, live.dimension, live.value, live.label, live.visitors, live.sessions
+  FROM live_rollups(tracking, (SELECT timezone FROM trackings WHERE id = tracking)) AS live;
+END;
+$$ LANGUAGE plpgsql;
+
+-- Whatever the stored sessions and visitors don't account for is left from
+-- folded purges. Those counts can only be moved to the hour they fall in under
+-- the new timezone, while the stored ones are counted again.
+CREATE OR REPLACE FUNCTION rollup_timezone() RETURNS TRIGGER AS $$
+DECLARE
+  purged JSONB;
+BEGIN
+  SELECT jsonb_agg(residuals) INTO purged
+  FROM (
+    SELECT DATE_TRUNC('hour', rollups.hour AT TIME ZONE OLD.timezone AT TIME ZONE NEW.timezone) AS hour,
+      rollups.dimension, rollups.value, MAX(rollups.label) AS label,
+      SUM(rollups.visitors - COALESCE(live.visitors, 0)) AS visitors,
+      SUM(rollups.sessions - COALESCE(live.sessions, 0)) AS sessions
+    FROM rollups
+      LEFT JOIN live_rollups(NEW.id, OLD.timezone) AS live
+        ON live.hour = rollups.hour AND live.dimension = rollups.dimension AND live.value = rollups.value
+    WHERE rollups.tracking_id = NEW.id
+    GROUP BY 1, 2, 3
+    HAVING SUM(rollups.visitors - COALESCE(live.visitors, 0)) <> 0
+      OR SUM(rollups.sessions - COALESCE(live.sessions, 0)) <> 0
+  ) residuals;
+
+  PERFORM rebuild_rollups(NEW.id);
+
+  INSERT INTO rollups (tracking_id, hour, dimension, value, label, visitors, sessions)
+  SELECT NEW.id, hour, dimension, value, label, visitors, sessions
+  FROM jsonb_to_recordset(COALESCE(purged, '[]'))
+    AS residuals (hour TIMESTAMP, dimension VARCHAR(16), value TEXT, label TEXT, visitors BIGINT, sessions BIGINT)
+  ON CONFLICT (tracking_id, dimension, value, hour) DO UPDATE
+  SET visitors = rollups.visitors + EXCLUDED.visitors,
+      sessions = rollups.sessions + EXCLUDED.sessions,
+      label = COALESCE(rollups.label, EXCLUDED.label);
+  RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION rollup_sessions() RETURNS TRIGGER AS $$
+BEGIN
+  IF TG_OP = 'INSERT' THEN
+    INSERT INTO rollups (tracking_id, hour, dimension, value, label, sessions)
+    SELECT NEW.tracking_id, rollup_hour(NEW.tracking_id, NEW.start_timestamp), dimension, value, label, 1
+    FROM (VALUES
+      ('path', NEW.pathname, NULL),
+      ('country', COALESCE(NEW.location->'country'->>'iso_code', ''), NEW.location->'country'->'names'->>'en'),
+      ('referral', COALESCE(NEW.referral, ''), NULL),
+      ('source', COALESCE(NEW.source_id::TEXT, ''), NULL)
+    ) AS dimensions (dimension, value, label)
+    ON CONFLICT (tracking_id, dimension, value, hour) DO UPDATE
+    SET sessions = rollups.sessions + 1,
+        label = COALESCE(EXCLUDED.label, rollups.label);
+    RETURN NEW;
+  END IF;
+
+  IF current_setting('trantor.keep_rollups', true) = 'on' THEN
+    RETURN OLD;
+  END IF;
+
+  -- Only ever decremented, so that deleting a tracking can cascade.
+  UPDATE rollups SET sessions = sessions - 1
+  WHERE tracking_id = OLD.tracking_id
+    AND hour = rollup_hour(OLD.tracking_id, OLD.start_timestamp)
+    AND (dimension, value) IN (
+      ('path', OLD.pathname),
+      ('country', COALESCE(OLD.location->'country'->>'iso_code', '')),
+      ('referral', COALESCE(OLD.referral, '')),
+      ('source', COALESCE(OLD.source_id::TEXT, ''))
+    );
+  RETURN OLD;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION rollup_visitors() RETURNS TRIGGER AS $$
+BEGIN
+  IF TG_OP = 'INSERT' THEN
+    INSERT INTO rollups (tracking_id, hour, dimension, value, visitors)
+    SELECT NEW.tracking_id, rollup_hour(NEW.tracking_id, NEW.created_at), dimension, value, 1
+    FROM (VALUES
+      ('device', NEW.user_agent_parsed->'device'->>'family'),
+      ('source', COALESCE(NEW.source_id::TEXT, ''))
+    ) AS dimensions (dimension, value)
+    WHERE value IS NOT NULL
+    ON CONFLICT (tracking_id, dimension, value, hour) DO UPDATE
+    SET visitors = rollups.visitors + 1;
+    RETURN NEW;
+  END IF;
+
+  IF current_setting('trantor.keep_rollups', true) = 'on' THEN
+    RETURN OLD;
+  END IF;
+
+  UPDATE rollups SET visitors = visitors - 1
+  WHERE tracking_id = OLD.tracking_id
+    AND hour = rollup_hour(OLD.tracking_id, OLD.created_at)
+    AND (dimension, value) IN (
+      ('device', OLD.user_agent_parsed->'device'->>'family'),
+      ('source', COALESCE(OLD.source_id::TEXT, ''))
+    );
+  RETURN OLD;
+END;
+$$ LANGUAGE plpgsql;
\ No newline at end of file
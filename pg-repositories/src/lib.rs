@@ -1,5 +1,6 @@
 use domain::{
-    async_trait::async_trait, tracing, Session, SessionEnd, SessionRepositoryError,
+    async_trait::async_trait, tracing, ApiKeyRepositoryError, ApiKeysRepository, Event,
+    EventRepositoryError, EventsRepository, Session, SessionEnd, SessionRepositoryError,
     SessionsRepository, Visitor, VisitorRepositoryError, VisitorsRepository,
 };
 
@@ -187,3 +188,104 @@ impl From<SqlxError> for VisitorRepositoryError {
         Self::Other
     }
 }
+
+#[derive(Clone)]
+pub struct PgEventsRepository {
+    pool: sqlx::PgPool,
+}
+
+impl PgEventsRepository {
+    pub fn new(pool: &sqlx::PgPool) -> Self {
+        Self { pool: pool.clone() }
+    }
+}
+
+#[async_trait]
+impl EventsRepository for PgEventsRepository {
+    async fn create(&self, event: &Event) -> Result<(), EventRepositoryError> {
+        sqlx::query!(
+            r#"
+with tracking as (
+  select id
+  from trackings
+  where tracking_id = $1
+)
+insert into events (session_id, type, target, properties, tracking_id)
+values (
+    (
+      select id
+      from sessions
+      where session_id = $2
+        and tracking_id = (
+          select id
+          from tracking
+        )
+    ),
+    $3,
+    $4,
+    $5,
+    (
+      select id
+      from tracking
+    )
+  )
+"#,
+            event.tracking_id(),
+            event.session_id(),
+            event.event_type(),
+            event.target(),
+            event.properties(),
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(SqlxError)?;
+
+        Ok(())
+    }
+}
+
+impl From<SqlxError> for EventRepositoryError {
+    fn from(err: SqlxError) -> Self {
+        tracing::error!("error in events repository: {}", err.0);
+        Self::Other
+    }
+}
+
+#[derive(Clone)]
+pub struct PgApiKeysRepository {
+    pool: sqlx::PgPool,
+}
+
+impl PgApiKeysRepository {
+    pub fn new(pool: &sqlx::PgPool) -> Self {
+        Self { pool: pool.clone() }
+    }
+}
+
+#[async_trait]
+impl ApiKeysRepository for PgApiKeysRepository {
+    async fn tracking_id(&self, api_key: &str) -> Result<Option<String>, ApiKeyRepositoryError> {
+        let record = sqlx::query!(
+            r#"
+select trackings.tracking_id
+from api_keys
+  join trackings on trackings.id = api_keys.tracking_id
+where api_keys.key_hash = encode(sha256(convert_to($1, 'UTF8')), 'hex')
+  and api_keys.revoked_at is null
+"#,
+            api_key
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(SqlxError)?;
+
+        Ok(record.map(|r| r.tracking_id))
+    }
+}
+
+impl From<SqlxError> for ApiKeyRepositoryError {
+    fn from(err: SqlxError) -> Self {
+        tracing::error!("error in api keys repository: {}", err.0);
+        Self::Other
+    }
+}
@@ -0,0 +1,65 @@
+use domain::{
+    async_trait::async_trait, thiserror, ApiKeyRepositoryError, ApiKeysRepository, Service,
+};
+
+#[derive(Clone)]
+pub struct ApiKeyAuthService<AKR> {
+    api_keys: AKR,
+}
+
+impl<AKR> ApiKeyAuthService<AKR>
+where
+    AKR: ApiKeysRepository + Clone + Send,
+{
+    pub fn new(api_keys: AKR) -> Self {
+        Self { api_keys }
+    }
+}
+
+#[async_trait]
+impl<AKR> Service for ApiKeyAuthService<AKR>
+where
+    AKR: ApiKeysRepository + Sync + Send + Clone,
+{
+    type Error = ApiKeyAuthError;
+    type Request = ApiKeyAuthRequest;
+    type Response = ApiKeyAuthResponse;
+
+    async fn execute(&self, req: Self::Request) -> Result<Self::Response, Self::Error> {
+        let tracking_id = self
+            .api_keys
+            .tracking_id(&req.api_key)
+            .await?
+            .ok_or(ApiKeyAuthError::InvalidApiKey)?;
+
+        Ok(ApiKeyAuthResponse { tracking_id })
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum ApiKeyAuthError {
+    #[error("api key is unknown or revoked")]
+    InvalidApiKey,
+    #[error("error in api keys repository")]
+    ApiKeysRepository(#[from] ApiKeyRepositoryError),
+}
+
+pub struct ApiKeyAuthRequest {
+    api_key: String,
+}
+
+impl ApiKeyAuthRequest {
+    pub fn new(api_key: String) -> Self {
+        Self { api_key }
+    }
+}
+
+pub struct ApiKeyAuthResponse {
+    tracking_id: String,
+}
+
+impl ApiKeyAuthResponse {
+    pub fn tracking_id(&self) -> &str {
+        &self.tracking_id
+    }
+}
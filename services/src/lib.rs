@@ -1,5 +1,9 @@
+mod api_key_auth;
 mod session_end;
+mod session_event;
 mod session_start;
 
+pub use api_key_auth::*;
 pub use session_end::*;
+pub use session_event::*;
 pub use session_start::*;
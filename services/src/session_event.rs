@@ -0,0 +1,83 @@
+use domain::{
+    async_trait::async_trait, serde_json, thiserror, valid_event_properties, Event,
+    EventProperties, EventRepositoryError, EventsRepository, Service,
+};
+
+#[derive(Clone)]
+pub struct SessionEventService<ER> {
+    events: ER,
+}
+
+impl<ER> SessionEventService<ER>
+where
+    ER: EventsRepository + Clone + Send,
+{
+    pub fn new(events: ER) -> Self {
+        Self { events }
+    }
+}
+
+#[async_trait]
+impl<ER> Service for SessionEventService<ER>
+where
+    ER: EventsRepository + Sync + Send + Clone,
+{
+    type Error = SessionEventError;
+    type Request = SessionEventRequest;
+    type Response = SessionEventResponse;
+
+    async fn execute(&self, req: Self::Request) -> Result<Self::Response, Self::Error> {
+        let properties = match req.properties {
+            Some(properties) if !valid_event_properties(&properties) => {
+                return Err(SessionEventError::InvalidProperties);
+            }
+            properties => properties.map(serde_json::Value::Object),
+        };
+
+        let event = Event::new(
+            req.tracking_id,
+            req.session_id,
+            req.event_type,
+            req.target,
+            properties,
+        );
+        self.events.create(&event).await?;
+        Ok(SessionEventResponse)
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum SessionEventError {
+    #[error("event properties must be a flat object of at most 32 short scalars")]
+    InvalidProperties,
+    #[error("error in events repository")]
+    EventsRepository(#[from] EventRepositoryError),
+}
+
+pub struct SessionEventRequest {
+    tracking_id: String,
+    session_id: String,
+    event_type: String,
+    target: String,
+    properties: Option<EventProperties>,
+}
+
+impl SessionEventRequest {
+    pub fn new(
+        tracking_id: String,
+        session_id: String,
+        event_type: String,
+        target: String,
+        properties: Option<EventProperties>,
+    ) -> Self {
+        Self {
+            tracking_id,
+            session_id,
+            event_type,
+            target,
+            properties,
+        }
+    }
+}
+
+pub struct SessionEventResponse;
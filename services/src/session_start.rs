@@ -1,5 +1,5 @@
 use domain::{
-    async_trait::async_trait, thiserror, GeoIpReader, GeoIpReaderError, Service, Session,
+    async_trait::async_trait, thiserror, GeoIpReader, GeoIpReaderError, Location, Service, Session,
     SessionRepositoryError, SessionsRepository, UserAgentParser, UserAgentParserError, Visitor,
     VisitorRepositoryError, VisitorsRepository,
 };
@@ -17,7 +17,7 @@ where
     SR: SessionsRepository + Clone + Send,
     VR: VisitorsRepository + Clone + Send,
     UAP: UserAgentParser + Clone + Send,
-    GIR: GeoIpReader + Clone + Send,
+    GIR: GeoIpReader<Location = Location> + Clone + Send,
 {
     pub fn new(sessions: SR, visitors: VR, user_agent_parser: UAP, geo_ip_reader: GIR) -> Self {
         Self {
@@ -35,7 +35,7 @@ where
     SR: SessionsRepository + Sync + Send + Clone,
     VR: VisitorsRepository + Sync + Send + Clone,
     UAP: UserAgentParser + Sync + Send + Clone,
-    GIR: GeoIpReader + Sync + Send + Clone,
+    GIR: GeoIpReader<Location = Location> + Sync + Send + Clone,
 {
     type Error = SessionStartError;
     type Request = SessionStartRequest;
@@ -97,6 +97,7 @@ pub struct SessionStartRequest {
 }
 
 impl SessionStartRequest {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         tracking_id: String,
         visitor_id: Option<String>,
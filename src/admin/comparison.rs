@@ -0,0 +1,377 @@
+use std::future::Future;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    db::{Comparison, DateRange},
+    errors::InvalidComparisonRange,
+};
+
+// Numeric fields that identify a breakdown row rather than measure it.
+const KEY_FIELDS: &[&str] = &["weekday", "hour", "min_seconds", "max_seconds"];
+// Rows of a time series are matched by position, since their buckets never
+// line up across two windows.
+const SERIES_FIELDS: &[&str] = &["bucket"];
+// Fields that only label a row identified by another field, and may be missing
+// from one window, like the names of countries in imported history.
+const LABEL_FIELDS: &[(&str, &str)] = &[("name", "iso_code")];
+
+#[derive(Deserialize)]
+pub struct CompareQuery {
+    compare: Option<Comparison>,
+}
+
+#[derive(Serialize)]
+pub struct Compared<T> {
+    #[serde(flatten)]
+    current: T,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    comparison: Option<ComparisonReport<T>>,
+}
+
+#[derive(Serialize)]
+struct ComparisonReport<T> {
+    compare: Comparison,
+    from: String,
+    to: String,
+    previous: T,
+    deltas: Value,
+}
+
+impl CompareQuery {
+    pub async fn run<T, F, Fut>(
+        self,
+        range: DateRange,
+        load: F,
+    ) -> Result<Compared<T>, warp::Rejection>
+    where
+        T: Serialize,
+        F: Fn(DateRange) -> Fut,
+        Fut: Future<Output = Result<T, warp::Rejection>>,
+    {
+        let previous_range = match self.compare {
+            Some(comparison) => {
+                let previous_range = range.compared_to(comparison).ok_or_else(|| {
+                    tracing::error!("Comparison requested without a from/to range");
+                    warp::reject::custom(InvalidComparisonRange)
+                })?;
+                Some((comparison, previous_range))
+            }
+            None => None,
+        };
+
+        let current = load(range).await?;
+
+        let comparison = match previous_range {
+            Some((compare, range)) => {
+                let from = range
+                    .from()
+                    .map(|date| date.to_string())
+                    .unwrap_or_default();
+                let to = range.to().map(|date| date.to_string()).unwrap_or_default();
+                let previous = load(range).await?;
+                let deltas = deltas(
+                    &serde_json::to_value(&current).unwrap_or_default(),
+                    Some(&serde_json::to_value(&previous).unwrap_or_default()),
+                );
+
+                Some(ComparisonReport {
+                    compare,
+                    from,
+                    to,
+                    previous,
+                    deltas,
+                })
+            }
+            None => None,
+        };
+
+        Ok(Compared {
+            current,
+            comparison,
+        })
+    }
+}
+
+// Mirrors `current`, keeping the fields that identify a row and replacing
+// every metric with its change since `previous`. A row missing from
+// `previous` is compared against zero.
+fn deltas(current: &Value, previous: Option<&Value>) -> Value {
+    match current {
+        Value::Number(current) => match previous {
+            Some(Value::Number(previous)) => delta(current, previous),
+            Some(_) => delta_unknown(),
+            None => delta(current, &0.into()),
+        },
+        Value::Object(fields) => Value::Object(
+            fields
+                .iter()
+                .map(|(name, value)| {
+                    let value = if is_key(name, value) {
+                        value.clone()
+                    } else {
+                        deltas(value, previous.and_then(|previous| previous.get(name)))
+                    };
+                    (name.clone(), value)
+                })
+                .collect(),
+        ),
+        Value::Array(rows) => Value::Array(
+            rows.iter()
+                .enumerate()
+                .map(|(index, row)| deltas(row, matching_row(row, index, previous)))
+                .collect(),
+        ),
+        other => other.clone(),
+    }
+}
+
+fn is_key(name: &str, value: &Value) -> bool {
+    match value {
+        Value::String(_) | Value::Bool(_) => true,
+        Value::Number(_) => KEY_FIELDS.contains(&name) || SERIES_FIELDS.contains(&name),
+        _ => false,
+    }
+}
+
+fn is_label(name: &str, fields: &serde_json::Map<String, Value>) -> bool {
+    LABEL_FIELDS
+        .iter()
+        .any(|(label, key)| *label == name && fields.contains_key(*key))
+}
+
+fn matching_row<'a>(row: &Value, index: usize, previous: Option<&'a Value>) -> Option<&'a Value> {
+    let previous_rows = previous?.as_array()?;
+    let Value::Object(fields) = row else {
+        return previous_rows.get(index);
+    };
+
+    if fields
+        .keys()
+        .any(|name| SERIES_FIELDS.contains(&name.as_str()))
+    {
+        return previous_rows.get(index);
+    }
+
+    // A null is either a missing key, like a direct visit's referral, or a
+    // metric with nothing to measure, like the duration of unended sessions.
+    previous_rows.iter().find(|previous_row| {
+        fields.iter().all(|(name, value)| {
+            let previous_value = previous_row.get(name).unwrap_or(&Value::Null);
+            if is_label(name, fields) {
+                true
+            } else if value.is_null() {
+                !previous_value.is_string() && !previous_value.is_boolean()
+            } else if is_key(name, value) {
+                previous_value == value
+            } else {
+                true
+            }
+        })
+    })
+}
+
+fn delta(current: &serde_json::Number, previous: &serde_json::Number) -> Value {
+    let absolute = match (current.as_i64(), previous.as_i64()) {
+        (Some(current), Some(previous)) => Value::from(current - previous),
+        _ => Value::from(
+            current.as_f64().unwrap_or_default() - previous.as_f64().unwrap_or_default(),
+        ),
+    };
+    let previous = previous.as_f64().unwrap_or_default();
+    let percentage = if previous == 0.0 {
+        Value::Null
+    } else {
+        Value::from((current.as_f64().unwrap_or_default() - previous) / previous * 100.0)
+    };
+
+    serde_json::json!({ "absolute": absolute, "percentage": percentage })
+}
+
+fn delta_unknown() -> Value {
+    serde_json::json!({ "absolute": null, "percentage": null })
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::deltas;
+
+    #[test]
+    fn breakdown_rows_are_matched_by_their_keys() {
+        let current = json!({
+            "name": "Blog",
+            "timezone": "UTC",
+            "visitor_count_by_weekday": [
+                { "weekday": 1, "count": 10 },
+                { "weekday": 2, "count": 4 },
+            ],
+            "visitor_count_by_os": [{ "os": "Linux", "count": 3 }],
+        });
+        let previous = json!({
+            "name": "Blog",
+            "timezone": "UTC",
+            "visitor_count_by_weekday": [
+                { "weekday": 2, "count": 8 },
+                { "weekday": 1, "count": 5 },
+            ],
+            "visitor_count_by_os": [],
+        });
+
+        assert_eq!(
+            deltas(&current, Some(&previous)),
+            json!({
+                "name": "Blog",
+                "timezone": "UTC",
+                "visitor_count_by_weekday": [
+                    { "weekday": 1, "count": { "absolute": 5, "percentage": 100.0 } },
+                    { "weekday": 2, "count": { "absolute": -4, "percentage": -50.0 } },
+                ],
+                "visitor_count_by_os": [
+                    { "os": "Linux", "count": { "absolute": 3, "percentage": null } },
+                ],
+            })
+        );
+    }
+
+    #[test]
+    fn time_series_points_are_matched_by_position() {
+        let current = json!({
+            "points": [
+                { "bucket": 1688169600000_i64, "visitors": 2, "sessions": 3, "page_views": 3, "events": 0 },
+                { "bucket": 1688256000000_i64, "visitors": 4, "sessions": 6, "page_views": 6, "events": 1 },
+            ],
+        });
+        let previous = json!({
+            "points": [
+                { "bucket": 1687996800000_i64, "visitors": 1, "sessions": 3, "page_views": 3, "events": 0 },
+                { "bucket": 1688083200000_i64, "visitors": 2, "sessions": 2, "page_views": 2, "events": 0 },
+            ],
+        });
+
+        assert_eq!(
+            deltas(&current, Some(&previous)),
+            json!({
+                "points": [
+                    {
+                        "bucket": 1688169600000_i64,
+                        "visitors": { "absolute": 1, "percentage": 100.0 },
+                        "sessions": { "absolute": 0, "percentage": 0.0 },
+                        "page_views": { "absolute": 0, "percentage": 0.0 },
+                        "events": { "absolute": 0, "percentage": null },
+                    },
+                    {
+                        "bucket": 1688256000000_i64,
+                        "visitors": { "absolute": 2, "percentage": 100.0 },
+                        "sessions": { "absolute": 4, "percentage": 200.0 },
+                        "page_views": { "absolute": 4, "percentage": 200.0 },
+                        "events": { "absolute": 1, "percentage": null },
+                    },
+                ],
+            })
+        );
+    }
+
+    #[test]
+    fn missing_durations_and_open_ended_buckets() {
+        let current = json!({
+            "sessions": 4,
+            "ended_sessions": 2,
+            "average_duration": 30.0,
+            "median_duration": null,
+            "histogram": [
+                { "min_seconds": 0, "max_seconds": 10, "sessions": 1 },
+                { "min_seconds": 1800, "max_seconds": null, "sessions": 1 },
+            ],
+        });
+        let previous = json!({
+            "sessions": 2,
+            "ended_sessions": 0,
+            "average_duration": null,
+            "median_duration": null,
+            "histogram": [
+                { "min_seconds": 1800, "max_seconds": null, "sessions": 2 },
+                { "min_seconds": 0, "max_seconds": 10, "sessions": 0 },
+            ],
+        });
+
+        assert_eq!(
+            deltas(&current, Some(&previous)),
+            json!({
+                "sessions": { "absolute": 2, "percentage": 100.0 },
+                "ended_sessions": { "absolute": 2, "percentage": null },
+                "average_duration": { "absolute": null, "percentage": null },
+                "median_duration": null,
+                "histogram": [
+                    { "min_seconds": 0, "max_seconds": 10, "sessions": { "absolute": 1, "percentage": null } },
+                    { "min_seconds": 1800, "max_seconds": null, "sessions": { "absolute": -1, "percentage": -50.0 } },
+                ],
+            })
+        );
+    }
+
+    #[test]
+    fn labels_are_ignored_when_matching() {
+        let current = json!({
+            "countries": [{ "name": "Germany", "iso_code": "DE", "count": 3 }],
+            "sources": [{ "name": "twitter", "visitor_count": 2, "session_count": 2 }],
+        });
+        let previous = json!({
+            "countries": [{ "name": null, "iso_code": "DE", "count": 2 }],
+            "sources": [{ "name": "telegram", "visitor_count": 1, "session_count": 1 }],
+        });
+
+        assert_eq!(
+            deltas(&current, Some(&previous)),
+            json!({
+                "countries": [
+                    { "name": "Germany", "iso_code": "DE", "count": { "absolute": 1, "percentage": 50.0 } },
+                ],
+                "sources": [
+                    {
+                        "name": "twitter",
+                        "visitor_count": { "absolute": 2, "percentage": null },
+                        "session_count": { "absolute": 2, "percentage": null },
+                    },
+                ],
+            })
+        );
+    }
+
+    #[test]
+    fn null_keys_match_null_keys() {
+        let current = json!({
+            "countries": [
+                { "name": "Germany", "iso_code": "DE", "count": 3 },
+                { "name": null, "iso_code": null, "count": 2 },
+            ],
+            "types": [{ "type": "click", "count": 6, "visitor_count": 2 }],
+        });
+        let previous = json!({
+            "countries": [
+                { "name": null, "iso_code": null, "count": 1 },
+                { "name": "Germany", "iso_code": "DE", "count": 6 },
+            ],
+            "types": [{ "type": "submit", "count": 1, "visitor_count": 1 }],
+        });
+
+        assert_eq!(
+            deltas(&current, Some(&previous)),
+            json!({
+                "countries": [
+                    { "name": "Germany", "iso_code": "DE", "count": { "absolute": -3, "percentage": -50.0 } },
+                    { "name": null, "iso_code": null, "count": { "absolute": 1, "percentage": 100.0 } },
+                ],
+                "types": [
+                    {
+                        "type": "click",
+                        "count": { "absolute": 6, "percentage": null },
+                        "visitor_count": { "absolute": 2, "percentage": null },
+                    },
+                ],
+            })
+        );
+    }
+}
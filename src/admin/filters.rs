@@ -1,15 +1,21 @@
 use warp::Filter;
 
 use super::{
-    handlers, CreateSourceRequest, CreateTrackingRequest, CreateUserRequest, RenameTrackingRequest,
+    handlers, CompareQuery, CreateApiKeyRequest, CreateSourceRequest, CreateTrackingRequest,
+    CreateUserRequest, DataSubjectQuery, EventPropertyQuery, ExportKind, ExportQuery,
+    IdentifiedVisitorsExportQuery, LiveQuery, RecentEventsQuery, RenameTrackingRequest,
+    SetCookielessRequest, SetOptOutPolicyRequest, SetRetentionRequest, SetTimezoneRequest,
+    TimeSeriesQuery, VisitorsQuery,
 };
 use crate::{
-    db::{with_db, DB},
+    db::{with_db, DateRange, Segment, DB},
     middleware::{authenticate_filter, extract_basic_token, user_id_owns_tracking},
+    realtime::{with_realtime, Realtime},
 };
 
 pub fn make_admin_routes(
     db: DB,
+    realtime: Realtime,
 ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
     let authenticate_user = warp::path!("authenticate")
         .and(warp::post())
@@ -44,14 +50,100 @@ pub fn make_admin_routes(
         .and_then(authenticate_filter)
         .and(warp::path!("trackings" / String))
         .and_then(user_id_owns_tracking)
-        .and_then(|(db, tracking_id)| handlers::get_tracking(db, tracking_id));
+        .and(warp::query::<DateRange>())
+        .and(warp::query::<Segment>())
+        .and(warp::query::<CompareQuery>())
+        .and_then(|(db, tracking_id), range, segment, compare| {
+            handlers::get_tracking(db, tracking_id, range, segment, compare)
+        });
     let tracking_counts = warp::get()
         .and(with_db(db.clone()))
         .and(extract_basic_token())
         .and_then(authenticate_filter)
         .and(warp::path!("trackings" / String / "counts"))
         .and_then(user_id_owns_tracking)
-        .and_then(|(db, tracking_id)| handlers::tracking_counts(db, tracking_id));
+        .and(warp::query::<DateRange>())
+        .and(warp::query::<Segment>())
+        .and(warp::query::<CompareQuery>())
+        .and_then(|(db, tracking_id), range, segment, compare| {
+            handlers::tracking_counts(db, tracking_id, range, segment, compare)
+        });
+    let time_series = warp::get()
+        .and(with_db(db.clone()))
+        .and(extract_basic_token())
+        .and_then(authenticate_filter)
+        .and(warp::path!("trackings" / String / "timeseries"))
+        .and_then(user_id_owns_tracking)
+        .and(warp::query::<TimeSeriesQuery>())
+        .and(warp::query::<CompareQuery>())
+        .and_then(|(db, tracking_id), query, compare| {
+            handlers::time_series(db, tracking_id, query, compare)
+        });
+    let new_vs_returning = warp::get()
+        .and(with_db(db.clone()))
+        .and(extract_basic_token())
+        .and_then(authenticate_filter)
+        .and(warp::path!("trackings" / String / "returning"))
+        .and_then(user_id_owns_tracking)
+        .and(warp::query::<TimeSeriesQuery>())
+        .and(warp::query::<CompareQuery>())
+        .and_then(|(db, tracking_id), query, compare| {
+            handlers::new_vs_returning(db, tracking_id, query, compare)
+        });
+    let sessions_per_visitor = warp::get()
+        .and(with_db(db.clone()))
+        .and(extract_basic_token())
+        .and_then(authenticate_filter)
+        .and(warp::path!("trackings" / String / "sessions-per-visitor"))
+        .and_then(user_id_owns_tracking)
+        .and(warp::query::<DateRange>())
+        .and(warp::query::<Segment>())
+        .and_then(|(db, tracking_id), range, segment| {
+            handlers::sessions_per_visitor(db, tracking_id, range, segment)
+        });
+    let opt_outs = warp::get()
+        .and(with_db(db.clone()))
+        .and(extract_basic_token())
+        .and_then(authenticate_filter)
+        .and(warp::path!("trackings" / String / "opt-outs"))
+        .and_then(user_id_owns_tracking)
+        .and(warp::query::<DateRange>())
+        .and_then(|(db, tracking_id), range| handlers::opt_outs(db, tracking_id, range));
+    let engagement = warp::get()
+        .and(with_db(db.clone()))
+        .and(extract_basic_token())
+        .and_then(authenticate_filter)
+        .and(warp::path!("trackings" / String / "engagement"))
+        .and_then(user_id_owns_tracking)
+        .and(warp::query::<DateRange>())
+        .and(warp::query::<Segment>())
+        .and(warp::query::<CompareQuery>())
+        .and_then(|(db, tracking_id), range, segment, compare| {
+            handlers::engagement(db, tracking_id, range, segment, compare)
+        });
+    let page_engagement = warp::get()
+        .and(with_db(db.clone()))
+        .and(extract_basic_token())
+        .and_then(authenticate_filter)
+        .and(warp::path!("trackings" / String / "engagement" / "pages"))
+        .and_then(user_id_owns_tracking)
+        .and(warp::query::<DateRange>())
+        .and(warp::query::<Segment>())
+        .and(warp::query::<CompareQuery>())
+        .and_then(|(db, tracking_id), range, segment, compare| {
+            handlers::page_engagement(db, tracking_id, range, segment, compare)
+        });
+    let live = warp::get()
+        .and(with_db(db.clone()))
+        .and(extract_basic_token())
+        .and_then(authenticate_filter)
+        .and(warp::path!("trackings" / String / "live"))
+        .and_then(user_id_owns_tracking)
+        .and(with_realtime(realtime))
+        .and(warp::query::<LiveQuery>())
+        .and_then(|(_db, tracking_id), realtime, query| {
+            handlers::live(realtime, tracking_id, query)
+        });
     let patch_tracking_name = warp::patch()
         .and(with_db(db.clone()))
         .and(extract_basic_token())
@@ -60,6 +152,40 @@ pub fn make_admin_routes(
         .and_then(user_id_owns_tracking)
         .and(warp::body::json::<RenameTrackingRequest>())
         .and_then(|(db, tracking_id), req| handlers::rename_tracking(db, tracking_id, req));
+    let patch_tracking_timezone = warp::patch()
+        .and(with_db(db.clone()))
+        .and(extract_basic_token())
+        .and_then(authenticate_filter)
+        .and(warp::path!("trackings" / String / "timezone"))
+        .and_then(user_id_owns_tracking)
+        .and(warp::body::json::<SetTimezoneRequest>())
+        .and_then(|(db, tracking_id), req| handlers::set_tracking_timezone(db, tracking_id, req));
+    let patch_tracking_cookieless = warp::patch()
+        .and(with_db(db.clone()))
+        .and(extract_basic_token())
+        .and_then(authenticate_filter)
+        .and(warp::path!("trackings" / String / "cookieless"))
+        .and_then(user_id_owns_tracking)
+        .and(warp::body::json::<SetCookielessRequest>())
+        .and_then(|(db, tracking_id), req| handlers::set_tracking_cookieless(db, tracking_id, req));
+    let patch_tracking_opt_out_policy = warp::patch()
+        .and(with_db(db.clone()))
+        .and(extract_basic_token())
+        .and_then(authenticate_filter)
+        .and(warp::path!("trackings" / String / "opt-out-policy"))
+        .and_then(user_id_owns_tracking)
+        .and(warp::body::json::<SetOptOutPolicyRequest>())
+        .and_then(|(db, tracking_id), req| {
+            handlers::set_tracking_opt_out_policy(db, tracking_id, req)
+        });
+    let patch_tracking_retention = warp::patch()
+        .and(with_db(db.clone()))
+        .and(extract_basic_token())
+        .and_then(authenticate_filter)
+        .and(warp::path!("trackings" / String / "retention"))
+        .and_then(user_id_owns_tracking)
+        .and(warp::body::json::<SetRetentionRequest>())
+        .and_then(|(db, tracking_id), req| handlers::set_tracking_retention(db, tracking_id, req));
     let delete_tracking = warp::delete()
         .and(with_db(db.clone()))
         .and(extract_basic_token())
@@ -68,6 +194,52 @@ pub fn make_admin_routes(
         .and_then(user_id_owns_tracking)
         .and_then(|(db, tracking_id)| handlers::delete_tracking(db, tracking_id));
 
+    let tracking_events = warp::get()
+        .and(with_db(db.clone()))
+        .and(extract_basic_token())
+        .and_then(authenticate_filter)
+        .and(warp::path!("trackings" / String / "events"))
+        .and_then(user_id_owns_tracking)
+        .and(warp::query::<DateRange>())
+        .and(warp::query::<Segment>())
+        .and(warp::query::<CompareQuery>())
+        .and_then(|(db, tracking_id), range, segment, compare| {
+            handlers::tracking_events(db, tracking_id, range, segment, compare)
+        });
+    let events_timeline = warp::get()
+        .and(with_db(db.clone()))
+        .and(extract_basic_token())
+        .and_then(authenticate_filter)
+        .and(warp::path!("trackings" / String / "events" / "timeline"))
+        .and_then(user_id_owns_tracking)
+        .and(warp::query::<DateRange>())
+        .and(warp::query::<Segment>())
+        .and_then(|(db, tracking_id), range, segment| {
+            handlers::events_timeline(db, tracking_id, range, segment)
+        });
+    let recent_events = warp::get()
+        .and(with_db(db.clone()))
+        .and(extract_basic_token())
+        .and_then(authenticate_filter)
+        .and(warp::path!("trackings" / String / "events" / "recent"))
+        .and_then(user_id_owns_tracking)
+        .and(warp::query::<DateRange>())
+        .and(warp::query::<Segment>())
+        .and(warp::query::<RecentEventsQuery>())
+        .and_then(|(db, tracking_id), range, segment, query| {
+            handlers::recent_events(db, tracking_id, range, segment, query)
+        });
+    let event_property_breakdown = warp::get()
+        .and(with_db(db.clone()))
+        .and(extract_basic_token())
+        .and_then(authenticate_filter)
+        .and(warp::path!("trackings" / String / "events" / "properties"))
+        .and_then(user_id_owns_tracking)
+        .and(warp::query::<EventPropertyQuery>())
+        .and_then(|(db, tracking_id), query| {
+            handlers::event_property_breakdown(db, tracking_id, query)
+        });
+
     let create_source = warp::post()
         .and(with_db(db.clone()))
         .and(extract_basic_token())
@@ -77,7 +249,7 @@ pub fn make_admin_routes(
         .and(warp::body::json::<CreateSourceRequest>())
         .and_then(|(db, tracking_id), source| handlers::create_source(db, tracking_id, source));
     let delete_source = warp::delete()
-        .and(with_db(db))
+        .and(with_db(db.clone()))
         .and(extract_basic_token())
         .and_then(authenticate_filter)
         .and(warp::path!("trackings" / String / "sources" / String))
@@ -90,16 +262,146 @@ pub fn make_admin_routes(
             handlers::delete_source(db, tracking_id, source_name)
         });
 
+    let create_api_key = warp::post()
+        .and(with_db(db.clone()))
+        .and(extract_basic_token())
+        .and_then(authenticate_filter)
+        .and(warp::path!("trackings" / String / "keys"))
+        .and_then(user_id_owns_tracking)
+        .and(warp::body::json::<CreateApiKeyRequest>())
+        .and_then(|(db, tracking_id), key| handlers::create_api_key(db, tracking_id, key));
+    let list_api_keys = warp::get()
+        .and(with_db(db.clone()))
+        .and(extract_basic_token())
+        .and_then(authenticate_filter)
+        .and(warp::path!("trackings" / String / "keys"))
+        .and_then(user_id_owns_tracking)
+        .and_then(|(db, tracking_id)| handlers::list_api_keys(db, tracking_id));
+    let revoke_api_key = warp::delete()
+        .and(with_db(db.clone()))
+        .and(extract_basic_token())
+        .and_then(authenticate_filter)
+        .and(warp::path!("trackings" / String / "keys" / String))
+        .and_then(|first, tracking_id, key_id| async move {
+            user_id_owns_tracking(first, tracking_id)
+                .await
+                .map(|(db, tracking_id)| (db, tracking_id, key_id))
+        })
+        .and_then(|(db, tracking_id, key_id)| handlers::revoke_api_key(db, tracking_id, key_id));
+    let list_visitors = warp::get()
+        .and(with_db(db.clone()))
+        .and(extract_basic_token())
+        .and_then(authenticate_filter)
+        .and(warp::path!("trackings" / String / "visitors"))
+        .and_then(user_id_owns_tracking)
+        .and(warp::query::<VisitorsQuery>())
+        .and_then(|(db, tracking_id), query| handlers::list_visitors(db, tracking_id, query));
+    let get_visitor = warp::get()
+        .and(with_db(db.clone()))
+        .and(extract_basic_token())
+        .and_then(authenticate_filter)
+        .and(warp::path!("trackings" / String / "visitors" / String))
+        .and_then(|first, tracking_id, visitor_id| async move {
+            user_id_owns_tracking(first, tracking_id)
+                .await
+                .map(|(db, tracking_id)| (db, tracking_id, visitor_id))
+        })
+        .and_then(|(db, tracking_id, visitor_id)| {
+            handlers::get_visitor(db, tracking_id, visitor_id)
+        });
+    let export_identified_visitors = warp::get()
+        .and(with_db(db.clone()))
+        .and(extract_basic_token())
+        .and_then(authenticate_filter)
+        .and(warp::path!("trackings" / String / "visitors" / "export"))
+        .and_then(user_id_owns_tracking)
+        .and(warp::query::<IdentifiedVisitorsExportQuery>())
+        .and_then(|(db, tracking_id), query| {
+            handlers::export_identified_visitors(db, tracking_id, query)
+        });
+    let export_data_subject = warp::get()
+        .and(with_db(db.clone()))
+        .and(extract_basic_token())
+        .and_then(authenticate_filter)
+        .and(warp::path!("trackings" / String / "data-subject"))
+        .and_then(|first: (DB, i32), tracking_id| async move {
+            let user_id = first.1;
+            user_id_owns_tracking(first, tracking_id)
+                .await
+                .map(|(db, tracking_id)| (db, tracking_id, user_id))
+        })
+        .and(warp::query::<DataSubjectQuery>())
+        .and_then(|(db, tracking_id, user_id), query| {
+            handlers::export_data_subject(db, tracking_id, user_id, query)
+        });
+    let erase_data_subject = warp::delete()
+        .and(with_db(db.clone()))
+        .and(extract_basic_token())
+        .and_then(authenticate_filter)
+        .and(warp::path!("trackings" / String / "data-subject"))
+        .and_then(|first: (DB, i32), tracking_id| async move {
+            let user_id = first.1;
+            user_id_owns_tracking(first, tracking_id)
+                .await
+                .map(|(db, tracking_id)| (db, tracking_id, user_id))
+        })
+        .and(warp::query::<DataSubjectQuery>())
+        .and_then(|(db, tracking_id, user_id), query| {
+            handlers::erase_data_subject(db, tracking_id, user_id, query)
+        });
+    let export = warp::get()
+        .and(with_db(db))
+        .and(extract_basic_token())
+        .and_then(authenticate_filter)
+        .and(warp::path!("trackings" / String / "export" / ExportKind))
+        .and_then(|first, tracking_id, kind| async move {
+            user_id_owns_tracking(first, tracking_id)
+                .await
+                .map(|(db, tracking_id)| (db, tracking_id, kind))
+        })
+        .and(warp::query::<DateRange>())
+        .and(warp::query::<ExportQuery>())
+        .and_then(|(db, tracking_id, kind), range, query| {
+            handlers::export(db, tracking_id, kind, range, query)
+        });
+
+    let trackings = create_tracking
+        .or(list_trackings)
+        .or(patch_tracking_name)
+        .or(patch_tracking_timezone)
+        .or(patch_tracking_cookieless)
+        .or(patch_tracking_opt_out_policy)
+        .or(patch_tracking_retention)
+        .or(delete_tracking);
+    let analytics = get_tracking
+        .or(tracking_counts)
+        .or(time_series)
+        .or(new_vs_returning)
+        .or(sessions_per_visitor)
+        .or(opt_outs)
+        .or(engagement)
+        .or(page_engagement)
+        .or(live);
+    let events = tracking_events
+        .or(events_timeline)
+        .or(recent_events)
+        .or(event_property_breakdown);
+    let sources = create_source.or(delete_source);
+    let api_keys = create_api_key.or(list_api_keys).or(revoke_api_key);
+    // The export route goes first, so `export` isn't taken for a visitor id.
+    let visitors = export_identified_visitors.or(list_visitors).or(get_visitor);
+    let data_subject = export_data_subject.or(erase_data_subject);
+
     warp::path("admin").and(
         authenticate_user
             .or(create_user)
-            .or(create_tracking)
-            .or(list_trackings)
-            .or(get_tracking)
-            .or(tracking_counts)
-            .or(patch_tracking_name)
-            .or(delete_tracking)
-            .or(create_source)
-            .or(delete_source),
+            .or(trackings)
+            .or(analytics)
+            .or(events)
+            .or(sources)
+            .or(api_keys)
+            .or(visitors)
+            .or(data_subject)
+            .or(export),
     )
 }
@@ -1,12 +1,25 @@
+use std::{collections::VecDeque, future::Future, str::FromStr, time::Duration};
+
+use futures_util::{stream::BoxStream, StreamExt, TryStreamExt};
 use serde::{Deserialize, Serialize};
+use tokio::sync::{broadcast::error::RecvError, mpsc};
 
+use super::CompareQuery;
 use crate::{
     db::{
-        CountByBrowser, CountByCountry, CountByDevice, CountByHour, CountByOs, CountByPathname,
-        CountByReferral, CountByTitle, CountByWeekday, NewTrackingData, NewUserData, SingleReferer,
-        SingleSource, SingleTracking, DB,
+        CountByBrowser, CountByCountry, CountByDevice, CountByEventTarget, CountByEventType,
+        CountByHour, CountByOs, CountByPathname, CountByProperty, CountByReferral, CountByTitle,
+        CountByWeekday, DataSubject, DateRange, DurationBucket, EventCountByDay, ExportedEvent,
+        ExportedIdentifiedVisitor, Interval, NewApiKeyData, NewTrackingData, NewUserData,
+        NewVsReturningPoint, OptOutPolicy, OptedOutHits, PageEngagement, RecentEvent, Segment,
+        SessionEngagement, SessionsPerVisitor, SingleApiKey, SingleReferer, SingleSource,
+        SingleTracking, TimeSeriesPoint, TimelineEntry, VisitorProfile, VisitorSummary, DB,
+    },
+    errors::{
+        DatabaseError, InvalidDataSubject, InvalidRetention, InvalidTimezone, TooManyBuckets,
+        UnknownApiKey, UnknownVisitor,
     },
-    errors::DatabaseError,
+    realtime::{LiveUpdate, Realtime, MAX_ACTIVE_WINDOW},
 };
 
 // User Routes
@@ -80,6 +93,7 @@ pub async fn list_trackings((db, user_id): (DB, i32)) -> Result<impl warp::Reply
 #[derive(Serialize)]
 pub struct TrackingResponse {
     name: String,
+    timezone: String,
     session_count_by_weekday: Vec<CountByWeekday>,
     visitor_count_by_weekday: Vec<CountByWeekday>,
 
@@ -91,59 +105,94 @@ pub struct TrackingResponse {
     visitor_count_by_device: Vec<CountByDevice>,
 }
 
-pub async fn get_tracking(db: DB, tracking_id: i32) -> Result<impl warp::Reply, warp::Rejection> {
+pub async fn get_tracking(
+    db: DB,
+    tracking_id: i32,
+    range: DateRange,
+    segment: Segment,
+    compare: CompareQuery,
+) -> Result<impl warp::Reply, warp::Rejection> {
     tracing::info!("Getting tracking");
 
+    let tracking = compare
+        .run(range, |range| {
+            load_tracking(db.clone(), tracking_id, range, segment.clone())
+        })
+        .await?;
+
+    Ok(warp::reply::json(&tracking))
+}
+
+async fn load_tracking(
+    db: DB,
+    tracking_id: i32,
+    range: DateRange,
+    segment: Segment,
+) -> Result<TrackingResponse, warp::Rejection> {
     let tracking_name = db.tracking_name(tracking_id).await.map_err(|e| {
         tracing::error!("Error getting tracking name: {}", e);
         warp::reject::custom(DatabaseError)
     })?;
-
-    let session_count_by_weekday =
-        db.count_sessions_by_weekday(tracking_id)
-            .await
-            .map_err(|e| {
-                tracing::error!("Error counting sessions: {}", e);
-                warp::reject::custom(DatabaseError)
-            })?;
-    let visitor_count_by_weekday =
-        db.count_visitors_by_weekday(tracking_id)
-            .await
-            .map_err(|e| {
-                tracing::error!("Error counting visitors: {}", e);
-                warp::reject::custom(DatabaseError)
-            })?;
-
-    let session_count_by_hour = db.count_sessions_by_hour(tracking_id).await.map_err(|e| {
-        tracing::error!("Error counting sessions by hour: {}", e);
-        warp::reject::custom(DatabaseError)
-    })?;
-    let visitor_count_by_hour = db.count_visitors_by_hour(tracking_id).await.map_err(|e| {
-        tracing::error!("Error counting visitors by hour: {}", e);
+    let timezone = db.tracking_timezone(tracking_id).await.map_err(|e| {
+        tracing::error!("Error getting tracking timezone: {}", e);
         warp::reject::custom(DatabaseError)
     })?;
 
-    let visitor_count_by_os = db.count_visitors_by_os(tracking_id).await.map_err(|e| {
-        tracing::error!("Error counting visitors by os: {}", e);
-        warp::reject::custom(DatabaseError)
-    })?;
-    let visitor_count_by_browser =
-        db.count_visitors_by_browser(tracking_id)
-            .await
-            .map_err(|e| {
-                tracing::error!("Error counting visitors by browser: {}", e);
-                warp::reject::custom(DatabaseError)
-            })?;
+    let session_count_by_weekday = db
+        .count_sessions_by_weekday(tracking_id, &range, &segment)
+        .await
+        .map_err(|e| {
+            tracing::error!("Error counting sessions: {}", e);
+            warp::reject::custom(DatabaseError)
+        })?;
+    let visitor_count_by_weekday = db
+        .count_visitors_by_weekday(tracking_id, &range, &segment)
+        .await
+        .map_err(|e| {
+            tracing::error!("Error counting visitors: {}", e);
+            warp::reject::custom(DatabaseError)
+        })?;
+
+    let session_count_by_hour = db
+        .count_sessions_by_hour(tracking_id, &range, &segment)
+        .await
+        .map_err(|e| {
+            tracing::error!("Error counting sessions by hour: {}", e);
+            warp::reject::custom(DatabaseError)
+        })?;
+    let visitor_count_by_hour = db
+        .count_visitors_by_hour(tracking_id, &range, &segment)
+        .await
+        .map_err(|e| {
+            tracing::error!("Error counting visitors by hour: {}", e);
+            warp::reject::custom(DatabaseError)
+        })?;
+
+    let visitor_count_by_os = db
+        .count_visitors_by_os(tracking_id, &range, &segment)
+        .await
+        .map_err(|e| {
+            tracing::error!("Error counting visitors by os: {}", e);
+            warp::reject::custom(DatabaseError)
+        })?;
+    let visitor_count_by_browser = db
+        .count_visitors_by_browser(tracking_id, &range, &segment)
+        .await
+        .map_err(|e| {
+            tracing::error!("Error counting visitors by browser: {}", e);
+            warp::reject::custom(DatabaseError)
+        })?;
     let visitor_count_by_device = db
-        .count_visitors_by_device(tracking_id)
+        .count_visitors_by_device(tracking_id, &range, &segment)
         .await
         .map_err(|e| {
             tracing::error!("Error counting visitors by device: {}", e);
             warp::reject::custom(DatabaseError)
         })?;
 
-    Ok(warp::reply::json(&TrackingResponse {
+    Ok(TrackingResponse {
         name: tracking_name,
+        timezone,
         session_count_by_weekday,
         visitor_count_by_weekday,
         session_count_by_hour,
@@ -151,9 +200,317 @@ pub async fn get_tracking(db: DB, tracking_id: i32) -> Result<impl warp::Reply,
         visitor_count_by_os,
         visitor_count_by_browser,
         visitor_count_by_device,
+    })
+}
+
+#[derive(Deserialize)]
+pub struct TimeSeriesQuery {
+    #[serde(default)]
+    interval: Interval,
+    #[serde(flatten)]
+    range: DateRange,
+    #[serde(flatten)]
+    segment: Segment,
+}
+
+#[derive(Serialize)]
+struct TimeSeriesResponse {
+    points: Vec<TimeSeriesPoint>,
+}
+
+const MAX_TIME_SERIES_BUCKETS: i64 = 1000;
+
+fn check_buckets(range: &DateRange, interval: Interval) -> Result<(), warp::Rejection> {
+    if interval.buckets(range) > MAX_TIME_SERIES_BUCKETS {
+        tracing::error!("Time series range has too many buckets");
+        return Err(warp::reject::custom(TooManyBuckets));
+    }
+
+    Ok(())
+}
+
+pub async fn time_series(
+    db: DB,
+    tracking_id: i32,
+    query: TimeSeriesQuery,
+    compare: CompareQuery,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    tracing::info!("Getting time series: {}", tracking_id);
+
+    let TimeSeriesQuery {
+        interval,
+        range,
+        segment,
+    } = query;
+    check_buckets(&range, interval)?;
+
+    let series = compare
+        .run(range, |range| {
+            load_time_series(db.clone(), tracking_id, range, segment.clone(), interval)
+        })
+        .await?;
+
+    Ok(warp::reply::json(&series))
+}
+
+async fn load_time_series(
+    db: DB,
+    tracking_id: i32,
+    range: DateRange,
+    segment: Segment,
+    interval: Interval,
+) -> Result<TimeSeriesResponse, warp::Rejection> {
+    let points = db
+        .time_series(tracking_id, &range, &segment, interval)
+        .await
+        .map_err(|e| {
+            tracing::error!("Error getting time series: {}", e);
+            warp::reject::custom(DatabaseError)
+        })?;
+
+    Ok(TimeSeriesResponse { points })
+}
+
+#[derive(Serialize)]
+struct NewVsReturningResponse {
+    points: Vec<NewVsReturningPoint>,
+}
+
+pub async fn new_vs_returning(
+    db: DB,
+    tracking_id: i32,
+    query: TimeSeriesQuery,
+    compare: CompareQuery,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    tracing::info!("Getting new vs returning visitors: {}", tracking_id);
+
+    let TimeSeriesQuery {
+        interval,
+        range,
+        segment,
+    } = query;
+    check_buckets(&range, interval)?;
+
+    let series = compare
+        .run(range, |range| {
+            load_new_vs_returning(db.clone(), tracking_id, range, segment.clone(), interval)
+        })
+        .await?;
+
+    Ok(warp::reply::json(&series))
+}
+
+async fn load_new_vs_returning(
+    db: DB,
+    tracking_id: i32,
+    range: DateRange,
+    segment: Segment,
+    interval: Interval,
+) -> Result<NewVsReturningResponse, warp::Rejection> {
+    let points = db
+        .new_vs_returning(tracking_id, &range, &segment, interval)
+        .await
+        .map_err(|e| {
+            tracing::error!("Error getting new vs returning visitors: {}", e);
+            warp::reject::custom(DatabaseError)
+        })?;
+
+    Ok(NewVsReturningResponse { points })
+}
+
+#[derive(Serialize)]
+struct SessionsPerVisitorResponse {
+    distribution: Vec<SessionsPerVisitor>,
+}
+
+pub async fn sessions_per_visitor(
+    db: DB,
+    tracking_id: i32,
+    range: DateRange,
+    segment: Segment,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    tracing::info!("Getting sessions per visitor: {}", tracking_id);
+
+    let distribution = db
+        .sessions_per_visitor(tracking_id, &range, &segment)
+        .await
+        .map_err(|e| {
+            tracing::error!("Error getting sessions per visitor: {}", e);
+            warp::reject::custom(DatabaseError)
+        })?;
+
+    Ok(warp::reply::json(&SessionsPerVisitorResponse {
+        distribution,
     }))
 }
 
+#[derive(Serialize)]
+struct EngagementResponse {
+    #[serde(flatten)]
+    engagement: SessionEngagement,
+    histogram: Vec<DurationBucket>,
+}
+
+pub async fn engagement(
+    db: DB,
+    tracking_id: i32,
+    range: DateRange,
+    segment: Segment,
+    compare: CompareQuery,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    tracing::info!("Getting engagement: {}", tracking_id);
+
+    let engagement = compare
+        .run(range, |range| {
+            load_engagement(db.clone(), tracking_id, range, segment.clone())
+        })
+        .await?;
+
+    Ok(warp::reply::json(&engagement))
+}
+
+async fn load_engagement(
+    db: DB,
+    tracking_id: i32,
+    range: DateRange,
+    segment: Segment,
+) -> Result<EngagementResponse, warp::Rejection> {
+    let engagement = db
+        .session_engagement(tracking_id, &range, &segment)
+        .await
+        .map_err(|e| {
+            tracing::error!("Error getting session engagement: {}", e);
+            warp::reject::custom(DatabaseError)
+        })?;
+    let histogram = db
+        .session_duration_histogram(tracking_id, &range, &segment)
+        .await
+        .map_err(|e| {
+            tracing::error!("Error getting session duration histogram: {}", e);
+            warp::reject::custom(DatabaseError)
+        })?;
+
+    Ok(EngagementResponse {
+        engagement,
+        histogram,
+    })
+}
+
+#[derive(Serialize)]
+struct PageEngagementResponse {
+    pages: Vec<PageEngagement>,
+}
+
+pub async fn page_engagement(
+    db: DB,
+    tracking_id: i32,
+    range: DateRange,
+    segment: Segment,
+    compare: CompareQuery,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    tracing::info!("Getting page engagement: {}", tracking_id);
+
+    let pages = compare
+        .run(range, |range| {
+            load_page_engagement(db.clone(), tracking_id, range, segment.clone())
+        })
+        .await?;
+
+    Ok(warp::reply::json(&pages))
+}
+
+async fn load_page_engagement(
+    db: DB,
+    tracking_id: i32,
+    range: DateRange,
+    segment: Segment,
+) -> Result<PageEngagementResponse, warp::Rejection> {
+    let pages = db
+        .page_engagement(tracking_id, &range, &segment)
+        .await
+        .map_err(|e| {
+            tracing::error!("Error getting page engagement: {}", e);
+            warp::reject::custom(DatabaseError)
+        })?;
+
+    Ok(PageEngagementResponse { pages })
+}
+
+const DEFAULT_LIVE_WINDOW_SECONDS: u64 = 5 * 60;
+const LIVE_REFRESH_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Deserialize)]
+pub struct LiveQuery {
+    window: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct ActiveSessionsUpdate {
+    active_sessions: usize,
+    window: u64,
+}
+
+// Pushes `active` events with the number of sessions started and not ended
+// within the window, and a `session_start` event for every new session. The
+// stream ends when the server shuts down, so it doesn't hold the shutdown up.
+pub async fn live(
+    realtime: Realtime,
+    tracking_id: i32,
+    query: LiveQuery,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    tracing::info!("Streaming live sessions: {}", tracking_id);
+
+    let window = query
+        .window
+        .unwrap_or(DEFAULT_LIVE_WINDOW_SECONDS)
+        .clamp(1, MAX_ACTIVE_WINDOW.as_secs());
+    let updates = realtime.subscribe();
+    let refresh = tokio::time::interval(LIVE_REFRESH_INTERVAL);
+
+    let events = futures_util::stream::unfold(
+        (realtime, updates, refresh, VecDeque::new()),
+        move |(realtime, mut updates, mut refresh, mut pending)| async move {
+            loop {
+                if let Some(event) = pending.pop_front() {
+                    return Some((event, (realtime, updates, refresh, pending)));
+                }
+
+                let changed = tokio::select! {
+                    _ = realtime.shutting_down() => return None,
+                    _ = refresh.tick() => true,
+                    update = updates.recv() => match update {
+                        Ok(LiveUpdate::SessionStarted { tracking_id: id, session }) if id == tracking_id => {
+                            pending.push_back(
+                                warp::sse::Event::default()
+                                    .event("session_start")
+                                    .json_data(session),
+                            );
+                            true
+                        }
+                        Ok(LiveUpdate::SessionEnded { tracking_id: id }) => id == tracking_id,
+                        Ok(LiveUpdate::SessionStarted { .. }) => false,
+                        Err(RecvError::Lagged(_)) => true,
+                        Err(RecvError::Closed) => return None,
+                    },
+                };
+
+                if changed {
+                    let active_sessions =
+                        realtime.active_sessions(tracking_id, Duration::from_secs(window));
+                    pending.push_back(warp::sse::Event::default().event("active").json_data(
+                        ActiveSessionsUpdate {
+                            active_sessions,
+                            window,
+                        },
+                    ));
+                }
+            }
+        },
+    );
+
+    Ok(warp::sse::reply(warp::sse::keep_alive().stream(events)))
+}
+
 #[derive(Deserialize)]
 pub struct RenameTrackingRequest {
     name: String,
@@ -179,16 +536,32 @@ pub async fn rename_tracking(
     ))
 }
 
-pub async fn delete_tracking(
+#[derive(Deserialize)]
+pub struct SetTimezoneRequest {
+    timezone: String,
+}
+
+pub async fn set_tracking_timezone(
     db: DB,
     tracking_id: i32,
+    req: SetTimezoneRequest,
 ) -> Result<impl warp::Reply, warp::Rejection> {
-    tracing::info!("Deleting tracking: {}", tracking_id);
+    tracing::info!("Setting tracking timezone: {}", tracking_id);
 
-    db.delete_tracking(tracking_id).await.map_err(|e| {
-        tracing::error!("Error deleting tracking: {}", e);
+    let valid = db.is_valid_timezone(&req.timezone).await.map_err(|e| {
+        tracing::error!("Error validating timezone: {}", e);
         warp::reject::custom(DatabaseError)
     })?;
+    if !valid {
+        return Err(warp::reject::custom(InvalidTimezone));
+    }
+
+    db.set_tracking_timezone(tracking_id, &req.timezone)
+        .await
+        .map_err(|e| {
+            tracing::error!("Error setting tracking timezone: {}", e);
+            warp::reject::custom(DatabaseError)
+        })?;
 
     Ok(warp::reply::with_status(
         warp::reply(),
@@ -196,112 +569,424 @@ pub async fn delete_tracking(
     ))
 }
 
-#[derive(Serialize)]
-struct TrackingCountsResponse {
-    sources: Vec<SingleSource>,
-    paths: Vec<CountByPathname>,
-    titles: Vec<CountByTitle>,
-    refers: Vec<SingleReferer>,
-    countries: Vec<CountByCountry>,
-    referrals: Vec<CountByReferral>,
+#[derive(Deserialize)]
+pub struct SetCookielessRequest {
+    cookieless: bool,
 }
 
-pub async fn tracking_counts(
+pub async fn set_tracking_cookieless(
     db: DB,
     tracking_id: i32,
+    req: SetCookielessRequest,
 ) -> Result<impl warp::Reply, warp::Rejection> {
-    tracing::info!("Getting tracking counts: {}", tracking_id);
+    tracing::info!("Setting tracking cookieless mode: {}", tracking_id);
 
-    let mut sources = db.list_sources(tracking_id).await.map_err(|e| {
-        tracing::error!("Error listing sources: {}", e);
-        warp::reject::custom(DatabaseError)
-    })?;
-    let direct = db
-        .visitors_and_sessions_no_source(tracking_id)
+    db.set_tracking_cookieless(tracking_id, req.cookieless)
         .await
         .map_err(|e| {
-            tracing::error!("Error counting direct visitors: {}", e);
+            tracing::error!("Error setting tracking cookieless mode: {}", e);
             warp::reject::custom(DatabaseError)
         })?;
-    sources.push(direct);
 
-    let paths = db
-        .count_sessions_by_pathname(tracking_id)
+    Ok(warp::reply::with_status(
+        warp::reply(),
+        warp::http::StatusCode::NO_CONTENT,
+    ))
+}
+
+#[derive(Deserialize)]
+pub struct SetOptOutPolicyRequest {
+    policy: OptOutPolicy,
+}
+
+pub async fn set_tracking_opt_out_policy(
+    db: DB,
+    tracking_id: i32,
+    req: SetOptOutPolicyRequest,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    tracing::info!("Setting tracking opt-out policy: {}", tracking_id);
+
+    db.set_tracking_opt_out_policy(tracking_id, req.policy)
         .await
         .map_err(|e| {
-            tracing::error!("Error counting sessions by pathname: {}", e);
+            tracing::error!("Error setting tracking opt-out policy: {}", e);
             warp::reject::custom(DatabaseError)
         })?;
-    let titles = db.count_sessions_by_title(tracking_id).await.map_err(|e| {
-        tracing::error!("Error counting sessions by title: {}", e);
-        warp::reject::custom(DatabaseError)
-    })?;
-    let refers = db.list_refers(tracking_id).await.map_err(|e| {
-        tracing::error!("Error listing refers: {}", e);
+
+    Ok(warp::reply::with_status(
+        warp::reply(),
+        warp::http::StatusCode::NO_CONTENT,
+    ))
+}
+
+#[derive(Serialize)]
+struct OptOutsResponse {
+    suppressed: i64,
+    page_views: i64,
+    pages: Vec<OptedOutHits>,
+}
+
+pub async fn opt_outs(
+    db: DB,
+    tracking_id: i32,
+    range: DateRange,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    tracing::info!("Getting opted out hits: {}", tracking_id);
+
+    let hits = db.opted_out_hits(tracking_id, &range).await.map_err(|e| {
+        tracing::error!("Error getting opted out hits: {}", e);
         warp::reject::custom(DatabaseError)
     })?;
 
-    let countries = db
-        .count_sessions_by_country(tracking_id)
-        .await
-        .map_err(|e| {
-            tracing::error!("Error counting sessions by country: {}", e);
-            warp::reject::custom(DatabaseError)
-        })?;
-
-    let referrals = db
-        .count_sessions_by_referral(tracking_id)
-        .await
-        .map_err(|e| {
-            tracing::error!("Error counting sessions by referral: {}", e);
-            warp::reject::custom(DatabaseError)
-        })?;
+    let suppressed = hits.iter().map(OptedOutHits::suppressed).sum();
+    let page_views = hits.iter().map(OptedOutHits::page_views).sum();
+    let pages = hits
+        .into_iter()
+        .filter(|hits| hits.page_views() > 0)
+        .collect();
 
-    Ok(warp::reply::json(&TrackingCountsResponse {
-        sources,
-        paths,
-        titles,
-        refers,
-        countries,
-        referrals,
+    Ok(warp::reply::json(&OptOutsResponse {
+        suppressed,
+        page_views,
+        pages,
     }))
 }
 
-// Source Routes
+fn default_retention_fold() -> bool {
+    true
+}
 
+// `days` left out keeps raw data forever.
 #[derive(Deserialize)]
-pub struct CreateSourceRequest {
-    name: String,
+pub struct SetRetentionRequest {
+    days: Option<i32>,
+    #[serde(default = "default_retention_fold")]
+    fold: bool,
 }
 
-pub async fn create_source(
+pub async fn set_tracking_retention(
     db: DB,
     tracking_id: i32,
-    request: CreateSourceRequest,
+    req: SetRetentionRequest,
 ) -> Result<impl warp::Reply, warp::Rejection> {
-    tracing::info!("Creating source: {}", request.name);
+    tracing::info!("Setting tracking retention: {}", tracking_id);
 
-    db.create_source(&request.name, tracking_id)
+    if req.days.is_some_and(|days| days <= 0) {
+        return Err(warp::reject::custom(InvalidRetention));
+    }
+
+    db.set_tracking_retention(tracking_id, req.days, req.fold)
         .await
         .map_err(|e| {
-            tracing::error!("Error creating source: {}", e);
+            tracing::error!("Error setting tracking retention: {}", e);
             warp::reject::custom(DatabaseError)
         })?;
 
     Ok(warp::reply::with_status(
         warp::reply(),
-        warp::http::StatusCode::CREATED,
+        warp::http::StatusCode::NO_CONTENT,
     ))
 }
 
-pub async fn delete_source(
+pub async fn delete_tracking(
     db: DB,
     tracking_id: i32,
-    source_name: String,
 ) -> Result<impl warp::Reply, warp::Rejection> {
-    tracing::info!("Deleting source: {}", source_name);
+    tracing::info!("Deleting tracking: {}", tracking_id);
 
-    db.delete_source(&source_name, tracking_id)
+    db.delete_tracking(tracking_id).await.map_err(|e| {
+        tracing::error!("Error deleting tracking: {}", e);
+        warp::reject::custom(DatabaseError)
+    })?;
+
+    Ok(warp::reply::with_status(
+        warp::reply(),
+        warp::http::StatusCode::NO_CONTENT,
+    ))
+}
+
+#[derive(Serialize)]
+struct TrackingCountsResponse {
+    sources: Vec<SingleSource>,
+    paths: Vec<CountByPathname>,
+    titles: Vec<CountByTitle>,
+    refers: Vec<SingleReferer>,
+    countries: Vec<CountByCountry>,
+    referrals: Vec<CountByReferral>,
+}
+
+pub async fn tracking_counts(
+    db: DB,
+    tracking_id: i32,
+    range: DateRange,
+    segment: Segment,
+    compare: CompareQuery,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    tracing::info!("Getting tracking counts: {}", tracking_id);
+
+    let counts = compare
+        .run(range, |range| {
+            load_tracking_counts(db.clone(), tracking_id, range, segment.clone())
+        })
+        .await?;
+
+    Ok(warp::reply::json(&counts))
+}
+
+async fn load_tracking_counts(
+    db: DB,
+    tracking_id: i32,
+    range: DateRange,
+    segment: Segment,
+) -> Result<TrackingCountsResponse, warp::Rejection> {
+    let mut sources = db
+        .list_sources(tracking_id, &range, &segment)
+        .await
+        .map_err(|e| {
+            tracing::error!("Error listing sources: {}", e);
+            warp::reject::custom(DatabaseError)
+        })?;
+    let direct = db
+        .visitors_and_sessions_no_source(tracking_id, &range, &segment)
+        .await
+        .map_err(|e| {
+            tracing::error!("Error counting direct visitors: {}", e);
+            warp::reject::custom(DatabaseError)
+        })?;
+    sources.push(direct);
+
+    let paths = db
+        .count_sessions_by_pathname(tracking_id, &range, &segment)
+        .await
+        .map_err(|e| {
+            tracing::error!("Error counting sessions by pathname: {}", e);
+            warp::reject::custom(DatabaseError)
+        })?;
+    let titles = db
+        .count_sessions_by_title(tracking_id, &range, &segment)
+        .await
+        .map_err(|e| {
+            tracing::error!("Error counting sessions by title: {}", e);
+            warp::reject::custom(DatabaseError)
+        })?;
+    let refers = db
+        .list_refers(tracking_id, &range, &segment)
+        .await
+        .map_err(|e| {
+            tracing::error!("Error listing refers: {}", e);
+            warp::reject::custom(DatabaseError)
+        })?;
+
+    let countries = db
+        .count_sessions_by_country(tracking_id, &range, &segment)
+        .await
+        .map_err(|e| {
+            tracing::error!("Error counting sessions by country: {}", e);
+            warp::reject::custom(DatabaseError)
+        })?;
+
+    let referrals = db
+        .count_sessions_by_referral(tracking_id, &range, &segment)
+        .await
+        .map_err(|e| {
+            tracing::error!("Error counting sessions by referral: {}", e);
+            warp::reject::custom(DatabaseError)
+        })?;
+
+    Ok(TrackingCountsResponse {
+        sources,
+        paths,
+        titles,
+        refers,
+        countries,
+        referrals,
+    })
+}
+
+// Event Routes
+
+#[derive(Serialize)]
+struct TrackingEventsResponse {
+    types: Vec<CountByEventType>,
+    targets: Vec<CountByEventTarget>,
+}
+
+pub async fn tracking_events(
+    db: DB,
+    tracking_id: i32,
+    range: DateRange,
+    segment: Segment,
+    compare: CompareQuery,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    tracing::info!("Getting tracking events: {}", tracking_id);
+
+    let events = compare
+        .run(range, |range| {
+            load_tracking_events(db.clone(), tracking_id, range, segment.clone())
+        })
+        .await?;
+
+    Ok(warp::reply::json(&events))
+}
+
+async fn load_tracking_events(
+    db: DB,
+    tracking_id: i32,
+    range: DateRange,
+    segment: Segment,
+) -> Result<TrackingEventsResponse, warp::Rejection> {
+    let types = db
+        .count_events_by_type(tracking_id, &range, &segment)
+        .await
+        .map_err(|e| {
+            tracing::error!("Error counting events by type: {}", e);
+            warp::reject::custom(DatabaseError)
+        })?;
+    let targets = db
+        .count_events_by_target(tracking_id, &range, &segment)
+        .await
+        .map_err(|e| {
+            tracing::error!("Error counting events by target: {}", e);
+            warp::reject::custom(DatabaseError)
+        })?;
+
+    Ok(TrackingEventsResponse { types, targets })
+}
+
+#[derive(Serialize)]
+struct EventsTimelineResponse {
+    days: Vec<EventCountByDay>,
+}
+
+pub async fn events_timeline(
+    db: DB,
+    tracking_id: i32,
+    range: DateRange,
+    segment: Segment,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    tracing::info!("Getting events timeline: {}", tracking_id);
+
+    let days = db
+        .count_events_by_day(tracking_id, &range, &segment)
+        .await
+        .map_err(|e| {
+            tracing::error!("Error counting events by day: {}", e);
+            warp::reject::custom(DatabaseError)
+        })?;
+
+    Ok(warp::reply::json(&EventsTimelineResponse { days }))
+}
+
+#[derive(Deserialize)]
+pub struct RecentEventsQuery {
+    limit: Option<i64>,
+}
+
+#[derive(Serialize)]
+struct RecentEventsResponse {
+    events: Vec<RecentEvent>,
+}
+
+pub async fn recent_events(
+    db: DB,
+    tracking_id: i32,
+    range: DateRange,
+    segment: Segment,
+    query: RecentEventsQuery,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    tracing::info!("Listing recent events: {}", tracking_id);
+
+    let limit = query.limit.unwrap_or(50).clamp(1, 500);
+    let events = db
+        .list_recent_events(tracking_id, &range, &segment, limit)
+        .await
+        .map_err(|e| {
+            tracing::error!("Error listing recent events: {}", e);
+            warp::reject::custom(DatabaseError)
+        })?;
+
+    Ok(warp::reply::json(&RecentEventsResponse { events }))
+}
+
+#[derive(Deserialize)]
+pub struct EventPropertyQuery {
+    property: String,
+    #[serde(rename = "type")]
+    event_type: Option<String>,
+    #[serde(flatten)]
+    range: DateRange,
+    #[serde(flatten)]
+    segment: Segment,
+}
+
+#[derive(Serialize)]
+struct EventPropertyResponse {
+    property: String,
+    values: Vec<CountByProperty>,
+}
+
+pub async fn event_property_breakdown(
+    db: DB,
+    tracking_id: i32,
+    query: EventPropertyQuery,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    tracing::info!("Breaking down events by property: {}", query.property);
+
+    let values = db
+        .count_events_by_property(
+            tracking_id,
+            &query.range,
+            &query.segment,
+            &query.property,
+            query.event_type.as_deref(),
+        )
+        .await
+        .map_err(|e| {
+            tracing::error!("Error counting events by property: {}", e);
+            warp::reject::custom(DatabaseError)
+        })?;
+
+    Ok(warp::reply::json(&EventPropertyResponse {
+        property: query.property,
+        values,
+    }))
+}
+
+// Source Routes
+
+#[derive(Deserialize)]
+pub struct CreateSourceRequest {
+    name: String,
+}
+
+pub async fn create_source(
+    db: DB,
+    tracking_id: i32,
+    request: CreateSourceRequest,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    tracing::info!("Creating source: {}", request.name);
+
+    db.create_source(&request.name, tracking_id)
+        .await
+        .map_err(|e| {
+            tracing::error!("Error creating source: {}", e);
+            warp::reject::custom(DatabaseError)
+        })?;
+
+    Ok(warp::reply::with_status(
+        warp::reply(),
+        warp::http::StatusCode::CREATED,
+    ))
+}
+
+pub async fn delete_source(
+    db: DB,
+    tracking_id: i32,
+    source_name: String,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    tracing::info!("Deleting source: {}", source_name);
+
+    db.delete_source(&source_name, tracking_id)
         .await
         .map_err(|e| {
             tracing::error!("Error creating source: {}", e);
@@ -313,3 +998,495 @@ pub async fn delete_source(
         warp::http::StatusCode::NO_CONTENT,
     ))
 }
+
+// API Key Routes
+
+#[derive(Deserialize)]
+pub struct CreateApiKeyRequest {
+    name: String,
+}
+
+pub async fn create_api_key(
+    db: DB,
+    tracking_id: i32,
+    request: CreateApiKeyRequest,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    tracing::info!("Creating api key: {}", request.name);
+
+    let new_key = NewApiKeyData::new(request.name, tracking_id);
+    let key = db.create_api_key(&new_key).await.map_err(|e| {
+        tracing::error!("Error creating api key: {}", e);
+        warp::reject::custom(DatabaseError)
+    })?;
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&key),
+        warp::http::StatusCode::CREATED,
+    ))
+}
+
+#[derive(Serialize)]
+struct ApiKeysResponse {
+    keys: Vec<SingleApiKey>,
+}
+
+pub async fn list_api_keys(db: DB, tracking_id: i32) -> Result<impl warp::Reply, warp::Rejection> {
+    tracing::info!("Listing api keys: {}", tracking_id);
+
+    let keys = db.list_api_keys(tracking_id).await.map_err(|e| {
+        tracing::error!("Error listing api keys: {}", e);
+        warp::reject::custom(DatabaseError)
+    })?;
+
+    Ok(warp::reply::json(&ApiKeysResponse { keys }))
+}
+
+pub async fn revoke_api_key(
+    db: DB,
+    tracking_id: i32,
+    key_id: String,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    tracing::info!("Revoking api key: {}", key_id);
+
+    let revoked = db.revoke_api_key(&key_id, tracking_id).await.map_err(|e| {
+        tracing::error!("Error revoking api key: {}", e);
+        warp::reject::custom(DatabaseError)
+    })?;
+
+    if !revoked {
+        tracing::error!("Api key {} not found in tracking {}", key_id, tracking_id);
+        return Err(warp::reject::custom(UnknownApiKey));
+    }
+
+    Ok(warp::reply::with_status(
+        warp::reply(),
+        warp::http::StatusCode::NO_CONTENT,
+    ))
+}
+
+// Export Routes
+
+const EXPORT_CHUNK_SIZE: usize = 32 * 1024;
+
+type ExportError = Box<dyn std::error::Error + Send + Sync>;
+
+#[derive(Debug, Clone, Copy)]
+pub enum ExportKind {
+    Visitors,
+    Sessions,
+    Events,
+}
+
+impl FromStr for ExportKind {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "visitors" => Ok(ExportKind::Visitors),
+            "sessions" => Ok(ExportKind::Sessions),
+            "events" => Ok(ExportKind::Events),
+            _ => Err(()),
+        }
+    }
+}
+
+impl ExportKind {
+    fn name(self) -> &'static str {
+        match self {
+            ExportKind::Visitors => "visitors",
+            ExportKind::Sessions => "sessions",
+            ExportKind::Events => "events",
+        }
+    }
+}
+
+#[derive(Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    #[default]
+    Csv,
+    Ndjson,
+}
+
+impl ExportFormat {
+    fn content_type(self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "text/csv; charset=utf-8",
+            ExportFormat::Ndjson => "application/x-ndjson",
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "csv",
+            ExportFormat::Ndjson => "ndjson",
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ExportQuery {
+    #[serde(default)]
+    format: ExportFormat,
+}
+
+pub async fn export(
+    db: DB,
+    tracking_id: i32,
+    kind: ExportKind,
+    range: DateRange,
+    query: ExportQuery,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    tracing::info!("Exporting {}: {}", kind.name(), tracking_id);
+
+    let format = query.format;
+
+    Ok(stream_export(
+        kind.name(),
+        tracking_id,
+        format,
+        move |chunks| async move {
+            match kind {
+                ExportKind::Visitors => {
+                    write_export(db.export_visitors(tracking_id, &range), format, &chunks).await
+                }
+                ExportKind::Sessions => {
+                    write_export(db.export_sessions(tracking_id, &range), format, &chunks).await
+                }
+                ExportKind::Events => {
+                    let events = db.export_events(tracking_id, &range);
+                    let events = match format {
+                        ExportFormat::Csv => {
+                            events.map_ok(ExportedEvent::with_text_properties).boxed()
+                        }
+                        ExportFormat::Ndjson => events,
+                    };
+                    write_export(events, format, &chunks).await
+                }
+            }
+        },
+    ))
+}
+
+type ExportChunks = mpsc::Sender<Result<Vec<u8>, ExportError>>;
+
+fn stream_export<F, Fut>(
+    name: &'static str,
+    tracking_id: i32,
+    format: ExportFormat,
+    write: F,
+) -> warp::reply::Response
+where
+    F: FnOnce(ExportChunks) -> Fut,
+    Fut: Future<Output = Result<(), ExportError>> + Send + 'static,
+{
+    // The channel only holds a couple of chunks, so a slow client slows down
+    // the database cursor instead of buffering the export in memory.
+    let (chunks, receiver) = mpsc::channel(2);
+    let written = write(chunks.clone());
+
+    tokio::spawn(async move {
+        // Failing mid-stream aborts the body so the client never mistakes a
+        // truncated export for a complete one.
+        if let Err(e) = written.await {
+            tracing::error!("Error exporting {}: {}", name, e);
+            let _ = chunks.send(Err(e)).await;
+        }
+    });
+
+    let body = futures_util::stream::unfold(receiver, |mut receiver| async move {
+        receiver.recv().await.map(|chunk| (chunk, receiver))
+    });
+
+    let filename = format!(
+        "attachment; filename=\"{}-{}.{}\"",
+        name,
+        tracking_id,
+        format.extension()
+    );
+
+    warp::http::Response::builder()
+        .header("content-type", format.content_type())
+        .header("content-disposition", filename)
+        .body(warp::hyper::Body::wrap_stream(body))
+        .expect("failed to create export response")
+}
+
+async fn write_export<T: Serialize + Default>(
+    mut rows: BoxStream<'_, sqlx::Result<T>>,
+    format: ExportFormat,
+    chunks: &ExportChunks,
+) -> Result<(), ExportError> {
+    let mut writer = ExportWriter::new(format, true);
+    let mut empty = true;
+
+    while let Some(row) = rows.try_next().await? {
+        empty = false;
+        writer.write(&row)?;
+
+        if writer.len() >= EXPORT_CHUNK_SIZE {
+            let chunk = std::mem::replace(&mut writer, ExportWriter::new(format, false));
+            if chunks.send(Ok(chunk.finish()?)).await.is_err() {
+                // The client went away, so there's no one left to export to.
+                return Ok(());
+            }
+        }
+    }
+
+    if empty {
+        writer.write_headers::<T>()?;
+    }
+
+    let chunk = writer.finish()?;
+    if !chunk.is_empty() {
+        let _ = chunks.send(Ok(chunk)).await;
+    }
+
+    Ok(())
+}
+
+// CSV headers are only written by the writer of the first chunk.
+enum ExportWriter {
+    Csv(Box<csv::Writer<Vec<u8>>>),
+    Ndjson(Vec<u8>),
+}
+
+impl ExportWriter {
+    fn new(format: ExportFormat, headers: bool) -> Self {
+        let buffer = Vec::with_capacity(EXPORT_CHUNK_SIZE);
+        match format {
+            ExportFormat::Csv => ExportWriter::Csv(Box::new(
+                csv::WriterBuilder::new()
+                    .has_headers(headers)
+                    .from_writer(buffer),
+            )),
+            ExportFormat::Ndjson => ExportWriter::Ndjson(buffer),
+        }
+    }
+
+    fn write<T: Serialize>(&mut self, row: &T) -> Result<(), ExportError> {
+        match self {
+            ExportWriter::Csv(writer) => writer.serialize(row)?,
+            ExportWriter::Ndjson(buffer) => {
+                serde_json::to_writer(&mut *buffer, row)?;
+                buffer.push(b'\n');
+            }
+        }
+        Ok(())
+    }
+
+    // The csv writer only writes headers along with the first row, so an
+    // empty export takes them from a default row that is then thrown away.
+    fn write_headers<T: Serialize + Default>(&mut self) -> Result<(), ExportError> {
+        if let ExportWriter::Csv(writer) = self {
+            let mut default_row = csv::Writer::from_writer(Vec::new());
+            default_row.serialize(T::default())?;
+            let default_row = default_row.into_inner().map_err(|e| e.into_error())?;
+
+            let headers = csv::Reader::from_reader(default_row.as_slice())
+                .headers()?
+                .clone();
+            writer.write_record(&headers)?;
+        }
+        Ok(())
+    }
+
+    // The csv writer keeps its own buffer, so this only counts what it has
+    // already flushed.
+    fn len(&self) -> usize {
+        match self {
+            ExportWriter::Csv(writer) => writer.get_ref().len(),
+            ExportWriter::Ndjson(buffer) => buffer.len(),
+        }
+    }
+
+    fn finish(self) -> Result<Vec<u8>, ExportError> {
+        match self {
+            ExportWriter::Csv(writer) => Ok(writer.into_inner().map_err(|e| e.into_error())?),
+            ExportWriter::Ndjson(buffer) => Ok(buffer),
+        }
+    }
+}
+
+// Visitor Routes
+
+#[derive(Deserialize)]
+pub struct VisitorsQuery {
+    #[serde(default)]
+    identified: bool,
+    search: Option<String>,
+    limit: Option<i64>,
+    offset: Option<i64>,
+}
+
+#[derive(Serialize)]
+struct VisitorsResponse {
+    visitors: Vec<VisitorSummary>,
+}
+
+pub async fn list_visitors(
+    db: DB,
+    tracking_id: i32,
+    query: VisitorsQuery,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    tracing::info!("Listing visitors: {}", tracking_id);
+
+    let limit = query.limit.unwrap_or(50).clamp(1, 500);
+    let offset = query.offset.unwrap_or(0).max(0);
+    let visitors = db
+        .list_visitors(
+            tracking_id,
+            query.identified,
+            query.search.as_deref(),
+            limit,
+            offset,
+        )
+        .await
+        .map_err(|e| {
+            tracing::error!("Error listing visitors: {}", e);
+            warp::reject::custom(DatabaseError)
+        })?;
+
+    Ok(warp::reply::json(&VisitorsResponse { visitors }))
+}
+
+#[derive(Serialize)]
+struct VisitorResponse {
+    #[serde(flatten)]
+    profile: VisitorProfile,
+    timeline: Vec<TimelineEntry>,
+}
+
+pub async fn get_visitor(
+    db: DB,
+    tracking_id: i32,
+    visitor_id: String,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    tracing::info!("Getting visitor: {}", visitor_id);
+
+    let profile = db
+        .visitor_profile(tracking_id, &visitor_id)
+        .await
+        .map_err(|e| {
+            tracing::error!("Error getting visitor profile: {}", e);
+            warp::reject::custom(DatabaseError)
+        })?
+        .ok_or_else(|| {
+            tracing::error!(
+                "Visitor {} not found in tracking {}",
+                visitor_id,
+                tracking_id
+            );
+            warp::reject::custom(UnknownVisitor)
+        })?;
+
+    let timeline = db.visitor_timeline(profile.id()).await.map_err(|e| {
+        tracing::error!("Error getting visitor timeline: {}", e);
+        warp::reject::custom(DatabaseError)
+    })?;
+
+    Ok(warp::reply::json(&VisitorResponse { profile, timeline }))
+}
+
+#[derive(Deserialize)]
+pub struct IdentifiedVisitorsExportQuery {
+    search: Option<String>,
+    #[serde(default)]
+    format: ExportFormat,
+}
+
+pub async fn export_identified_visitors(
+    db: DB,
+    tracking_id: i32,
+    query: IdentifiedVisitorsExportQuery,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    tracing::info!("Exporting identified visitors: {}", tracking_id);
+
+    let format = query.format;
+    let search = query.search;
+
+    Ok(stream_export(
+        "identified-visitors",
+        tracking_id,
+        format,
+        move |chunks| async move {
+            let rows = db.export_identified_visitors(tracking_id, search.as_deref());
+            let rows = match format {
+                ExportFormat::Csv => rows
+                    .map_ok(ExportedIdentifiedVisitor::with_text_traits)
+                    .boxed(),
+                ExportFormat::Ndjson => rows,
+            };
+            write_export(rows, format, &chunks).await
+        },
+    ))
+}
+
+// Data Subject Routes
+
+#[derive(Deserialize)]
+pub struct DataSubjectQuery {
+    visitor_id: Option<String>,
+    email: Option<String>,
+}
+
+impl DataSubjectQuery {
+    fn subject(self) -> Result<DataSubject, warp::Rejection> {
+        match (self.visitor_id, self.email) {
+            (Some(visitor_id), None) => Ok(DataSubject::VisitorId(visitor_id)),
+            (None, Some(email)) => Ok(DataSubject::Email(email)),
+            _ => {
+                tracing::error!("Data subject needs exactly one of visitor_id or email");
+                Err(warp::reject::custom(InvalidDataSubject))
+            }
+        }
+    }
+}
+
+pub async fn export_data_subject(
+    db: DB,
+    tracking_id: i32,
+    user_id: i32,
+    query: DataSubjectQuery,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    tracing::info!("Exporting data subject: {}", tracking_id);
+
+    let subject = query.subject()?;
+    let data = db
+        .export_data_subject(tracking_id, user_id, &subject)
+        .await
+        .map_err(|e| {
+            tracing::error!("Error exporting data subject: {}", e);
+            warp::reject::custom(DatabaseError)
+        })?;
+
+    if data.is_empty() {
+        return Err(warp::reject::custom(UnknownVisitor));
+    }
+
+    Ok(warp::reply::json(&data))
+}
+
+pub async fn erase_data_subject(
+    db: DB,
+    tracking_id: i32,
+    user_id: i32,
+    query: DataSubjectQuery,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    tracing::info!("Erasing data subject: {}", tracking_id);
+
+    let subject = query.subject()?;
+    let erased = db
+        .erase_data_subject(tracking_id, user_id, &subject)
+        .await
+        .map_err(|e| {
+            tracing::error!("Error erasing data subject: {}", e);
+            warp::reject::custom(DatabaseError)
+        })?;
+
+    if erased.is_empty() {
+        return Err(warp::reject::custom(UnknownVisitor));
+    }
+
+    Ok(warp::reply::json(&erased))
+}
@@ -1,5 +1,7 @@
+mod comparison;
 mod filters;
 mod handlers;
 
+pub use comparison::CompareQuery;
 pub use filters::*;
 pub use handlers::*;
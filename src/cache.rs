@@ -0,0 +1,111 @@
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+// A map whose entries expire `ttl` after they are inserted. Once it holds
+// `capacity` entries, the expired ones are dropped, or everything if none
+// are, so that it never grows past it.
+pub struct TtlCache<K, V> {
+    entries: Mutex<HashMap<K, (V, Instant)>>,
+    capacity: usize,
+    ttl: Duration,
+}
+
+impl<K: Eq + Hash, V: Copy> TtlCache<K, V> {
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        Self {
+            entries: Mutex::new(HashMap::new()),
+            capacity,
+            ttl,
+        }
+    }
+
+    pub fn get(&self, key: &K) -> Option<V> {
+        let mut entries = self.entries.lock().unwrap();
+
+        match entries.get(key) {
+            Some((value, inserted_at)) if inserted_at.elapsed() < self.ttl => Some(*value),
+            Some(_) => {
+                entries.remove(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    pub fn insert(&self, key: K, value: V) {
+        let mut entries = self.entries.lock().unwrap();
+
+        if entries.len() >= self.capacity && !entries.contains_key(&key) {
+            entries.retain(|_, (_, inserted_at)| inserted_at.elapsed() < self.ttl);
+            if entries.len() >= self.capacity {
+                entries.clear();
+            }
+        }
+
+        entries.insert(key, (value, Instant::now()));
+    }
+
+    pub fn remove(&self, key: &K) {
+        self.entries.lock().unwrap().remove(key);
+    }
+
+    pub fn retain(&self, mut keep: impl FnMut(&K, &V) -> bool) {
+        self.entries
+            .lock()
+            .unwrap()
+            .retain(|key, (value, _)| keep(key, value));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::TtlCache;
+
+    const TTL: Duration = Duration::from_secs(60);
+
+    #[test]
+    fn entries_expire_after_the_ttl() {
+        let cache = TtlCache::new(10, Duration::ZERO);
+        cache.insert("a", 1);
+
+        assert_eq!(cache.get(&"a"), None);
+    }
+
+    #[test]
+    fn a_full_cache_of_live_entries_is_cleared() {
+        let cache = TtlCache::new(2, TTL);
+        cache.insert("a", 1);
+        cache.insert("b", 2);
+        cache.insert("b", 3);
+
+        assert_eq!(cache.get(&"a"), Some(1));
+        assert_eq!(cache.get(&"b"), Some(3));
+
+        cache.insert("c", 4);
+
+        assert_eq!(cache.get(&"a"), None);
+        assert_eq!(cache.get(&"b"), None);
+        assert_eq!(cache.get(&"c"), Some(4));
+    }
+
+    #[test]
+    fn removed_and_filtered_entries_are_gone() {
+        let cache = TtlCache::new(10, TTL);
+        cache.insert(("x", 1), 1);
+        cache.insert(("x", 2), 2);
+        cache.insert(("y", 1), 3);
+
+        cache.remove(&("x", 1));
+        cache.retain(|(name, _), _| *name != "y");
+
+        assert_eq!(cache.get(&("x", 1)), None);
+        assert_eq!(cache.get(&("x", 2)), Some(2));
+        assert_eq!(cache.get(&("y", 1)), None);
+    }
+}
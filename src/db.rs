@@ -1,42 +1,95 @@
-use std::{convert::Infallible, net::SocketAddr, sync::Arc};
+use std::{convert::Infallible, sync::Arc, time::Duration};
 
-use maxminddb::geoip2;
-use serde::Serialize;
+use chrono::Months;
+use futures_util::stream::BoxStream;
+use serde::{Deserialize, Serialize};
 use sqlx::{
-    types::{chrono::NaiveDateTime, BigDecimal},
+    types::{
+        chrono::{NaiveDate, NaiveDateTime, Utc},
+        BigDecimal,
+    },
     FromRow, PgPool,
 };
 use uaparser::Parser;
 use warp::Filter;
 
-use crate::utils;
+use crate::{cache::TtlCache, utils};
 
 type Result<T> = std::result::Result<T, sqlx::Error>;
 
+const LOOKUPS_CAPACITY: usize = 10_000;
+const LOOKUPS_TTL: Duration = Duration::from_secs(5 * 60);
+
+// Every ingestion request resolves its tracking, source and visitor to their
+// row ids. Those are cached, and forgotten whenever the rows are deleted or
+// renamed.
+struct Lookups {
+    trackings: TtlCache<String, i32>,
+    sources: TtlCache<(i32, String), i32>,
+    visitors: TtlCache<(i32, String), i32>,
+}
+
 #[derive(Clone)]
 pub struct DB {
     pool: PgPool,
+    lookups: Arc<Lookups>,
 }
 
 impl DB {
     pub fn new(pool: PgPool) -> Self {
-        Self { pool }
+        let lookups = Lookups {
+            trackings: TtlCache::new(LOOKUPS_CAPACITY, LOOKUPS_TTL),
+            sources: TtlCache::new(LOOKUPS_CAPACITY, LOOKUPS_TTL),
+            visitors: TtlCache::new(LOOKUPS_CAPACITY, LOOKUPS_TTL),
+        };
+
+        Self {
+            pool,
+            lookups: Arc::new(lookups),
+        }
+    }
+
+    fn forget_tracking(&self, tracking_id: i32) {
+        self.lookups.trackings.retain(|_, id| *id != tracking_id);
+        self.lookups.sources.retain(|(id, _), _| *id != tracking_id);
+        self.forget_visitors(tracking_id);
+    }
+
+    fn forget_visitors(&self, tracking_id: i32) {
+        self.lookups
+            .visitors
+            .retain(|(id, _), _| *id != tracking_id);
     }
 }
 
 impl DB {
-    pub async fn id_from_visitor_id(&self, visitor_id: &str) -> Result<i32> {
+    pub async fn find_visitor_id(&self, tracking_id: i32, visitor_id: &str) -> Result<Option<i32>> {
+        let key = (tracking_id, visitor_id.to_owned());
+        if let Some(id) = self.lookups.visitors.get(&key) {
+            return Ok(Some(id));
+        }
+
         let rec = sqlx::query!(
-            r#"SELECT id FROM visitors WHERE visitor_id = $1"#,
+            r#"SELECT MIN(id) as id FROM visitors WHERE tracking_id = $1 AND visitor_id = $2"#,
+            tracking_id,
             visitor_id
         )
         .fetch_one(&self.pool)
         .await?;
 
+        if let Some(id) = rec.id {
+            self.lookups.visitors.insert(key, id);
+        }
+
         Ok(rec.id)
     }
 
     pub async fn id_from_source_name(&self, tracking_id: i32, name: &str) -> Result<i32> {
+        let key = (tracking_id, name.to_owned());
+        if let Some(id) = self.lookups.sources.get(&key) {
+            return Ok(id);
+        }
+
         let rec = sqlx::query!(
             r#"SELECT id FROM sources WHERE tracking_id = $1 AND name = $2"#,
             tracking_id,
@@ -44,6 +97,7 @@ impl DB {
         )
         .fetch_one(&self.pool)
         .await?;
+        self.lookups.sources.insert(key, rec.id);
 
         Ok(rec.id)
     }
@@ -51,17 +105,155 @@ impl DB {
     pub async fn id_from_tracking_id(&self, tracking_id: &str) -> Result<i32> {
         tracing::info!("Extracting tracking id: {:?}", tracking_id);
 
+        if let Some(id) = self.lookups.trackings.get(&tracking_id.to_owned()) {
+            return Ok(id);
+        }
+
         let rec = sqlx::query!(
             r#"SELECT id FROM trackings WHERE tracking_id = $1"#,
             tracking_id
         )
         .fetch_one(&self.pool)
         .await?;
+        self.lookups
+            .trackings
+            .insert(tracking_id.to_owned(), rec.id);
 
         Ok(rec.id)
     }
 }
 
+#[derive(Deserialize, Default)]
+#[serde(try_from = "UncheckedDateRange")]
+pub struct DateRange {
+    from: Option<NaiveDate>,
+    to: Option<NaiveDate>,
+}
+
+#[derive(Deserialize)]
+struct UncheckedDateRange {
+    #[serde(default, with = "optional_date_format")]
+    from: Option<NaiveDate>,
+    #[serde(default, with = "optional_date_format")]
+    to: Option<NaiveDate>,
+}
+
+// A range that ends before it starts is rejected as an invalid query.
+impl TryFrom<UncheckedDateRange> for DateRange {
+    type Error = &'static str;
+
+    fn try_from(range: UncheckedDateRange) -> std::result::Result<Self, Self::Error> {
+        match (range.from, range.to) {
+            (Some(from), Some(to)) if from > to => Err("from is after to"),
+            (from, to) => Ok(DateRange { from, to }),
+        }
+    }
+}
+
+// Narrows analytics down to the sessions matching every given field. Country
+// codes and referer hosts are matched case-insensitively. Queries pass the
+// fields as $4 to $11, in this order, to the `segment_matches` SQL function.
+#[derive(Deserialize, Default, Clone)]
+pub struct Segment {
+    country: Option<String>,
+    device: Option<String>,
+    os: Option<String>,
+    browser: Option<String>,
+    source: Option<String>,
+    referral: Option<String>,
+    referer_host: Option<String>,
+    pathname: Option<String>,
+}
+
+impl DateRange {
+    pub fn from(&self) -> Option<NaiveDate> {
+        self.from
+    }
+
+    pub fn to(&self) -> Option<NaiveDate> {
+        self.to
+    }
+
+    // Only a range with both ends can be shifted to an earlier window.
+    pub fn compared_to(&self, comparison: Comparison) -> Option<DateRange> {
+        let (from, to) = (self.from?, self.to?);
+        if to < from {
+            return None;
+        }
+
+        let (from, to) = match comparison {
+            Comparison::PreviousPeriod => {
+                let previous_to = from.pred_opt()?;
+                (previous_to - (to - from), previous_to)
+            }
+            Comparison::PreviousYear => (
+                from.checked_sub_months(Months::new(12))?,
+                to.checked_sub_months(Months::new(12))?,
+            ),
+        };
+
+        Some(DateRange {
+            from: Some(from),
+            to: Some(to),
+        })
+    }
+}
+
+impl Segment {
+    fn is_empty(&self) -> bool {
+        self.country.is_none()
+            && self.device.is_none()
+            && self.os.is_none()
+            && self.browser.is_none()
+            && self.source.is_none()
+            && self.referral.is_none()
+            && self.referer_host.is_none()
+            && self.pathname.is_none()
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum Comparison {
+    PreviousPeriod,
+    PreviousYear,
+}
+
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Interval {
+    #[default]
+    Day,
+    Week,
+    Month,
+}
+
+impl Interval {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Interval::Day => "day",
+            Interval::Week => "week",
+            Interval::Month => "month",
+        }
+    }
+
+    // How many buckets a range is split into at most. A missing end is taken
+    // as today, and a missing start as 29 days before the end.
+    pub fn buckets(&self, range: &DateRange) -> i64 {
+        let to = range.to.unwrap_or_else(|| Utc::now().date_naive());
+        let days = match range.from {
+            Some(from) => (to - from).num_days() + 1,
+            None => 30,
+        };
+
+        match self {
+            Interval::Day => days,
+            Interval::Week => days / 7 + 2,
+            Interval::Month => days / 28 + 2,
+        }
+    }
+}
+
 pub struct NewVisitorData {
     visitor_id: String,
     referer: String,
@@ -92,9 +284,18 @@ impl NewVisitorData {
         }
     }
 
+    // Cookieless visitors bring their id from the daily hash instead of a new one.
+    pub fn with_visitor_id(self, visitor_id: String) -> Self {
+        Self { visitor_id, ..self }
+    }
+
     pub fn visitor_id(&self) -> String {
         self.visitor_id.to_owned()
     }
+
+    pub fn tracking_id(&self) -> i32 {
+        self.tracking_id
+    }
 }
 
 #[derive(FromRow, Serialize)]
@@ -155,35 +356,59 @@ pub struct CountByReferral {
 }
 
 impl DB {
-    pub async fn create_visitor(&self, data: &NewVisitorData) -> Result<i32> {
-        let rec = sqlx::query!(
-            r#"INSERT INTO visitors (
-                visitor_id, user_agent, referer, source_id, user_agent_parsed, tracking_id
-            ) VALUES ($1, $2, $3, $4, $5, $6) RETURNING id"#,
-            data.visitor_id,
-            data.user_agent,
-            data.referer,
-            data.source_id,
-            data.user_agent_parsed,
-            data.tracking_id
-        )
-        .fetch_one(&self.pool)
-        .await?;
+    // Visitors are written by the ingestion queue, so their id is taken from
+    // the sequence up front for their sessions to refer to.
+    pub async fn next_visitor_id(&self) -> Result<i32> {
+        let rec = sqlx::query!(r#"SELECT NEXTVAL('visitors_id_seq')::INTEGER as "id!""#)
+            .fetch_one(&self.pool)
+            .await?;
 
         Ok(rec.id)
     }
 
-    pub async fn count_visitors_by_weekday(&self, tracking_id: i32) -> Result<Vec<CountByWeekday>> {
+    pub async fn count_visitors_by_weekday(
+        &self,
+        tracking_id: i32,
+        range: &DateRange,
+        segment: &Segment,
+    ) -> Result<Vec<CountByWeekday>> {
+        if segment.is_empty() {
+            return self
+                .count_visitors_by_weekday_from_rollups(tracking_id, range)
+                .await;
+        }
+
         let rec = sqlx::query_as!(
             CountByWeekday,
             r#"
+            WITH tracking AS (SELECT timezone FROM trackings WHERE id = $1)
             SELECT COUNT(id) as "count!",
-                EXTRACT(DOW FROM created_at) as "weekday!"
-            FROM visitors
+                EXTRACT(DOW FROM created_at AT TIME ZONE 'UTC' AT TIME ZONE tracking.timezone) as "weekday!"
+            FROM visitors CROSS JOIN tracking
             WHERE tracking_id = $1
+                AND ($2::DATE IS NULL OR created_at >= ($2::DATE::TIMESTAMP AT TIME ZONE tracking.timezone) AT TIME ZONE 'UTC')
+                AND ($3::DATE IS NULL OR created_at < (($3::DATE + 1)::TIMESTAMP AT TIME ZONE tracking.timezone) AT TIME ZONE 'UTC')
+                AND visitor_matches(visitors, $5, $6, $7, $8, $10)
+                AND (($4::TEXT IS NULL AND $9::TEXT IS NULL AND $11::TEXT IS NULL) OR EXISTS (
+                    SELECT 1 FROM sessions
+                    WHERE sessions.visitor_id = visitors.id
+                        AND ($2::DATE IS NULL OR sessions.start_timestamp >= ($2::DATE::TIMESTAMP AT TIME ZONE tracking.timezone) AT TIME ZONE 'UTC')
+                        AND ($3::DATE IS NULL OR sessions.start_timestamp < (($3::DATE + 1)::TIMESTAMP AT TIME ZONE tracking.timezone) AT TIME ZONE 'UTC')
+                        AND session_matches(sessions, $4, $9, $11)
+                ))
             GROUP BY "weekday!"
         "#,
-            tracking_id
+            tracking_id,
+            range.from,
+            range.to,
+            segment.country,
+            segment.device,
+            segment.os,
+            segment.browser,
+            segment.source,
+            segment.referral,
+            segment.referer_host,
+            segment.pathname
         )
         .fetch_all(&self.pool)
         .await?;
@@ -191,17 +416,49 @@ impl DB {
         Ok(rec)
     }
 
-    pub async fn count_visitors_by_hour(&self, tracking_id: i32) -> Result<Vec<CountByHour>> {
+    pub async fn count_visitors_by_hour(
+        &self,
+        tracking_id: i32,
+        range: &DateRange,
+        segment: &Segment,
+    ) -> Result<Vec<CountByHour>> {
+        if segment.is_empty() {
+            return self
+                .count_visitors_by_hour_from_rollups(tracking_id, range)
+                .await;
+        }
+
         let rec = sqlx::query_as!(
             CountByHour,
             r#"
+            WITH tracking AS (SELECT timezone FROM trackings WHERE id = $1)
             SELECT COUNT(id) as "count!",
-                EXTRACT(HOUR FROM created_at) as "hour!"
-            FROM visitors
+                EXTRACT(HOUR FROM created_at AT TIME ZONE 'UTC' AT TIME ZONE tracking.timezone) as "hour!"
+            FROM visitors CROSS JOIN tracking
             WHERE tracking_id = $1
+                AND ($2::DATE IS NULL OR created_at >= ($2::DATE::TIMESTAMP AT TIME ZONE tracking.timezone) AT TIME ZONE 'UTC')
+                AND ($3::DATE IS NULL OR created_at < (($3::DATE + 1)::TIMESTAMP AT TIME ZONE tracking.timezone) AT TIME ZONE 'UTC')
+                AND visitor_matches(visitors, $5, $6, $7, $8, $10)
+                AND (($4::TEXT IS NULL AND $9::TEXT IS NULL AND $11::TEXT IS NULL) OR EXISTS (
+                    SELECT 1 FROM sessions
+                    WHERE sessions.visitor_id = visitors.id
+                        AND ($2::DATE IS NULL OR sessions.start_timestamp >= ($2::DATE::TIMESTAMP AT TIME ZONE tracking.timezone) AT TIME ZONE 'UTC')
+                        AND ($3::DATE IS NULL OR sessions.start_timestamp < (($3::DATE + 1)::TIMESTAMP AT TIME ZONE tracking.timezone) AT TIME ZONE 'UTC')
+                        AND session_matches(sessions, $4, $9, $11)
+                ))
             GROUP BY "hour!"
         "#,
-            tracking_id
+            tracking_id,
+            range.from,
+            range.to,
+            segment.country,
+            segment.device,
+            segment.os,
+            segment.browser,
+            segment.source,
+            segment.referral,
+            segment.referer_host,
+            segment.pathname
         )
         .fetch_all(&self.pool)
         .await?;
@@ -209,17 +466,58 @@ impl DB {
         Ok(rec)
     }
 
-    pub async fn count_visitors_by_os(&self, tracking_id: i32) -> Result<Vec<CountByOs>> {
+    pub async fn count_visitors_by_os(
+        &self,
+        tracking_id: i32,
+        range: &DateRange,
+        segment: &Segment,
+    ) -> Result<Vec<CountByOs>> {
         let rec = sqlx::query_as!(
             CountByOs,
             r#"
-            SELECT COUNT(id) as "count!",
-                user_agent_parsed->'os'->>'family' AS "os!"
-            FROM visitors
-            WHERE tracking_id = $1
-            GROUP BY "os!"
+            WITH tracking AS (SELECT timezone FROM trackings WHERE id = $1),
+            live_counts AS (
+                SELECT COUNT(id) AS count,
+                    user_agent_parsed->'os'->>'family' AS os
+                FROM visitors CROSS JOIN tracking
+                WHERE tracking_id = $1
+                    AND ($2::DATE IS NULL OR created_at >= ($2::DATE::TIMESTAMP AT TIME ZONE tracking.timezone) AT TIME ZONE 'UTC')
+                    AND ($3::DATE IS NULL OR created_at < (($3::DATE + 1)::TIMESTAMP AT TIME ZONE tracking.timezone) AT TIME ZONE 'UTC')
+                    AND visitor_matches(visitors, $5, $6, $7, $8, $10)
+                    AND (($4::TEXT IS NULL AND $9::TEXT IS NULL AND $11::TEXT IS NULL) OR EXISTS (
+                        SELECT 1 FROM sessions
+                        WHERE sessions.visitor_id = visitors.id
+                            AND ($2::DATE IS NULL OR sessions.start_timestamp >= ($2::DATE::TIMESTAMP AT TIME ZONE tracking.timezone) AT TIME ZONE 'UTC')
+                            AND ($3::DATE IS NULL OR sessions.start_timestamp < (($3::DATE + 1)::TIMESTAMP AT TIME ZONE tracking.timezone) AT TIME ZONE 'UTC')
+                            AND session_matches(sessions, $4, $9, $11)
+                    ))
+                GROUP BY os
+            )
+            SELECT os as "os!", SUM(count)::BIGINT as "count!"
+            FROM (
+                SELECT os, count FROM live_counts
+                UNION ALL
+                SELECT value, visitors FROM imported_stats
+                WHERE imported_stats.tracking_id = $1
+                    AND imported_stats.dimension = 'os'
+                    AND ($2::DATE IS NULL OR imported_stats.date >= $2)
+                    AND ($3::DATE IS NULL OR imported_stats.date <= $3)
+                    AND $4::TEXT IS NULL AND $5::TEXT IS NULL AND $6::TEXT IS NULL AND $7::TEXT IS NULL
+                    AND $8::TEXT IS NULL AND $9::TEXT IS NULL AND $10::TEXT IS NULL AND $11::TEXT IS NULL
+            ) counts
+            GROUP BY os
         "#,
-            tracking_id
+            tracking_id,
+            range.from,
+            range.to,
+            segment.country,
+            segment.device,
+            segment.os,
+            segment.browser,
+            segment.source,
+            segment.referral,
+            segment.referer_host,
+            segment.pathname
         )
         .fetch_all(&self.pool)
         .await?;
@@ -227,17 +525,64 @@ impl DB {
         Ok(rec)
     }
 
-    pub async fn count_visitors_by_device(&self, tracking_id: i32) -> Result<Vec<CountByDevice>> {
+    pub async fn count_visitors_by_device(
+        &self,
+        tracking_id: i32,
+        range: &DateRange,
+        segment: &Segment,
+    ) -> Result<Vec<CountByDevice>> {
+        if segment.is_empty() {
+            return self
+                .count_visitors_by_device_from_rollups(tracking_id, range)
+                .await;
+        }
+
         let rec = sqlx::query_as!(
             CountByDevice,
             r#"
-            SELECT COUNT(id) as "count!",
-                user_agent_parsed->'device'->>'family' AS "device!"
-            FROM visitors
-            WHERE tracking_id = $1
-            GROUP BY "device!"
+            WITH tracking AS (SELECT timezone FROM trackings WHERE id = $1),
+            live_counts AS (
+                SELECT COUNT(id) AS count,
+                    user_agent_parsed->'device'->>'family' AS device
+                FROM visitors CROSS JOIN tracking
+                WHERE tracking_id = $1
+                    AND ($2::DATE IS NULL OR created_at >= ($2::DATE::TIMESTAMP AT TIME ZONE tracking.timezone) AT TIME ZONE 'UTC')
+                    AND ($3::DATE IS NULL OR created_at < (($3::DATE + 1)::TIMESTAMP AT TIME ZONE tracking.timezone) AT TIME ZONE 'UTC')
+                    AND visitor_matches(visitors, $5, $6, $7, $8, $10)
+                    AND (($4::TEXT IS NULL AND $9::TEXT IS NULL AND $11::TEXT IS NULL) OR EXISTS (
+                        SELECT 1 FROM sessions
+                        WHERE sessions.visitor_id = visitors.id
+                            AND ($2::DATE IS NULL OR sessions.start_timestamp >= ($2::DATE::TIMESTAMP AT TIME ZONE tracking.timezone) AT TIME ZONE 'UTC')
+                            AND ($3::DATE IS NULL OR sessions.start_timestamp < (($3::DATE + 1)::TIMESTAMP AT TIME ZONE tracking.timezone) AT TIME ZONE 'UTC')
+                            AND session_matches(sessions, $4, $9, $11)
+                    ))
+                GROUP BY device
+            )
+            SELECT device as "device!", SUM(count)::BIGINT as "count!"
+            FROM (
+                SELECT device, count FROM live_counts
+                UNION ALL
+                SELECT value, visitors FROM imported_stats
+                WHERE imported_stats.tracking_id = $1
+                    AND imported_stats.dimension = 'device'
+                    AND ($2::DATE IS NULL OR imported_stats.date >= $2)
+                    AND ($3::DATE IS NULL OR imported_stats.date <= $3)
+                    AND $4::TEXT IS NULL AND $5::TEXT IS NULL AND $6::TEXT IS NULL AND $7::TEXT IS NULL
+                    AND $8::TEXT IS NULL AND $9::TEXT IS NULL AND $10::TEXT IS NULL AND $11::TEXT IS NULL
+            ) counts
+            GROUP BY device
         "#,
-            tracking_id
+            tracking_id,
+            range.from,
+            range.to,
+            segment.country,
+            segment.device,
+            segment.os,
+            segment.browser,
+            segment.source,
+            segment.referral,
+            segment.referer_host,
+            segment.pathname
         )
         .fetch_all(&self.pool)
         .await?;
@@ -245,17 +590,58 @@ impl DB {
         Ok(rec)
     }
 
-    pub async fn count_visitors_by_browser(&self, tracking_id: i32) -> Result<Vec<CountByBrowser>> {
+    pub async fn count_visitors_by_browser(
+        &self,
+        tracking_id: i32,
+        range: &DateRange,
+        segment: &Segment,
+    ) -> Result<Vec<CountByBrowser>> {
         let rec = sqlx::query_as!(
             CountByBrowser,
             r#"
-            SELECT COUNT(id) as "count!",
-                user_agent_parsed->'user_agent'->>'family' AS "browser!"
-            FROM visitors
-            WHERE tracking_id = $1
-            GROUP BY "browser!"
+            WITH tracking AS (SELECT timezone FROM trackings WHERE id = $1),
+            live_counts AS (
+                SELECT COUNT(id) AS count,
+                    user_agent_parsed->'user_agent'->>'family' AS browser
+                FROM visitors CROSS JOIN tracking
+                WHERE tracking_id = $1
+                    AND ($2::DATE IS NULL OR created_at >= ($2::DATE::TIMESTAMP AT TIME ZONE tracking.timezone) AT TIME ZONE 'UTC')
+                    AND ($3::DATE IS NULL OR created_at < (($3::DATE + 1)::TIMESTAMP AT TIME ZONE tracking.timezone) AT TIME ZONE 'UTC')
+                    AND visitor_matches(visitors, $5, $6, $7, $8, $10)
+                    AND (($4::TEXT IS NULL AND $9::TEXT IS NULL AND $11::TEXT IS NULL) OR EXISTS (
+                        SELECT 1 FROM sessions
+                        WHERE sessions.visitor_id = visitors.id
+                            AND ($2::DATE IS NULL OR sessions.start_timestamp >= ($2::DATE::TIMESTAMP AT TIME ZONE tracking.timezone) AT TIME ZONE 'UTC')
+                            AND ($3::DATE IS NULL OR sessions.start_timestamp < (($3::DATE + 1)::TIMESTAMP AT TIME ZONE tracking.timezone) AT TIME ZONE 'UTC')
+                            AND session_matches(sessions, $4, $9, $11)
+                    ))
+                GROUP BY browser
+            )
+            SELECT browser as "browser!", SUM(count)::BIGINT as "count!"
+            FROM (
+                SELECT browser, count FROM live_counts
+                UNION ALL
+                SELECT value, visitors FROM imported_stats
+                WHERE imported_stats.tracking_id = $1
+                    AND imported_stats.dimension = 'browser'
+                    AND ($2::DATE IS NULL OR imported_stats.date >= $2)
+                    AND ($3::DATE IS NULL OR imported_stats.date <= $3)
+                    AND $4::TEXT IS NULL AND $5::TEXT IS NULL AND $6::TEXT IS NULL AND $7::TEXT IS NULL
+                    AND $8::TEXT IS NULL AND $9::TEXT IS NULL AND $10::TEXT IS NULL AND $11::TEXT IS NULL
+            ) counts
+            GROUP BY browser
         "#,
-            tracking_id
+            tracking_id,
+            range.from,
+            range.to,
+            segment.country,
+            segment.device,
+            segment.os,
+            segment.browser,
+            segment.source,
+            segment.referral,
+            segment.referer_host,
+            segment.pathname
         )
         .fetch_all(&self.pool)
         .await?;
@@ -272,18 +658,54 @@ pub struct SingleReferer {
 }
 
 impl DB {
-    pub async fn list_refers(&self, tracking_id: i32) -> Result<Vec<SingleReferer>> {
+    pub async fn list_refers(
+        &self,
+        tracking_id: i32,
+        range: &DateRange,
+        segment: &Segment,
+    ) -> Result<Vec<SingleReferer>> {
         let rec = sqlx::query_as!(
             SingleReferer,
             r#"
-            SELECT visitors.referer as referer,
-                COUNT(DISTINCT visitors.id) as "visitor_count!",
-                COUNT(DISTINCT sessions.id) as "session_count!"
-            FROM visitors JOIN sessions ON visitors.id = sessions.visitor_id
-            WHERE visitors.tracking_id = $1
+            WITH tracking AS (SELECT timezone FROM trackings WHERE id = $1),
+            live_counts AS (
+                SELECT visitors.referer AS referer,
+                    COUNT(DISTINCT visitors.id) AS visitor_count,
+                    COUNT(DISTINCT sessions.id) AS session_count
+                FROM visitors JOIN sessions ON visitors.id = sessions.visitor_id CROSS JOIN tracking
+                WHERE visitors.tracking_id = $1
+                    AND ($2::DATE IS NULL OR sessions.start_timestamp >= ($2::DATE::TIMESTAMP AT TIME ZONE tracking.timezone) AT TIME ZONE 'UTC')
+                    AND ($3::DATE IS NULL OR sessions.start_timestamp < (($3::DATE + 1)::TIMESTAMP AT TIME ZONE tracking.timezone) AT TIME ZONE 'UTC')
+                    AND segment_matches(sessions, visitors, $4, $5, $6, $7, $8, $9, $10, $11)
+                GROUP BY referer
+            )
+            SELECT referer as "referer!",
+                SUM(visitor_count)::BIGINT as "visitor_count!",
+                SUM(session_count)::BIGINT as "session_count!"
+            FROM (
+                SELECT referer, visitor_count, session_count FROM live_counts
+                UNION ALL
+                SELECT value, visitors, sessions FROM imported_stats
+                WHERE imported_stats.tracking_id = $1
+                    AND imported_stats.dimension = 'referer'
+                    AND ($2::DATE IS NULL OR imported_stats.date >= $2)
+                    AND ($3::DATE IS NULL OR imported_stats.date <= $3)
+                    AND $4::TEXT IS NULL AND $5::TEXT IS NULL AND $6::TEXT IS NULL AND $7::TEXT IS NULL
+                    AND $8::TEXT IS NULL AND $9::TEXT IS NULL AND $10::TEXT IS NULL AND $11::TEXT IS NULL
+            ) counts
             GROUP BY referer
         "#,
-            tracking_id
+            tracking_id,
+            range.from,
+            range.to,
+            segment.country,
+            segment.device,
+            segment.os,
+            segment.browser,
+            segment.source,
+            segment.referral,
+            segment.referer_host,
+            segment.pathname
         )
         .fetch_all(&self.pool)
         .await?;
@@ -292,6 +714,91 @@ impl DB {
     }
 }
 
+impl DB {
+    // Cookieless visitors are told apart by a hash of the day's salt, the
+    // tracking, their IP and their user agent. Once the salt is gone the hash
+    // can't be linked back to anyone, and the next day they're a new visitor.
+    pub async fn cookieless_visitor_id(
+        &self,
+        tracking_id: i32,
+        ip: &str,
+        user_agent: &str,
+    ) -> Result<String> {
+        loop {
+            let rec = sqlx::query!(
+                r#"
+                SELECT UPPER(LEFT(ENCODE(SHA256(CONVERT_TO(salt || ':' || $1 || ':' || $2 || ':' || $3, 'UTF8')), 'hex'), 26)) as "visitor_id!"
+                FROM daily_salts
+                WHERE day = (CURRENT_TIMESTAMP AT TIME ZONE 'UTC')::DATE
+            "#,
+                tracking_id.to_string(),
+                ip,
+                user_agent
+            )
+            .fetch_optional(&self.pool)
+            .await?;
+
+            match rec {
+                Some(rec) => return Ok(rec.visitor_id),
+                None => self.rotate_daily_salt().await?,
+            }
+        }
+    }
+
+    // Runs once a day, on the first cookieless hit. The salts of previous days
+    // are dropped right away, and by the retention task on days without any
+    // cookieless hits.
+    async fn rotate_daily_salt(&self) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+
+        sqlx::query!(
+            r#"
+            INSERT INTO daily_salts (day, salt)
+            VALUES ((CURRENT_TIMESTAMP AT TIME ZONE 'UTC')::DATE, REPLACE(GEN_RANDOM_UUID()::TEXT || GEN_RANDOM_UUID()::TEXT, '-', ''))
+            ON CONFLICT (day) DO NOTHING
+        "#
+        )
+        .execute(&mut tx)
+        .await?;
+        sqlx::query!(
+            r#"DELETE FROM daily_salts WHERE day < (CURRENT_TIMESTAMP AT TIME ZONE 'UTC')::DATE"#
+        )
+        .execute(&mut tx)
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    pub async fn delete_expired_salts(&self) -> Result<()> {
+        sqlx::query!(
+            r#"DELETE FROM daily_salts WHERE day < (CURRENT_TIMESTAMP AT TIME ZONE 'UTC')::DATE"#
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn latest_session_id(&self, visitor_id: i32) -> Result<Option<String>> {
+        let rec = sqlx::query!(
+            r#"
+            SELECT session_id FROM sessions
+            WHERE visitor_id = $1
+            ORDER BY start_timestamp DESC, id DESC
+            LIMIT 1
+        "#,
+            visitor_id
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(rec.map(|rec| rec.session_id))
+    }
+}
+
+#[derive(Clone)]
 pub struct NewSessionData {
     session_id: String,
     visitor_id: i32,
@@ -307,9 +814,29 @@ impl NewSessionData {
     pub fn session_id(&self) -> &str {
         &self.session_id
     }
+
+    pub fn visitor_id(&self) -> i32 {
+        self.visitor_id
+    }
+
+    pub fn tracking_id(&self) -> i32 {
+        self.tracking_id
+    }
+
+    pub fn pathname(&self) -> &str {
+        &self.pathname
+    }
+
+    pub fn country(&self) -> Option<String> {
+        self.location
+            .as_ref()
+            .and_then(|location| location["country"]["names"]["en"].as_str())
+            .map(str::to_owned)
+    }
 }
 
 impl NewSessionData {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         visitor_id: i32,
         timestamp: f64,
@@ -317,15 +844,8 @@ impl NewSessionData {
         pathname: String,
         referral: Option<String>,
         tracking_id: i32,
-        remote_addr: Option<SocketAddr>,
-        maxmind_reader: Arc<maxminddb::Reader<Vec<u8>>>,
+        location: Option<serde_json::Value>,
     ) -> Self {
-        let ip = remote_addr.map(|addr| addr.ip());
-        let location = ip.and_then(|ip| {
-            let location = maxmind_reader.lookup::<geoip2::City>(ip).ok();
-            location.map(|location| serde_json::to_value(location).unwrap())
-        });
-
         Self {
             session_id: utils::generate_id(),
             visitor_id,
@@ -339,63 +859,240 @@ impl NewSessionData {
     }
 }
 
+// A row written by the ingestion queue. Writes are applied visitors first,
+// then identifications, sessions, session ends and events, so that each can
+// refer to the ones before it.
+pub enum Write {
+    Visitor {
+        id: i32,
+        data: NewVisitorData,
+    },
+    Session(NewSessionData),
+    End {
+        session_id: String,
+        timestamp: f64,
+        tracking_id: Option<i32>,
+    },
+    Event {
+        session_id: String,
+        event_type: String,
+        event_target: String,
+        properties: Option<serde_json::Value>,
+        tracking_id: i32,
+    },
+    Identify {
+        id: i32,
+        identity: VisitorIdentity,
+    },
+}
+
 impl DB {
-    pub async fn create_session(&self, data: &NewSessionData) -> Result<()> {
-        sqlx::query!(
-            r#"INSERT INTO sessions (session_id, visitor_id, start_timestamp, title, pathname, referral, tracking_id, location)
-            VALUES ($1, $2, TO_TIMESTAMP($3), $4, $5, $6, $7, $8)"#,
-            data.session_id,
-            data.visitor_id,
-            data.start_timestamp,
-            data.title,
-            data.pathname,
-            data.referral,
-            data.tracking_id,
-            data.location,
+    pub async fn existing_session_ids(
+        &self,
+        tracking_id: i32,
+        session_ids: &[String],
+    ) -> Result<Vec<String>> {
+        let rec = sqlx::query!(
+            r#"SELECT session_id FROM sessions WHERE tracking_id = $1 AND session_id = ANY($2)"#,
+            tracking_id,
+            session_ids
         )
-        .execute(&self.pool)
+        .fetch_all(&self.pool)
         .await?;
 
-        Ok(())
+        Ok(rec.into_iter().map(|r| r.session_id).collect())
     }
 
-    pub async fn end_session(&self, session_id: &str, timestamp: f64) -> Result<()> {
-        sqlx::query!(
-            r#"UPDATE sessions SET ended_at = CURRENT_TIMESTAMP, end_timestamp = TO_TIMESTAMP($1) WHERE session_id = $2"#,
-            timestamp,
-            session_id
-        )
-        .execute(&self.pool)
-        .await?;
+    // Every kind of write goes in as a single multi-row statement, all in one
+    // transaction, except identifications, which are applied one by one in
+    // order since each merges into the ones before it. Events of sessions that
+    // don't exist are skipped, and how many is returned.
+    pub async fn write_many(&self, writes: &[&Write]) -> Result<u64> {
+        let mut visitor_ids = Vec::new();
+        let mut visitor_public_ids = Vec::new();
+        let mut visitor_user_agents = Vec::new();
+        let mut visitor_referers = Vec::new();
+        let mut visitor_sources = Vec::new();
+        let mut visitor_user_agents_parsed = Vec::new();
+        let mut visitor_trackings = Vec::new();
 
-        Ok(())
-    }
+        let mut session_ids = Vec::new();
+        let mut session_visitors = Vec::new();
+        let mut session_timestamps = Vec::new();
+        let mut session_titles = Vec::new();
+        let mut session_pathnames = Vec::new();
+        let mut session_referrals = Vec::new();
+        let mut session_trackings = Vec::new();
+        let mut session_locations = Vec::new();
 
-    pub async fn create_event(
-        &self,
-        session_id: &str,
-        event_type: &str,
-        event_target: &str,
-        tracking_id: i32,
-    ) -> Result<()> {
-        sqlx::query!(
-            r#"
-            INSERT INTO events (session_id, type, target, tracking_id)
-            VALUES (
-                (SELECT id FROM sessions WHERE session_id = $1), $2, $3, $4
+        let mut end_session_ids = Vec::new();
+        let mut end_timestamps = Vec::new();
+        let mut end_trackings = Vec::new();
+
+        let mut event_session_ids = Vec::new();
+        let mut event_types = Vec::new();
+        let mut event_targets = Vec::new();
+        let mut event_properties = Vec::new();
+        let mut event_trackings = Vec::new();
+
+        let mut identifications = Vec::new();
+
+        for write in writes {
+            match write {
+                Write::Visitor { id, data } => {
+                    visitor_ids.push(*id);
+                    visitor_public_ids.push(data.visitor_id.as_str());
+                    visitor_user_agents.push(data.user_agent.as_str());
+                    visitor_referers.push(data.referer.as_str());
+                    visitor_sources.push(data.source_id);
+                    visitor_user_agents_parsed.push(data.user_agent_parsed.to_string());
+                    visitor_trackings.push(data.tracking_id);
+                }
+                Write::Session(data) => {
+                    session_ids.push(data.session_id.as_str());
+                    session_visitors.push(data.visitor_id);
+                    session_timestamps.push(data.start_timestamp);
+                    session_titles.push(data.title.as_str());
+                    session_pathnames.push(data.pathname.as_str());
+                    session_referrals.push(data.referral.as_deref());
+                    session_trackings.push(data.tracking_id);
+                    session_locations.push(data.location.as_ref().map(|l| l.to_string()));
+                }
+                Write::End {
+                    session_id,
+                    timestamp,
+                    tracking_id,
+                } => {
+                    end_session_ids.push(session_id.as_str());
+                    end_timestamps.push(*timestamp);
+                    end_trackings.push(*tracking_id);
+                }
+                Write::Event {
+                    session_id,
+                    event_type,
+                    event_target,
+                    properties,
+                    tracking_id,
+                } => {
+                    event_session_ids.push(session_id.as_str());
+                    event_types.push(event_type.as_str());
+                    event_targets.push(event_target.as_str());
+                    event_properties.push(properties.as_ref().map(|p| p.to_string()));
+                    event_trackings.push(*tracking_id);
+                }
+                Write::Identify { id, identity } => identifications.push((*id, identity)),
+            }
+        }
+
+        let mut tx = self.pool.begin().await?;
+
+        if !visitor_ids.is_empty() {
+            sqlx::query!(
+                r#"
+                INSERT INTO visitors (id, visitor_id, user_agent, referer, source_id, user_agent_parsed, tracking_id)
+                SELECT id, visitor_id, user_agent, referer, source_id, user_agent_parsed::JSON, tracking_id
+                FROM UNNEST($1::INTEGER[], $2::TEXT[], $3::TEXT[], $4::TEXT[], $5::INTEGER[], $6::TEXT[], $7::INTEGER[])
+                    AS visitors (id, visitor_id, user_agent, referer, source_id, user_agent_parsed, tracking_id)
+                "#,
+                &visitor_ids,
+                &visitor_public_ids as &[&str],
+                &visitor_user_agents as &[&str],
+                &visitor_referers as &[&str],
+                &visitor_sources as &[Option<i32>],
+                &visitor_user_agents_parsed,
+                &visitor_trackings
             )
-            "#,
-            session_id,
-            event_type,
-            event_target,
-            tracking_id
-        )
-        .execute(&self.pool)
-        .await?;
+            .execute(&mut tx)
+            .await?;
+        }
 
-        Ok(())
+        for (id, identity) in identifications {
+            sqlx::query!(
+                r#"
+                UPDATE visitors
+                SET email = COALESCE($2, email),
+                    name = COALESCE($3, name),
+                    traits = CASE WHEN $4::JSONB IS NULL THEN traits ELSE COALESCE(traits, '{}'::JSONB) || $4::JSONB END,
+                    identified_at = CURRENT_TIMESTAMP
+                WHERE id = $1
+                "#,
+                id,
+                identity.email,
+                identity.name,
+                identity.traits
+            )
+            .execute(&mut tx)
+            .await?;
+        }
+
+        if !session_ids.is_empty() {
+            sqlx::query!(
+                r#"
+                INSERT INTO sessions (session_id, visitor_id, start_timestamp, title, pathname, referral, tracking_id, location)
+                SELECT session_id, visitor_id, TO_TIMESTAMP(start_timestamp), title, pathname, referral, tracking_id, location::JSON
+                FROM UNNEST($1::TEXT[], $2::INTEGER[], $3::FLOAT8[], $4::TEXT[], $5::TEXT[], $6::TEXT[], $7::INTEGER[], $8::TEXT[])
+                    AS sessions (session_id, visitor_id, start_timestamp, title, pathname, referral, tracking_id, location)
+                "#,
+                &session_ids as &[&str],
+                &session_visitors,
+                &session_timestamps,
+                &session_titles as &[&str],
+                &session_pathnames as &[&str],
+                &session_referrals as &[Option<&str>],
+                &session_trackings,
+                &session_locations as &[Option<String>]
+            )
+            .execute(&mut tx)
+            .await?;
+        }
+
+        if !end_session_ids.is_empty() {
+            sqlx::query!(
+                r#"
+                UPDATE sessions SET ended_at = CURRENT_TIMESTAMP, end_timestamp = TO_TIMESTAMP(ends.timestamp)
+                FROM UNNEST($1::TEXT[], $2::FLOAT8[], $3::INTEGER[]) AS ends (session_id, timestamp, tracking_id)
+                WHERE sessions.session_id = ends.session_id
+                    AND (ends.tracking_id IS NULL OR sessions.tracking_id = ends.tracking_id)
+                "#,
+                &end_session_ids as &[&str],
+                &end_timestamps,
+                &end_trackings as &[Option<i32>]
+            )
+            .execute(&mut tx)
+            .await?;
+        }
+
+        let mut skipped = 0;
+        if !event_session_ids.is_empty() {
+            let inserted = sqlx::query!(
+                r#"
+                INSERT INTO events (session_id, type, target, properties, tracking_id)
+                SELECT sessions.id, events.type, events.target, events.properties::JSONB, events.tracking_id
+                FROM UNNEST($1::TEXT[], $2::TEXT[], $3::TEXT[], $4::TEXT[], $5::INTEGER[])
+                    WITH ORDINALITY AS events (session_id, type, target, properties, tracking_id, position)
+                    JOIN sessions ON sessions.session_id = events.session_id
+                        AND sessions.tracking_id = events.tracking_id
+                ORDER BY events.position
+                "#,
+                &event_session_ids as &[&str],
+                &event_types as &[&str],
+                &event_targets as &[&str],
+                &event_properties as &[Option<String>],
+                &event_trackings
+            )
+            .execute(&mut tx)
+            .await?
+            .rows_affected();
+            skipped = event_session_ids.len() as u64 - inserted;
+        }
+
+        tx.commit().await?;
+
+        Ok(skipped)
     }
+}
 
+impl DB {
     pub async fn count_sessions(&self) -> Result<Option<i64>> {
         let rec = sqlx::query!(r#"SELECT COUNT(id) as count FROM sessions"#)
             .fetch_one(&self.pool)
@@ -404,17 +1101,42 @@ impl DB {
         Ok(rec.count)
     }
 
-    pub async fn count_sessions_by_weekday(&self, tracking_id: i32) -> Result<Vec<CountByWeekday>> {
+    pub async fn count_sessions_by_weekday(
+        &self,
+        tracking_id: i32,
+        range: &DateRange,
+        segment: &Segment,
+    ) -> Result<Vec<CountByWeekday>> {
+        if segment.is_empty() {
+            return self
+                .count_sessions_by_weekday_from_rollups(tracking_id, range)
+                .await;
+        }
+
         let rec = sqlx::query_as!(
             CountByWeekday,
             r#"
-            SELECT COUNT(id) as "count!",
-                EXTRACT(DOW FROM start_timestamp) as "weekday!"
-            FROM sessions
-            WHERE tracking_id = $1
+            WITH tracking AS (SELECT timezone FROM trackings WHERE id = $1)
+            SELECT COUNT(sessions.id) as "count!",
+                EXTRACT(DOW FROM start_timestamp AT TIME ZONE 'UTC' AT TIME ZONE tracking.timezone) as "weekday!"
+            FROM sessions JOIN visitors ON visitors.id = sessions.visitor_id CROSS JOIN tracking
+            WHERE sessions.tracking_id = $1
+                AND ($2::DATE IS NULL OR start_timestamp >= ($2::DATE::TIMESTAMP AT TIME ZONE tracking.timezone) AT TIME ZONE 'UTC')
+                AND ($3::DATE IS NULL OR start_timestamp < (($3::DATE + 1)::TIMESTAMP AT TIME ZONE tracking.timezone) AT TIME ZONE 'UTC')
+                AND segment_matches(sessions, visitors, $4, $5, $6, $7, $8, $9, $10, $11)
             GROUP BY "weekday!"
         "#,
-            tracking_id
+            tracking_id,
+            range.from,
+            range.to,
+            segment.country,
+            segment.device,
+            segment.os,
+            segment.browser,
+            segment.source,
+            segment.referral,
+            segment.referer_host,
+            segment.pathname
         )
         .fetch_all(&self.pool)
         .await?;
@@ -422,17 +1144,42 @@ impl DB {
         Ok(rec)
     }
 
-    pub async fn count_sessions_by_hour(&self, tracking_id: i32) -> Result<Vec<CountByHour>> {
+    pub async fn count_sessions_by_hour(
+        &self,
+        tracking_id: i32,
+        range: &DateRange,
+        segment: &Segment,
+    ) -> Result<Vec<CountByHour>> {
+        if segment.is_empty() {
+            return self
+                .count_sessions_by_hour_from_rollups(tracking_id, range)
+                .await;
+        }
+
         let rec = sqlx::query_as!(
             CountByHour,
             r#"
-            SELECT COUNT(id) as "count!",
-                EXTRACT(HOUR FROM start_timestamp) as "hour!"
-            FROM sessions
-            WHERE tracking_id = $1
+            WITH tracking AS (SELECT timezone FROM trackings WHERE id = $1)
+            SELECT COUNT(sessions.id) as "count!",
+                EXTRACT(HOUR FROM start_timestamp AT TIME ZONE 'UTC' AT TIME ZONE tracking.timezone) as "hour!"
+            FROM sessions JOIN visitors ON visitors.id = sessions.visitor_id CROSS JOIN tracking
+            WHERE sessions.tracking_id = $1
+                AND ($2::DATE IS NULL OR start_timestamp >= ($2::DATE::TIMESTAMP AT TIME ZONE tracking.timezone) AT TIME ZONE 'UTC')
+                AND ($3::DATE IS NULL OR start_timestamp < (($3::DATE + 1)::TIMESTAMP AT TIME ZONE tracking.timezone) AT TIME ZONE 'UTC')
+                AND segment_matches(sessions, visitors, $4, $5, $6, $7, $8, $9, $10, $11)
             GROUP BY "hour!"
         "#,
-            tracking_id
+            tracking_id,
+            range.from,
+            range.to,
+            segment.country,
+            segment.device,
+            segment.os,
+            segment.browser,
+            segment.source,
+            segment.referral,
+            segment.referer_host,
+            segment.pathname
         )
         .fetch_all(&self.pool)
         .await?;
@@ -443,17 +1190,54 @@ impl DB {
     pub async fn count_sessions_by_pathname(
         &self,
         tracking_id: i32,
+        range: &DateRange,
+        segment: &Segment,
     ) -> Result<Vec<CountByPathname>> {
+        if segment.is_empty() {
+            return self
+                .count_sessions_by_pathname_from_rollups(tracking_id, range)
+                .await;
+        }
+
         let rec = sqlx::query_as!(
             CountByPathname,
             r#"
-            SELECT COUNT(DISTINCT sessions.id) as "count!",
-                sessions.pathname as pathname
-            FROM sessions
-            WHERE tracking_id = $1
+            WITH tracking AS (SELECT timezone FROM trackings WHERE id = $1),
+            live_counts AS (
+                SELECT COUNT(DISTINCT sessions.id) AS count,
+                    sessions.pathname AS pathname
+                FROM sessions JOIN visitors ON visitors.id = sessions.visitor_id CROSS JOIN tracking
+                WHERE sessions.tracking_id = $1
+                    AND ($2::DATE IS NULL OR start_timestamp >= ($2::DATE::TIMESTAMP AT TIME ZONE tracking.timezone) AT TIME ZONE 'UTC')
+                    AND ($3::DATE IS NULL OR start_timestamp < (($3::DATE + 1)::TIMESTAMP AT TIME ZONE tracking.timezone) AT TIME ZONE 'UTC')
+                    AND segment_matches(sessions, visitors, $4, $5, $6, $7, $8, $9, $10, $11)
+                GROUP BY pathname
+            )
+            SELECT pathname as "pathname!", SUM(count)::BIGINT as "count!"
+            FROM (
+                SELECT pathname, count FROM live_counts
+                UNION ALL
+                SELECT value, sessions FROM imported_stats
+                WHERE imported_stats.tracking_id = $1
+                    AND imported_stats.dimension = 'pathname'
+                    AND ($2::DATE IS NULL OR imported_stats.date >= $2)
+                    AND ($3::DATE IS NULL OR imported_stats.date <= $3)
+                    AND $4::TEXT IS NULL AND $5::TEXT IS NULL AND $6::TEXT IS NULL AND $7::TEXT IS NULL
+                    AND $8::TEXT IS NULL AND $9::TEXT IS NULL AND $10::TEXT IS NULL AND $11::TEXT IS NULL
+            ) counts
             GROUP BY pathname
         "#,
-            tracking_id
+            tracking_id,
+            range.from,
+            range.to,
+            segment.country,
+            segment.device,
+            segment.os,
+            segment.browser,
+            segment.source,
+            segment.referral,
+            segment.referer_host,
+            segment.pathname
         )
         .fetch_all(&self.pool)
         .await?;
@@ -461,17 +1245,36 @@ impl DB {
         Ok(rec)
     }
 
-    pub async fn count_sessions_by_title(&self, tracking_id: i32) -> Result<Vec<CountByTitle>> {
+    pub async fn count_sessions_by_title(
+        &self,
+        tracking_id: i32,
+        range: &DateRange,
+        segment: &Segment,
+    ) -> Result<Vec<CountByTitle>> {
         let rec = sqlx::query_as!(
             CountByTitle,
             r#"
+            WITH tracking AS (SELECT timezone FROM trackings WHERE id = $1)
             SELECT COUNT(DISTINCT sessions.id) as "count!",
                 sessions.title as title
-            FROM sessions
-            WHERE tracking_id = $1
+            FROM sessions JOIN visitors ON visitors.id = sessions.visitor_id CROSS JOIN tracking
+            WHERE sessions.tracking_id = $1
+                AND ($2::DATE IS NULL OR start_timestamp >= ($2::DATE::TIMESTAMP AT TIME ZONE tracking.timezone) AT TIME ZONE 'UTC')
+                AND ($3::DATE IS NULL OR start_timestamp < (($3::DATE + 1)::TIMESTAMP AT TIME ZONE tracking.timezone) AT TIME ZONE 'UTC')
+                AND segment_matches(sessions, visitors, $4, $5, $6, $7, $8, $9, $10, $11)
             GROUP BY title
         "#,
-            tracking_id
+            tracking_id,
+            range.from,
+            range.to,
+            segment.country,
+            segment.device,
+            segment.os,
+            segment.browser,
+            segment.source,
+            segment.referral,
+            segment.referer_host,
+            segment.pathname
         )
         .fetch_all(&self.pool)
         .await?;
@@ -479,17 +1282,58 @@ impl DB {
         Ok(rec)
     }
 
-    pub async fn count_sessions_by_country(&self, tracking_id: i32) -> Result<Vec<CountByCountry>> {
+    pub async fn count_sessions_by_country(
+        &self,
+        tracking_id: i32,
+        range: &DateRange,
+        segment: &Segment,
+    ) -> Result<Vec<CountByCountry>> {
+        if segment.is_empty() {
+            return self
+                .count_sessions_by_country_from_rollups(tracking_id, range)
+                .await;
+        }
+
         let rec = sqlx::query_as!(
             CountByCountry,
             r#"
-            SELECT location->'country'->>'iso_code' as iso_code,
-                location->'country'->'names'->>'en' as name,
-                COUNT(id) as "count!"
-            FROM sessions
-            WHERE tracking_id = $1
-            GROUP BY iso_code, name"#,
-            tracking_id
+            WITH tracking AS (SELECT timezone FROM trackings WHERE id = $1),
+            live_counts AS (
+                SELECT sessions.location->'country'->>'iso_code' AS iso_code,
+                    sessions.location->'country'->'names'->>'en' AS name,
+                    COUNT(sessions.id) AS count
+                FROM sessions JOIN visitors ON visitors.id = sessions.visitor_id CROSS JOIN tracking
+                WHERE sessions.tracking_id = $1
+                    AND ($2::DATE IS NULL OR start_timestamp >= ($2::DATE::TIMESTAMP AT TIME ZONE tracking.timezone) AT TIME ZONE 'UTC')
+                    AND ($3::DATE IS NULL OR start_timestamp < (($3::DATE + 1)::TIMESTAMP AT TIME ZONE tracking.timezone) AT TIME ZONE 'UTC')
+                    AND segment_matches(sessions, visitors, $4, $5, $6, $7, $8, $9, $10, $11)
+                GROUP BY 1, 2
+            )
+            SELECT iso_code, MAX(name) as name, SUM(count)::BIGINT as "count!"
+            FROM (
+                SELECT iso_code, name, count FROM live_counts
+                UNION ALL
+                SELECT value, NULL, sessions FROM imported_stats
+                WHERE imported_stats.tracking_id = $1
+                    AND imported_stats.dimension = 'country'
+                    AND ($2::DATE IS NULL OR imported_stats.date >= $2)
+                    AND ($3::DATE IS NULL OR imported_stats.date <= $3)
+                    AND $4::TEXT IS NULL AND $5::TEXT IS NULL AND $6::TEXT IS NULL AND $7::TEXT IS NULL
+                    AND $8::TEXT IS NULL AND $9::TEXT IS NULL AND $10::TEXT IS NULL AND $11::TEXT IS NULL
+            ) counts
+            GROUP BY iso_code
+        "#,
+            tracking_id,
+            range.from,
+            range.to,
+            segment.country,
+            segment.device,
+            segment.os,
+            segment.browser,
+            segment.source,
+            segment.referral,
+            segment.referer_host,
+            segment.pathname
         )
         .fetch_all(&self.pool)
         .await?;
@@ -500,15 +1344,37 @@ impl DB {
     pub async fn count_sessions_by_referral(
         &self,
         tracking_id: i32,
+        range: &DateRange,
+        segment: &Segment,
     ) -> Result<Vec<CountByReferral>> {
+        if segment.is_empty() {
+            return self
+                .count_sessions_by_referral_from_rollups(tracking_id, range)
+                .await;
+        }
+
         let rec = sqlx::query_as!(
             CountByReferral,
             r#"
-            SELECT referral, COUNT(id) as "count!"
-            FROM sessions
-            WHERE tracking_id = $1
+            WITH tracking AS (SELECT timezone FROM trackings WHERE id = $1)
+            SELECT referral, COUNT(sessions.id) as "count!"
+            FROM sessions JOIN visitors ON visitors.id = sessions.visitor_id CROSS JOIN tracking
+            WHERE sessions.tracking_id = $1
+                AND ($2::DATE IS NULL OR start_timestamp >= ($2::DATE::TIMESTAMP AT TIME ZONE tracking.timezone) AT TIME ZONE 'UTC')
+                AND ($3::DATE IS NULL OR start_timestamp < (($3::DATE + 1)::TIMESTAMP AT TIME ZONE tracking.timezone) AT TIME ZONE 'UTC')
+                AND segment_matches(sessions, visitors, $4, $5, $6, $7, $8, $9, $10, $11)
             GROUP BY referral"#,
-            tracking_id
+            tracking_id,
+            range.from,
+            range.to,
+            segment.country,
+            segment.device,
+            segment.os,
+            segment.browser,
+            segment.source,
+            segment.referral,
+            segment.referer_host,
+            segment.pathname
         )
         .fetch_all(&self.pool)
         .await?;
@@ -517,52 +1383,278 @@ impl DB {
     }
 }
 
-#[derive(FromRow, Serialize)]
-pub struct SingleSource {
-    name: String,
-    visitor_count: i64,
-    session_count: i64,
-}
-
+// Without a segment, the dashboard breakdowns are summed from the hourly
+// `rollups` kept up to date by triggers, instead of scanning every session.
+// Hours are in the tracking's local time, so they line up with whole days and
+// give the weekday and hour histograms directly.
 impl DB {
-    pub async fn create_source(&self, name: &str, tracking_id: i32) -> Result<()> {
-        let _ = sqlx::query!(
-            r#"INSERT INTO sources (name, tracking_id) VALUES ($1, $2) RETURNING id"#,
-            name,
-            tracking_id
+    async fn count_visitors_by_device_from_rollups(
+        &self,
+        tracking_id: i32,
+        range: &DateRange,
+    ) -> Result<Vec<CountByDevice>> {
+        let rec = sqlx::query_as!(
+            CountByDevice,
+            r#"
+            SELECT device as "device!", SUM(count)::BIGINT as "count!"
+            FROM (
+                SELECT value AS device, visitors AS count FROM rollups
+                WHERE rollups.tracking_id = $1
+                    AND rollups.dimension = 'device'
+                    AND ($2::DATE IS NULL OR rollups.hour >= $2::DATE::TIMESTAMP)
+                    AND ($3::DATE IS NULL OR rollups.hour < ($3::DATE + 1)::TIMESTAMP)
+                UNION ALL
+                SELECT value, visitors FROM imported_stats
+                WHERE imported_stats.tracking_id = $1
+                    AND imported_stats.dimension = 'device'
+                    AND ($2::DATE IS NULL OR imported_stats.date >= $2)
+                    AND ($3::DATE IS NULL OR imported_stats.date <= $3)
+            ) counts
+            GROUP BY device
+            HAVING SUM(count) > 0
+        "#,
+            tracking_id,
+            range.from,
+            range.to
         )
-        .fetch_one(&self.pool)
+        .fetch_all(&self.pool)
         .await?;
 
-        Ok(())
+        Ok(rec)
     }
 
-    pub async fn delete_source(&self, name: &str, tracking_id: i32) -> Result<()> {
-        let _ = sqlx::query!(
-            r#"DELETE FROM sources WHERE name = $1 AND tracking_id = $2"#,
-            name,
-            tracking_id
+    async fn count_sessions_by_pathname_from_rollups(
+        &self,
+        tracking_id: i32,
+        range: &DateRange,
+    ) -> Result<Vec<CountByPathname>> {
+        let rec = sqlx::query_as!(
+            CountByPathname,
+            r#"
+            SELECT pathname as "pathname!", SUM(count)::BIGINT as "count!"
+            FROM (
+                SELECT value AS pathname, sessions AS count FROM rollups
+                WHERE rollups.tracking_id = $1
+                    AND rollups.dimension = 'path'
+                    AND ($2::DATE IS NULL OR rollups.hour >= $2::DATE::TIMESTAMP)
+                    AND ($3::DATE IS NULL OR rollups.hour < ($3::DATE + 1)::TIMESTAMP)
+                UNION ALL
+                SELECT value, sessions FROM imported_stats
+                WHERE imported_stats.tracking_id = $1
+                    AND imported_stats.dimension = 'pathname'
+                    AND ($2::DATE IS NULL OR imported_stats.date >= $2)
+                    AND ($3::DATE IS NULL OR imported_stats.date <= $3)
+            ) counts
+            GROUP BY pathname
+            HAVING SUM(count) > 0
+        "#,
+            tracking_id,
+            range.from,
+            range.to
         )
-        .execute(&self.pool)
+        .fetch_all(&self.pool)
         .await?;
 
-        Ok(())
+        Ok(rec)
+    }
+
+    async fn count_sessions_by_country_from_rollups(
+        &self,
+        tracking_id: i32,
+        range: &DateRange,
+    ) -> Result<Vec<CountByCountry>> {
+        let rec = sqlx::query_as!(
+            CountByCountry,
+            r#"
+            SELECT NULLIF(iso_code, '') as iso_code, MAX(name) as name, SUM(count)::BIGINT as "count!"
+            FROM (
+                SELECT value AS iso_code, label AS name, sessions AS count FROM rollups
+                WHERE rollups.tracking_id = $1
+                    AND rollups.dimension = 'country'
+                    AND ($2::DATE IS NULL OR rollups.hour >= $2::DATE::TIMESTAMP)
+                    AND ($3::DATE IS NULL OR rollups.hour < ($3::DATE + 1)::TIMESTAMP)
+                UNION ALL
+                SELECT value, NULL, sessions FROM imported_stats
+                WHERE imported_stats.tracking_id = $1
+                    AND imported_stats.dimension = 'country'
+                    AND ($2::DATE IS NULL OR imported_stats.date >= $2)
+                    AND ($3::DATE IS NULL OR imported_stats.date <= $3)
+            ) counts
+            GROUP BY iso_code
+            HAVING SUM(count) > 0
+        "#,
+            tracking_id,
+            range.from,
+            range.to
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rec)
+    }
+
+    async fn count_sessions_by_referral_from_rollups(
+        &self,
+        tracking_id: i32,
+        range: &DateRange,
+    ) -> Result<Vec<CountByReferral>> {
+        let rec = sqlx::query_as!(
+            CountByReferral,
+            r#"
+            SELECT NULLIF(value, '') as referral, SUM(sessions)::BIGINT as "count!"
+            FROM rollups
+            WHERE rollups.tracking_id = $1
+                AND rollups.dimension = 'referral'
+                AND ($2::DATE IS NULL OR rollups.hour >= $2::DATE::TIMESTAMP)
+                AND ($3::DATE IS NULL OR rollups.hour < ($3::DATE + 1)::TIMESTAMP)
+            GROUP BY value
+            HAVING SUM(sessions) > 0
+        "#,
+            tracking_id,
+            range.from,
+            range.to
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rec)
+    }
+
+    async fn count_visitors_by_weekday_from_rollups(
+        &self,
+        tracking_id: i32,
+        range: &DateRange,
+    ) -> Result<Vec<CountByWeekday>> {
+        let rec = sqlx::query_as!(
+            CountByWeekday,
+            r#"
+            SELECT SUM(visitors)::BIGINT as "count!",
+                EXTRACT(DOW FROM hour) as "weekday!"
+            FROM rollups
+            WHERE rollups.tracking_id = $1
+                AND rollups.dimension = 'source'
+                AND ($2::DATE IS NULL OR rollups.hour >= $2::DATE::TIMESTAMP)
+                AND ($3::DATE IS NULL OR rollups.hour < ($3::DATE + 1)::TIMESTAMP)
+            GROUP BY "weekday!"
+            HAVING SUM(visitors) > 0
+        "#,
+            tracking_id,
+            range.from,
+            range.to
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rec)
+    }
+
+    async fn count_visitors_by_hour_from_rollups(
+        &self,
+        tracking_id: i32,
+        range: &DateRange,
+    ) -> Result<Vec<CountByHour>> {
+        let rec = sqlx::query_as!(
+            CountByHour,
+            r#"
+            SELECT SUM(visitors)::BIGINT as "count!",
+                EXTRACT(HOUR FROM hour) as "hour!"
+            FROM rollups
+            WHERE rollups.tracking_id = $1
+                AND rollups.dimension = 'source'
+                AND ($2::DATE IS NULL OR rollups.hour >= $2::DATE::TIMESTAMP)
+                AND ($3::DATE IS NULL OR rollups.hour < ($3::DATE + 1)::TIMESTAMP)
+            GROUP BY "hour!"
+            HAVING SUM(visitors) > 0
+        "#,
+            tracking_id,
+            range.from,
+            range.to
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rec)
+    }
+
+    async fn count_sessions_by_weekday_from_rollups(
+        &self,
+        tracking_id: i32,
+        range: &DateRange,
+    ) -> Result<Vec<CountByWeekday>> {
+        let rec = sqlx::query_as!(
+            CountByWeekday,
+            r#"
+            SELECT SUM(sessions)::BIGINT as "count!",
+                EXTRACT(DOW FROM hour) as "weekday!"
+            FROM rollups
+            WHERE rollups.tracking_id = $1
+                AND rollups.dimension = 'path'
+                AND ($2::DATE IS NULL OR rollups.hour >= $2::DATE::TIMESTAMP)
+                AND ($3::DATE IS NULL OR rollups.hour < ($3::DATE + 1)::TIMESTAMP)
+            GROUP BY "weekday!"
+            HAVING SUM(sessions) > 0
+        "#,
+            tracking_id,
+            range.from,
+            range.to
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rec)
+    }
+
+    async fn count_sessions_by_hour_from_rollups(
+        &self,
+        tracking_id: i32,
+        range: &DateRange,
+    ) -> Result<Vec<CountByHour>> {
+        let rec = sqlx::query_as!(
+            CountByHour,
+            r#"
+            SELECT SUM(sessions)::BIGINT as "count!",
+                EXTRACT(HOUR FROM hour) as "hour!"
+            FROM rollups
+            WHERE rollups.tracking_id = $1
+                AND rollups.dimension = 'path'
+                AND ($2::DATE IS NULL OR rollups.hour >= $2::DATE::TIMESTAMP)
+                AND ($3::DATE IS NULL OR rollups.hour < ($3::DATE + 1)::TIMESTAMP)
+            GROUP BY "hour!"
+            HAVING SUM(sessions) > 0
+        "#,
+            tracking_id,
+            range.from,
+            range.to
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rec)
     }
 
-    pub async fn list_sources(&self, tracking_id: i32) -> Result<Vec<SingleSource>> {
+    async fn list_sources_from_rollups(
+        &self,
+        tracking_id: i32,
+        range: &DateRange,
+    ) -> Result<Vec<SingleSource>> {
         let sources = sqlx::query_as!(
             SingleSource,
             r#"
             SELECT sources.name as name,
-                COUNT(DISTINCT visitors.id) as "visitor_count!",
-                COUNT(DISTINCT sessions.id) as "session_count!"
-            FROM sources 
-                LEFT JOIN visitors ON visitors.source_id = sources.id
-                LEFT JOIN sessions ON sessions.visitor_id = visitors.id
+                COALESCE(SUM(rollups.visitors), 0)::BIGINT as "visitor_count!",
+                COALESCE(SUM(rollups.sessions), 0)::BIGINT as "session_count!"
+            FROM sources
+                LEFT JOIN rollups ON rollups.tracking_id = sources.tracking_id
+                    AND rollups.dimension = 'source'
+                    AND rollups.value = sources.id::TEXT
+                    AND ($2::DATE IS NULL OR rollups.hour >= $2::DATE::TIMESTAMP)
+                    AND ($3::DATE IS NULL OR rollups.hour < ($3::DATE + 1)::TIMESTAMP)
             WHERE sources.tracking_id = $1
-            GROUP BY sources.name 
-            "#,
-            tracking_id
+            GROUP BY sources.name
+        "#,
+            tracking_id,
+            range.from,
+            range.to
         )
         .fetch_all(&self.pool)
         .await?;
@@ -570,16 +1662,25 @@ impl DB {
         Ok(sources)
     }
 
-    pub async fn visitors_and_sessions_no_source(&self, tracking_id: i32) -> Result<SingleSource> {
+    async fn visitors_and_sessions_no_source_from_rollups(
+        &self,
+        tracking_id: i32,
+        range: &DateRange,
+    ) -> Result<SingleSource> {
         let rec = sqlx::query!(
             r#"
-            SELECT COUNT(DISTINCT visitors.id) as "visitor_count!",
-                COUNT(DISTINCT sessions.id) as "sessions_count!"
-            FROM visitors 
-                LEFT JOIN sessions ON sessions.visitor_id = visitors.id
-            WHERE visitors.source_id IS NULL AND visitors.tracking_id = $1
-            "#,
-            tracking_id
+            SELECT COALESCE(SUM(visitors), 0)::BIGINT as "visitor_count!",
+                COALESCE(SUM(sessions), 0)::BIGINT as "sessions_count!"
+            FROM rollups
+            WHERE rollups.tracking_id = $1
+                AND rollups.dimension = 'source'
+                AND rollups.value = ''
+                AND ($2::DATE IS NULL OR rollups.hour >= $2::DATE::TIMESTAMP)
+                AND ($3::DATE IS NULL OR rollups.hour < ($3::DATE + 1)::TIMESTAMP)
+        "#,
+            tracking_id,
+            range.from,
+            range.to
         )
         .fetch_one(&self.pool)
         .await?;
@@ -590,166 +1691,2549 @@ impl DB {
             session_count: rec.sessions_count,
         })
     }
-
-    pub async fn count_sources(&self) -> Result<Option<i64>> {
-        let rec = sqlx::query!(r#"SELECT COUNT(id) as count FROM sources"#)
-            .fetch_one(&self.pool)
-            .await?;
-
-        Ok(rec.count)
-    }
 }
 
-pub struct NewUserData {
-    user_id: String,
-    secret_code: String,
+#[derive(FromRow, Serialize)]
+pub struct TimeSeriesPoint {
+    #[serde(with = "native_date_format")]
+    bucket: NaiveDateTime,
+    visitors: i64,
+    sessions: i64,
+    page_views: i64,
+    events: i64,
 }
 
-impl NewUserData {
-    pub fn new(secret_code: &str) -> Self {
-        Self {
-            user_id: utils::generate_id(),
-            secret_code: secret_code.to_owned(),
-        }
+impl DB {
+    // Without a range the series covers the last 30 days. Every page load
+    // starts a session, so each session is a page view. Imported history only
+    // has daily totals, so it's left out of anything narrowed down by a
+    // segment.
+    pub async fn time_series(
+        &self,
+        tracking_id: i32,
+        range: &DateRange,
+        segment: &Segment,
+        interval: Interval,
+    ) -> Result<Vec<TimeSeriesPoint>> {
+        let rec = sqlx::query_as!(
+            TimeSeriesPoint,
+            r#"
+            WITH bounds AS (SELECT * FROM report_bounds($1, $2, $3)),
+            buckets AS (SELECT report_buckets($1, $2, $3, $12) AS bucket),
+            ranged_sessions AS (
+                SELECT sessions.id, sessions.visitor_id,
+                    sessions.start_timestamp AT TIME ZONE 'UTC' AT TIME ZONE bounds.timezone AS local_start
+                FROM sessions JOIN visitors ON visitors.id = sessions.visitor_id, bounds
+                WHERE sessions.tracking_id = $1
+                    AND sessions.start_timestamp >= bounds.first_instant
+                    AND sessions.start_timestamp < bounds.after_last_instant
+                    AND segment_matches(sessions, visitors, $4, $5, $6, $7, $8, $9, $10, $11)
+            ),
+            session_counts AS (
+                SELECT DATE_TRUNC($12, local_start) AS bucket,
+                    COUNT(DISTINCT visitor_id) AS visitors,
+                    COUNT(id) AS sessions,
+                    COUNT(id) AS page_views
+                FROM ranged_sessions
+                GROUP BY 1
+            ),
+            event_counts AS (
+                SELECT DATE_TRUNC($12, events.created_at AT TIME ZONE 'UTC' AT TIME ZONE bounds.timezone) AS bucket,
+                    COUNT(events.id) AS events
+                FROM events JOIN sessions ON sessions.id = events.session_id
+                    JOIN visitors ON visitors.id = sessions.visitor_id, bounds
+                WHERE events.tracking_id = $1
+                    AND events.created_at >= bounds.first_instant
+                    AND events.created_at < bounds.after_last_instant
+                    AND segment_matches(sessions, visitors, $4, $5, $6, $7, $8, $9, $10, $11)
+                GROUP BY 1
+            ),
+            imported_counts AS (
+                SELECT DATE_TRUNC($12, imported_stats.date::TIMESTAMP) AS bucket,
+                    SUM(imported_stats.visitors) FILTER (WHERE imported_stats.dimension = 'total')::BIGINT AS visitors,
+                    SUM(imported_stats.sessions) FILTER (WHERE imported_stats.dimension = 'total')::BIGINT AS sessions,
+                    SUM(imported_stats.page_views) FILTER (WHERE imported_stats.dimension = 'total')::BIGINT AS page_views,
+                    SUM(imported_stats.events) FILTER (WHERE imported_stats.dimension = 'event')::BIGINT AS events
+                FROM imported_stats, bounds
+                WHERE imported_stats.tracking_id = $1
+                    AND imported_stats.dimension IN ('total', 'event')
+                    AND imported_stats.date >= bounds.first_day
+                    AND imported_stats.date < bounds.after_last_day
+                    AND $4::TEXT IS NULL AND $5::TEXT IS NULL AND $6::TEXT IS NULL AND $7::TEXT IS NULL
+                    AND $8::TEXT IS NULL AND $9::TEXT IS NULL AND $10::TEXT IS NULL AND $11::TEXT IS NULL
+                GROUP BY 1
+            )
+            SELECT buckets.bucket as "bucket!",
+                COALESCE(session_counts.visitors, 0) + COALESCE(imported_counts.visitors, 0) as "visitors!",
+                COALESCE(session_counts.sessions, 0) + COALESCE(imported_counts.sessions, 0) as "sessions!",
+                COALESCE(session_counts.page_views, 0) + COALESCE(imported_counts.page_views, 0) as "page_views!",
+                COALESCE(event_counts.events, 0) + COALESCE(imported_counts.events, 0) as "events!"
+            FROM buckets
+                LEFT JOIN session_counts ON session_counts.bucket = buckets.bucket
+                LEFT JOIN event_counts ON event_counts.bucket = buckets.bucket
+                LEFT JOIN imported_counts ON imported_counts.bucket = buckets.bucket
+            ORDER BY buckets.bucket
+        "#,
+            tracking_id,
+            range.from,
+            range.to,
+            segment.country,
+            segment.device,
+            segment.os,
+            segment.browser,
+            segment.source,
+            segment.referral,
+            segment.referer_host,
+            segment.pathname,
+            interval.as_str()
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rec)
     }
 }
 
 #[derive(FromRow, Serialize)]
-pub struct CreatedUser {
-    user_id: String,
-    secret_code: String,
+pub struct NewVsReturningPoint {
+    #[serde(with = "native_date_format")]
+    bucket: NaiveDateTime,
+    new_visitors: i64,
+    returning_visitors: i64,
+    new_sessions: i64,
+    returning_sessions: i64,
+}
+
+#[derive(FromRow, Serialize)]
+pub struct SessionsPerVisitor {
+    sessions: i64,
+    visitors: i64,
 }
 
 impl DB {
-    pub async fn create_user(&self, data: &NewUserData) -> Result<CreatedUser> {
-        let user = sqlx::query_as!(
-            CreatedUser,
-            r#"INSERT INTO users (user_id, secret_code) VALUES ($1, $2) RETURNING user_id, secret_code"#,
-            data.user_id,
-            data.secret_code
+    // Every page load is a session, so a session is new when it starts on the
+    // local day the visitor first came, returning when it starts on a later
+    // one. The earlier sessions of the visitors in the range are still looked
+    // at so a visitor who first came before `from` is counted as returning.
+    // Within a bucket a visitor is either new or returning, never both.
+    pub async fn new_vs_returning(
+        &self,
+        tracking_id: i32,
+        range: &DateRange,
+        segment: &Segment,
+        interval: Interval,
+    ) -> Result<Vec<NewVsReturningPoint>> {
+        let rec = sqlx::query_as!(
+            NewVsReturningPoint,
+            r#"
+            WITH bounds AS (SELECT * FROM report_bounds($1, $2, $3)),
+            buckets AS (SELECT report_buckets($1, $2, $3, $12) AS bucket),
+            ranged_sessions AS (
+                SELECT sessions.id, sessions.visitor_id,
+                    DATE_TRUNC($12, sessions.start_timestamp AT TIME ZONE 'UTC' AT TIME ZONE bounds.timezone) AS bucket,
+                    (sessions.start_timestamp AT TIME ZONE 'UTC' AT TIME ZONE bounds.timezone)::DATE AS local_day
+                FROM sessions JOIN visitors ON visitors.id = sessions.visitor_id, bounds
+                WHERE sessions.tracking_id = $1
+                    AND sessions.start_timestamp >= bounds.first_instant
+                    AND sessions.start_timestamp < bounds.after_last_instant
+                    AND segment_matches(sessions, visitors, $4, $5, $6, $7, $8, $9, $10, $11)
+            ),
+            first_days AS (
+                SELECT visitor_ids.visitor_id,
+                    (MIN(sessions.start_timestamp) AT TIME ZONE 'UTC' AT TIME ZONE bounds.timezone)::DATE AS first_local_day
+                FROM (SELECT DISTINCT visitor_id FROM ranged_sessions) visitor_ids
+                    JOIN sessions ON sessions.visitor_id = visitor_ids.visitor_id, bounds
+                GROUP BY visitor_ids.visitor_id, bounds.timezone
+            ),
+            classified_sessions AS (
+                SELECT ranged_sessions.*, first_days.first_local_day
+                FROM ranged_sessions JOIN first_days ON first_days.visitor_id = ranged_sessions.visitor_id
+            ),
+            session_counts AS (
+                SELECT bucket,
+                    COUNT(DISTINCT visitor_id) FILTER (WHERE DATE_TRUNC($12, first_local_day::TIMESTAMP) = bucket) AS new_visitors,
+                    COUNT(DISTINCT visitor_id) FILTER (WHERE DATE_TRUNC($12, first_local_day::TIMESTAMP) < bucket) AS returning_visitors,
+                    COUNT(id) FILTER (WHERE local_day = first_local_day) AS new_sessions,
+                    COUNT(id) FILTER (WHERE local_day > first_local_day) AS returning_sessions
+                FROM classified_sessions
+                GROUP BY 1
+            )
+            SELECT buckets.bucket as "bucket!",
+                COALESCE(session_counts.new_visitors, 0) as "new_visitors!",
+                COALESCE(session_counts.returning_visitors, 0) as "returning_visitors!",
+                COALESCE(session_counts.new_sessions, 0) as "new_sessions!",
+                COALESCE(session_counts.returning_sessions, 0) as "returning_sessions!"
+            FROM buckets
+                LEFT JOIN session_counts ON session_counts.bucket = buckets.bucket
+            ORDER BY buckets.bucket
+        "#,
+            tracking_id,
+            range.from,
+            range.to,
+            segment.country,
+            segment.device,
+            segment.os,
+            segment.browser,
+            segment.source,
+            segment.referral,
+            segment.referer_host,
+            segment.pathname,
+            interval.as_str()
         )
-        .fetch_one(&self.pool)
+        .fetch_all(&self.pool)
         .await?;
 
-        Ok(user)
+        Ok(rec)
     }
 
-    pub async fn authenticate_user(&self, user_id: &str) -> Result<(i32, String)> {
-        let rec = sqlx::query!(
-            r#"SELECT id, secret_code FROM users WHERE user_id = $1"#,
-            user_id,
+    pub async fn sessions_per_visitor(
+        &self,
+        tracking_id: i32,
+        range: &DateRange,
+        segment: &Segment,
+    ) -> Result<Vec<SessionsPerVisitor>> {
+        let rec = sqlx::query_as!(
+            SessionsPerVisitor,
+            r#"
+            WITH tracking AS (SELECT timezone FROM trackings WHERE id = $1),
+            visitor_sessions AS (
+                SELECT COUNT(sessions.id) AS sessions
+                FROM sessions JOIN visitors ON visitors.id = sessions.visitor_id CROSS JOIN tracking
+                WHERE sessions.tracking_id = $1
+                    AND ($2::DATE IS NULL OR start_timestamp >= ($2::DATE::TIMESTAMP AT TIME ZONE tracking.timezone) AT TIME ZONE 'UTC')
+                    AND ($3::DATE IS NULL OR start_timestamp < (($3::DATE + 1)::TIMESTAMP AT TIME ZONE tracking.timezone) AT TIME ZONE 'UTC')
+                    AND segment_matches(sessions, visitors, $4, $5, $6, $7, $8, $9, $10, $11)
+                GROUP BY sessions.visitor_id
+            )
+            SELECT sessions as "sessions!",
+                COUNT(*) as "visitors!"
+            FROM visitor_sessions
+            GROUP BY sessions
+            ORDER BY sessions
+        "#,
+            tracking_id,
+            range.from,
+            range.to,
+            segment.country,
+            segment.device,
+            segment.os,
+            segment.browser,
+            segment.source,
+            segment.referral,
+            segment.referer_host,
+            segment.pathname
         )
-        .fetch_one(&self.pool)
+        .fetch_all(&self.pool)
         .await?;
 
-        Ok((rec.id, rec.secret_code))
+        Ok(rec)
     }
 }
 
-pub struct NewTrackingData {
-    tracking_id: String,
-    name: String,
-    owner_id: i32,
+// A visitor bounced when their only session in the range was shorter than
+// this, or was never ended.
+const BOUNCE_THRESHOLD_SECONDS: f64 = 10.0;
+
+#[derive(FromRow, Serialize)]
+pub struct SessionEngagement {
+    sessions: i64,
+    ended_sessions: i64,
+    average_duration: Option<f64>,
+    median_duration: Option<f64>,
+    p75_duration: Option<f64>,
+    p90_duration: Option<f64>,
+    p95_duration: Option<f64>,
+    bounce_rate: Option<f64>,
 }
 
-impl NewTrackingData {
-    pub fn new(name: String, owner_id: i32) -> Self {
-        Self {
-            tracking_id: utils::generate_id(),
-            name,
-            owner_id,
-        }
-    }
+#[derive(FromRow, Serialize)]
+pub struct DurationBucket {
+    min_seconds: i32,
+    max_seconds: Option<i32>,
+    sessions: i64,
 }
 
 #[derive(FromRow, Serialize)]
-pub struct SingleTracking {
-    id: String,
-    name: String,
-    #[serde(with = "native_date_format")]
-    created_at: NaiveDateTime,
-    visitor_count: Option<i64>,
-    sessions_count: Option<i64>,
-    events_count: Option<i64>,
-    sources_count: Option<i64>,
+pub struct PageEngagement {
+    pathname: String,
+    sessions: i64,
+    average_duration: Option<f64>,
+    median_duration: Option<f64>,
 }
 
 impl DB {
-    pub async fn create_tracking(&self, data: &NewTrackingData) -> Result<()> {
+    // Durations are in seconds and only come from sessions that were ended,
+    // skipping the odd one whose client clock made it end before it started.
+    pub async fn session_engagement(
+        &self,
+        tracking_id: i32,
+        range: &DateRange,
+        segment: &Segment,
+    ) -> Result<SessionEngagement> {
+        let rec = sqlx::query_as!(
+            SessionEngagement,
+            r#"
+            WITH tracking AS (SELECT timezone FROM trackings WHERE id = $1),
+            ranged_sessions AS (
+                SELECT sessions.visitor_id,
+                    CASE WHEN end_timestamp >= start_timestamp
+                        THEN EXTRACT(EPOCH FROM end_timestamp - start_timestamp)::FLOAT8
+                    END AS duration
+                FROM sessions JOIN visitors ON visitors.id = sessions.visitor_id CROSS JOIN tracking
+                WHERE sessions.tracking_id = $1
+                    AND ($2::DATE IS NULL OR start_timestamp >= ($2::DATE::TIMESTAMP AT TIME ZONE tracking.timezone) AT TIME ZONE 'UTC')
+                    AND ($3::DATE IS NULL OR start_timestamp < (($3::DATE + 1)::TIMESTAMP AT TIME ZONE tracking.timezone) AT TIME ZONE 'UTC')
+                    AND segment_matches(sessions, visitors, $4, $5, $6, $7, $8, $9, $10, $11)
+            ),
+            visitor_visits AS (
+                SELECT COUNT(*) = 1 AND COALESCE(MAX(duration), 0) < $12 AS bounced
+                FROM ranged_sessions
+                GROUP BY visitor_id
+            )
+            SELECT COUNT(*) as "sessions!",
+                COUNT(duration) as "ended_sessions!",
+                AVG(duration) as average_duration,
+                PERCENTILE_CONT(0.5) WITHIN GROUP (ORDER BY duration) as median_duration,
+                PERCENTILE_CONT(0.75) WITHIN GROUP (ORDER BY duration) as p75_duration,
+                PERCENTILE_CONT(0.9) WITHIN GROUP (ORDER BY duration) as p90_duration,
+                PERCENTILE_CONT(0.95) WITHIN GROUP (ORDER BY duration) as p95_duration,
+                (SELECT AVG(bounced::INT)::FLOAT8 FROM visitor_visits) as bounce_rate
+            FROM ranged_sessions
+        "#,
+            tracking_id,
+            range.from,
+            range.to,
+            segment.country,
+            segment.device,
+            segment.os,
+            segment.browser,
+            segment.source,
+            segment.referral,
+            segment.referer_host,
+            segment.pathname,
+            BOUNCE_THRESHOLD_SECONDS
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(rec)
+    }
+
+    pub async fn session_duration_histogram(
+        &self,
+        tracking_id: i32,
+        range: &DateRange,
+        segment: &Segment,
+    ) -> Result<Vec<DurationBucket>> {
+        let rec = sqlx::query_as!(
+            DurationBucket,
+            r#"
+            WITH tracking AS (SELECT timezone FROM trackings WHERE id = $1),
+            limits AS (
+                SELECT ARRAY[10, 30, 60, 180, 600, 1800] AS upper_bounds
+            ),
+            buckets AS (
+                SELECT index - 1 AS index,
+                    COALESCE(limits.upper_bounds[index - 1], 0) AS min_seconds,
+                    limits.upper_bounds[index] AS max_seconds
+                FROM limits, GENERATE_SERIES(1, ARRAY_LENGTH(limits.upper_bounds, 1) + 1) AS index
+            ),
+            bucketed_sessions AS (
+                SELECT WIDTH_BUCKET(
+                    EXTRACT(EPOCH FROM end_timestamp - start_timestamp)::FLOAT8,
+                    limits.upper_bounds::FLOAT8[]
+                ) AS index
+                FROM sessions JOIN visitors ON visitors.id = sessions.visitor_id
+                    CROSS JOIN tracking CROSS JOIN limits
+                WHERE sessions.tracking_id = $1
+                    AND end_timestamp >= start_timestamp
+                    AND ($2::DATE IS NULL OR start_timestamp >= ($2::DATE::TIMESTAMP AT TIME ZONE tracking.timezone) AT TIME ZONE 'UTC')
+                    AND ($3::DATE IS NULL OR start_timestamp < (($3::DATE + 1)::TIMESTAMP AT TIME ZONE tracking.timezone) AT TIME ZONE 'UTC')
+                    AND segment_matches(sessions, visitors, $4, $5, $6, $7, $8, $9, $10, $11)
+            )
+            SELECT buckets.min_seconds as "min_seconds!",
+                buckets.max_seconds,
+                COUNT(bucketed_sessions.index) as "sessions!"
+            FROM buckets
+                LEFT JOIN bucketed_sessions ON bucketed_sessions.index = buckets.index
+            GROUP BY buckets.index, buckets.min_seconds, buckets.max_seconds
+            ORDER BY buckets.index
+        "#,
+            tracking_id,
+            range.from,
+            range.to,
+            segment.country,
+            segment.device,
+            segment.os,
+            segment.browser,
+            segment.source,
+            segment.referral,
+            segment.referer_host,
+            segment.pathname
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rec)
+    }
+
+    pub async fn page_engagement(
+        &self,
+        tracking_id: i32,
+        range: &DateRange,
+        segment: &Segment,
+    ) -> Result<Vec<PageEngagement>> {
+        let rec = sqlx::query_as!(
+            PageEngagement,
+            r#"
+            WITH tracking AS (SELECT timezone FROM trackings WHERE id = $1),
+            ranged_sessions AS (
+                SELECT pathname,
+                    CASE WHEN end_timestamp >= start_timestamp
+                        THEN EXTRACT(EPOCH FROM end_timestamp - start_timestamp)::FLOAT8
+                    END AS duration
+                FROM sessions JOIN visitors ON visitors.id = sessions.visitor_id CROSS JOIN tracking
+                WHERE sessions.tracking_id = $1
+                    AND ($2::DATE IS NULL OR start_timestamp >= ($2::DATE::TIMESTAMP AT TIME ZONE tracking.timezone) AT TIME ZONE 'UTC')
+                    AND ($3::DATE IS NULL OR start_timestamp < (($3::DATE + 1)::TIMESTAMP AT TIME ZONE tracking.timezone) AT TIME ZONE 'UTC')
+                    AND segment_matches(sessions, visitors, $4, $5, $6, $7, $8, $9, $10, $11)
+            )
+            SELECT pathname,
+                COUNT(*) as "sessions!",
+                AVG(duration) as average_duration,
+                PERCENTILE_CONT(0.5) WITHIN GROUP (ORDER BY duration) as median_duration
+            FROM ranged_sessions
+            GROUP BY pathname
+            ORDER BY "sessions!" DESC
+        "#,
+            tracking_id,
+            range.from,
+            range.to,
+            segment.country,
+            segment.device,
+            segment.os,
+            segment.browser,
+            segment.source,
+            segment.referral,
+            segment.referer_host,
+            segment.pathname
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rec)
+    }
+}
+
+#[derive(FromRow, Serialize)]
+pub struct CountByEventType {
+    #[serde(rename = "type")]
+    event_type: String,
+    count: i64,
+    visitor_count: i64,
+}
+
+#[derive(FromRow, Serialize)]
+pub struct CountByEventTarget {
+    #[serde(rename = "type")]
+    event_type: String,
+    target: String,
+    count: i64,
+    visitor_count: i64,
+}
+
+#[derive(FromRow, Serialize)]
+pub struct EventCountByDay {
+    #[serde(with = "native_date_format")]
+    day: NaiveDateTime,
+    #[serde(rename = "type")]
+    event_type: String,
+    count: i64,
+}
+
+#[derive(FromRow, Serialize)]
+pub struct RecentEvent {
+    #[serde(rename = "type")]
+    event_type: String,
+    target: String,
+    properties: Option<serde_json::Value>,
+    #[serde(with = "native_date_format")]
+    created_at: NaiveDateTime,
+    session_id: String,
+    pathname: String,
+}
+
+impl DB {
+    // Purged events are only kept as daily counts by type, so they're added to
+    // the counts by type and by day, and left out of anything narrowed down by
+    // a segment.
+    pub async fn count_events_by_type(
+        &self,
+        tracking_id: i32,
+        range: &DateRange,
+        segment: &Segment,
+    ) -> Result<Vec<CountByEventType>> {
+        let rec = sqlx::query_as!(
+            CountByEventType,
+            r#"
+            WITH tracking AS (SELECT timezone FROM trackings WHERE id = $1)
+            SELECT event_type as "event_type!",
+                SUM(count)::BIGINT as "count!",
+                SUM(visitor_count)::BIGINT as "visitor_count!"
+            FROM (
+                SELECT events.type as event_type,
+                    COUNT(events.id) as count,
+                    COUNT(DISTINCT sessions.visitor_id) as visitor_count
+                FROM events JOIN sessions ON sessions.id = events.session_id
+                    JOIN visitors ON visitors.id = sessions.visitor_id
+                    CROSS JOIN tracking
+                WHERE events.tracking_id = $1
+                    AND ($2::DATE IS NULL OR events.created_at >= ($2::DATE::TIMESTAMP AT TIME ZONE tracking.timezone) AT TIME ZONE 'UTC')
+                    AND ($3::DATE IS NULL OR events.created_at < (($3::DATE + 1)::TIMESTAMP AT TIME ZONE tracking.timezone) AT TIME ZONE 'UTC')
+                    AND segment_matches(sessions, visitors, $4, $5, $6, $7, $8, $9, $10, $11)
+                GROUP BY events.type
+                UNION ALL
+                SELECT value, events, visitors FROM imported_stats
+                WHERE imported_stats.tracking_id = $1
+                    AND imported_stats.dimension = 'event'
+                    AND ($2::DATE IS NULL OR imported_stats.date >= $2)
+                    AND ($3::DATE IS NULL OR imported_stats.date <= $3)
+                    AND $4::TEXT IS NULL AND $5::TEXT IS NULL AND $6::TEXT IS NULL AND $7::TEXT IS NULL
+                    AND $8::TEXT IS NULL AND $9::TEXT IS NULL AND $10::TEXT IS NULL AND $11::TEXT IS NULL
+            ) counts
+            GROUP BY event_type
+        "#,
+            tracking_id,
+            range.from,
+            range.to,
+            segment.country,
+            segment.device,
+            segment.os,
+            segment.browser,
+            segment.source,
+            segment.referral,
+            segment.referer_host,
+            segment.pathname
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rec)
+    }
+
+    pub async fn count_events_by_target(
+        &self,
+        tracking_id: i32,
+        range: &DateRange,
+        segment: &Segment,
+    ) -> Result<Vec<CountByEventTarget>> {
+        let rec = sqlx::query_as!(
+            CountByEventTarget,
+            r#"
+            WITH tracking AS (SELECT timezone FROM trackings WHERE id = $1)
+            SELECT events.type as event_type,
+                events.target as target,
+                COUNT(events.id) as "count!",
+                COUNT(DISTINCT sessions.visitor_id) as "visitor_count!"
+            FROM events JOIN sessions ON sessions.id = events.session_id
+                JOIN visitors ON visitors.id = sessions.visitor_id
+                CROSS JOIN tracking
+            WHERE events.tracking_id = $1
+                AND ($2::DATE IS NULL OR events.created_at >= ($2::DATE::TIMESTAMP AT TIME ZONE tracking.timezone) AT TIME ZONE 'UTC')
+                AND ($3::DATE IS NULL OR events.created_at < (($3::DATE + 1)::TIMESTAMP AT TIME ZONE tracking.timezone) AT TIME ZONE 'UTC')
+                AND segment_matches(sessions, visitors, $4, $5, $6, $7, $8, $9, $10, $11)
+            GROUP BY events.type, events.target
+        "#,
+            tracking_id,
+            range.from,
+            range.to,
+            segment.country,
+            segment.device,
+            segment.os,
+            segment.browser,
+            segment.source,
+            segment.referral,
+            segment.referer_host,
+            segment.pathname
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rec)
+    }
+
+    pub async fn count_events_by_day(
+        &self,
+        tracking_id: i32,
+        range: &DateRange,
+        segment: &Segment,
+    ) -> Result<Vec<EventCountByDay>> {
+        let rec = sqlx::query_as!(
+            EventCountByDay,
+            r#"
+            WITH tracking AS (SELECT timezone FROM trackings WHERE id = $1)
+            SELECT day as "day!", event_type as "event_type!", SUM(count)::BIGINT as "count!"
+            FROM (
+                SELECT DATE_TRUNC('day', events.created_at AT TIME ZONE 'UTC' AT TIME ZONE tracking.timezone) as day,
+                    events.type as event_type,
+                    COUNT(events.id) as count
+                FROM events JOIN sessions ON sessions.id = events.session_id
+                    JOIN visitors ON visitors.id = sessions.visitor_id
+                    CROSS JOIN tracking
+                WHERE events.tracking_id = $1
+                    AND ($2::DATE IS NULL OR events.created_at >= ($2::DATE::TIMESTAMP AT TIME ZONE tracking.timezone) AT TIME ZONE 'UTC')
+                    AND ($3::DATE IS NULL OR events.created_at < (($3::DATE + 1)::TIMESTAMP AT TIME ZONE tracking.timezone) AT TIME ZONE 'UTC')
+                    AND segment_matches(sessions, visitors, $4, $5, $6, $7, $8, $9, $10, $11)
+                GROUP BY 1, 2
+                UNION ALL
+                SELECT imported_stats.date::TIMESTAMP, value, events FROM imported_stats
+                WHERE imported_stats.tracking_id = $1
+                    AND imported_stats.dimension = 'event'
+                    AND ($2::DATE IS NULL OR imported_stats.date >= $2)
+                    AND ($3::DATE IS NULL OR imported_stats.date <= $3)
+                    AND $4::TEXT IS NULL AND $5::TEXT IS NULL AND $6::TEXT IS NULL AND $7::TEXT IS NULL
+                    AND $8::TEXT IS NULL AND $9::TEXT IS NULL AND $10::TEXT IS NULL AND $11::TEXT IS NULL
+            ) counts
+            GROUP BY day, event_type
+            ORDER BY day
+        "#,
+            tracking_id,
+            range.from,
+            range.to,
+            segment.country,
+            segment.device,
+            segment.os,
+            segment.browser,
+            segment.source,
+            segment.referral,
+            segment.referer_host,
+            segment.pathname
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rec)
+    }
+
+    pub async fn list_recent_events(
+        &self,
+        tracking_id: i32,
+        range: &DateRange,
+        segment: &Segment,
+        limit: i64,
+    ) -> Result<Vec<RecentEvent>> {
+        let rec = sqlx::query_as!(
+            RecentEvent,
+            r#"
+            WITH tracking AS (SELECT timezone FROM trackings WHERE id = $1)
+            SELECT events.type as event_type,
+                events.target as target,
+                events.properties as properties,
+                events.created_at as created_at,
+                sessions.session_id as session_id,
+                sessions.pathname as pathname
+            FROM events JOIN sessions ON sessions.id = events.session_id
+                JOIN visitors ON visitors.id = sessions.visitor_id
+                CROSS JOIN tracking
+            WHERE events.tracking_id = $1
+                AND ($2::DATE IS NULL OR events.created_at >= ($2::DATE::TIMESTAMP AT TIME ZONE tracking.timezone) AT TIME ZONE 'UTC')
+                AND ($3::DATE IS NULL OR events.created_at < (($3::DATE + 1)::TIMESTAMP AT TIME ZONE tracking.timezone) AT TIME ZONE 'UTC')
+                AND segment_matches(sessions, visitors, $4, $5, $6, $7, $8, $9, $10, $11)
+            ORDER BY events.created_at DESC, events.id DESC
+            LIMIT $12
+        "#,
+            tracking_id,
+            range.from,
+            range.to,
+            segment.country,
+            segment.device,
+            segment.os,
+            segment.browser,
+            segment.source,
+            segment.referral,
+            segment.referer_host,
+            segment.pathname,
+            limit
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rec)
+    }
+}
+
+#[derive(FromRow, Serialize)]
+pub struct CountByProperty {
+    value: Option<String>,
+    count: i64,
+}
+
+impl DB {
+    pub async fn count_events_by_property(
+        &self,
+        tracking_id: i32,
+        range: &DateRange,
+        segment: &Segment,
+        property: &str,
+        event_type: Option<&str>,
+    ) -> Result<Vec<CountByProperty>> {
+        let rec = sqlx::query_as!(
+            CountByProperty,
+            r#"
+            WITH tracking AS (SELECT timezone FROM trackings WHERE id = $1)
+            SELECT events.properties->>$12 as value, COUNT(events.id) as "count!"
+            FROM events JOIN sessions ON sessions.id = events.session_id
+                JOIN visitors ON visitors.id = sessions.visitor_id
+                CROSS JOIN tracking
+            WHERE events.tracking_id = $1
+                AND events.properties ? $12
+                AND ($13::TEXT IS NULL OR events.type = $13)
+                AND ($2::DATE IS NULL OR events.created_at >= ($2::DATE::TIMESTAMP AT TIME ZONE tracking.timezone) AT TIME ZONE 'UTC')
+                AND ($3::DATE IS NULL OR events.created_at < (($3::DATE + 1)::TIMESTAMP AT TIME ZONE tracking.timezone) AT TIME ZONE 'UTC')
+                AND segment_matches(sessions, visitors, $4, $5, $6, $7, $8, $9, $10, $11)
+            GROUP BY value"#,
+            tracking_id,
+            range.from,
+            range.to,
+            segment.country,
+            segment.device,
+            segment.os,
+            segment.browser,
+            segment.source,
+            segment.referral,
+            segment.referer_host,
+            segment.pathname,
+            property,
+            event_type
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rec)
+    }
+}
+
+#[derive(FromRow, Serialize)]
+pub struct SingleSource {
+    name: String,
+    visitor_count: i64,
+    session_count: i64,
+}
+
+impl DB {
+    pub async fn create_source(&self, name: &str, tracking_id: i32) -> Result<()> {
+        let _ = sqlx::query!(
+            r#"INSERT INTO sources (name, tracking_id) VALUES ($1, $2) RETURNING id"#,
+            name,
+            tracking_id
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    // Its visitors and their sessions go with it, but events don't cascade.
+    pub async fn delete_source(&self, name: &str, tracking_id: i32) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+
+        sqlx::query!(
+            r#"
+            DELETE FROM events
+            USING sessions, visitors, sources
+            WHERE events.session_id = sessions.id
+                AND sessions.visitor_id = visitors.id
+                AND visitors.source_id = sources.id
+                AND sources.name = $1
+                AND sources.tracking_id = $2
+        "#,
+            name,
+            tracking_id
+        )
+        .execute(&mut tx)
+        .await?;
+        sqlx::query!(
+            r#"DELETE FROM sources WHERE name = $1 AND tracking_id = $2"#,
+            name,
+            tracking_id
+        )
+        .execute(&mut tx)
+        .await?;
+
+        tx.commit().await?;
+        self.lookups.sources.remove(&(tracking_id, name.to_owned()));
+        self.forget_visitors(tracking_id);
+
+        Ok(())
+    }
+
+    // Visitors that have no session left are still counted, as long as neither
+    // a range nor a segment narrows the sessions down. Without a segment, the
+    // counts come from the rollups, where visitors within a range are the ones
+    // first seen in it, as they are for devices.
+    pub async fn list_sources(
+        &self,
+        tracking_id: i32,
+        range: &DateRange,
+        segment: &Segment,
+    ) -> Result<Vec<SingleSource>> {
+        if segment.is_empty() {
+            return self.list_sources_from_rollups(tracking_id, range).await;
+        }
+
+        let sources = sqlx::query_as!(
+            SingleSource,
+            r#"
+            WITH tracking AS (SELECT timezone FROM trackings WHERE id = $1)
+            SELECT sources.name as name,
+                CASE WHEN COALESCE($2::DATE, $3::DATE) IS NULL AND COALESCE($4, $5, $6, $7, $8, $9, $10, $11) IS NULL
+                    THEN COUNT(DISTINCT visitors.id)
+                    ELSE COUNT(DISTINCT sessions.visitor_id)
+                END as "visitor_count!",
+                COUNT(DISTINCT sessions.id) as "session_count!"
+            FROM sources CROSS JOIN tracking
+                LEFT JOIN visitors ON visitors.source_id = sources.id
+                LEFT JOIN sessions ON sessions.visitor_id = visitors.id
+                    AND ($2::DATE IS NULL OR sessions.start_timestamp >= ($2::DATE::TIMESTAMP AT TIME ZONE tracking.timezone) AT TIME ZONE 'UTC')
+                    AND ($3::DATE IS NULL OR sessions.start_timestamp < (($3::DATE + 1)::TIMESTAMP AT TIME ZONE tracking.timezone) AT TIME ZONE 'UTC')
+                    AND segment_matches(sessions, visitors, $4, $5, $6, $7, $8, $9, $10, $11)
+            WHERE sources.tracking_id = $1
+            GROUP BY sources.name 
+            "#,
+            tracking_id,
+            range.from,
+            range.to,
+            segment.country,
+            segment.device,
+            segment.os,
+            segment.browser,
+            segment.source,
+            segment.referral,
+            segment.referer_host,
+            segment.pathname
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(sources)
+    }
+
+    pub async fn visitors_and_sessions_no_source(
+        &self,
+        tracking_id: i32,
+        range: &DateRange,
+        segment: &Segment,
+    ) -> Result<SingleSource> {
+        if segment.is_empty() {
+            return self
+                .visitors_and_sessions_no_source_from_rollups(tracking_id, range)
+                .await;
+        }
+
+        let rec = sqlx::query!(
+            r#"
+            WITH tracking AS (SELECT timezone FROM trackings WHERE id = $1)
+            SELECT CASE WHEN COALESCE($2::DATE, $3::DATE) IS NULL AND COALESCE($4, $5, $6, $7, $8, $9, $10, $11) IS NULL
+                    THEN COUNT(DISTINCT visitors.id)
+                    ELSE COUNT(DISTINCT sessions.visitor_id)
+                END as "visitor_count!",
+                COUNT(DISTINCT sessions.id) as "sessions_count!"
+            FROM visitors CROSS JOIN tracking
+                LEFT JOIN sessions ON sessions.visitor_id = visitors.id
+                    AND ($2::DATE IS NULL OR sessions.start_timestamp >= ($2::DATE::TIMESTAMP AT TIME ZONE tracking.timezone) AT TIME ZONE 'UTC')
+                    AND ($3::DATE IS NULL OR sessions.start_timestamp < (($3::DATE + 1)::TIMESTAMP AT TIME ZONE tracking.timezone) AT TIME ZONE 'UTC')
+                    AND segment_matches(sessions, visitors, $4, $5, $6, $7, $8, $9, $10, $11)
+            WHERE visitors.source_id IS NULL AND visitors.tracking_id = $1
+            "#,
+            tracking_id,
+            range.from,
+            range.to,
+            segment.country,
+            segment.device,
+            segment.os,
+            segment.browser,
+            segment.source,
+            segment.referral,
+            segment.referer_host,
+            segment.pathname
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(SingleSource {
+            name: "direct".to_owned(),
+            visitor_count: rec.visitor_count,
+            session_count: rec.sessions_count,
+        })
+    }
+
+    pub async fn count_sources(&self) -> Result<Option<i64>> {
+        let rec = sqlx::query!(r#"SELECT COUNT(id) as count FROM sources"#)
+            .fetch_one(&self.pool)
+            .await?;
+
+        Ok(rec.count)
+    }
+}
+
+pub struct NewUserData {
+    user_id: String,
+    secret_code: String,
+}
+
+impl NewUserData {
+    pub fn new(secret_code: &str) -> Self {
+        Self {
+            user_id: utils::generate_id(),
+            secret_code: secret_code.to_owned(),
+        }
+    }
+}
+
+#[derive(FromRow, Serialize)]
+pub struct CreatedUser {
+    user_id: String,
+    secret_code: String,
+}
+
+impl DB {
+    pub async fn create_user(&self, data: &NewUserData) -> Result<CreatedUser> {
+        let user = sqlx::query_as!(
+            CreatedUser,
+            r#"INSERT INTO users (user_id, secret_code) VALUES ($1, $2) RETURNING user_id, secret_code"#,
+            data.user_id,
+            data.secret_code
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(user)
+    }
+
+    pub async fn authenticate_user(&self, user_id: &str) -> Result<(i32, String)> {
+        let rec = sqlx::query!(
+            r#"SELECT id, secret_code FROM users WHERE user_id = $1"#,
+            user_id,
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok((rec.id, rec.secret_code))
+    }
+}
+
+pub struct NewTrackingData {
+    tracking_id: String,
+    name: String,
+    owner_id: i32,
+}
+
+impl NewTrackingData {
+    pub fn new(name: String, owner_id: i32) -> Self {
+        Self {
+            tracking_id: utils::generate_id(),
+            name,
+            owner_id,
+        }
+    }
+}
+
+#[derive(FromRow, Serialize)]
+pub struct SingleTracking {
+    id: String,
+    name: String,
+    timezone: String,
+    cookieless: bool,
+    opt_out_policy: String,
+    retention_days: Option<i32>,
+    retention_fold: bool,
+    #[serde(with = "native_date_format")]
+    created_at: NaiveDateTime,
+    visitor_count: Option<i64>,
+    sessions_count: Option<i64>,
+    events_count: Option<i64>,
+    sources_count: Option<i64>,
+}
+
+impl DB {
+    pub async fn create_tracking(&self, data: &NewTrackingData) -> Result<()> {
+        sqlx::query!(
+            r#"INSERT INTO trackings (tracking_id, name, owner_id) VALUES ($1, $2, $3)"#,
+            data.tracking_id,
+            data.name,
+            data.owner_id
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn list_trackings(&self, owner_id: i32) -> Result<Vec<SingleTracking>> {
+        let trackings = sqlx::query_as!(
+            SingleTracking,
+            r#"
+            SELECT trackings.tracking_id as id,
+                trackings.name as name,
+                trackings.timezone as timezone,
+                trackings.cookieless as cookieless,
+                trackings.opt_out_policy as opt_out_policy,
+                trackings.retention_days as retention_days,
+                trackings.retention_fold as retention_fold,
+                trackings.created_at as created_at,
+                COUNT(DISTINCT visitors.id) as visitor_count,
+                COUNT(DISTINCT sessions.id) as sessions_count,
+                COUNT(DISTINCT events.id) as events_count,
+                COUNT(DISTINCT sources.id) as sources_count
+            FROM trackings
+                LEFT JOIN visitors ON visitors.tracking_id = trackings.id
+                LEFT JOIN sessions ON sessions.tracking_id = trackings.id
+                LEFT JOIN events ON events.tracking_id = trackings.id
+                LEFT JOIN sources ON sources.tracking_id = trackings.id
+            WHERE trackings.owner_id = $1 
+            GROUP BY trackings.tracking_id, trackings.name, trackings.timezone, trackings.cookieless, trackings.opt_out_policy, trackings.retention_days, trackings.retention_fold, trackings.created_at
+        "#,
+            owner_id
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(trackings)
+    }
+
+    pub async fn tracking_owner_and_primary_key(&self, tracking_id: &str) -> Result<(i32, i32)> {
+        let rec = sqlx::query!(
+            r#"SELECT id, owner_id FROM trackings WHERE tracking_id = $1"#,
+            tracking_id
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok((rec.id, rec.owner_id))
+    }
+
+    pub async fn tracking_name(&self, tracking_id: i32) -> Result<String> {
+        let rec = sqlx::query!(r#"SELECT name FROM trackings WHERE id = $1"#, tracking_id)
+            .fetch_one(&self.pool)
+            .await?;
+
+        Ok(rec.name)
+    }
+
+    pub async fn delete_tracking(&self, tracking_id: i32) -> Result<()> {
+        sqlx::query!(r#"DELETE FROM trackings WHERE id = $1"#, tracking_id)
+            .execute(&self.pool)
+            .await?;
+        self.forget_tracking(tracking_id);
+
+        Ok(())
+    }
+
+    pub async fn rename_tracking(&self, tracking_id: i32, name: &str) -> Result<()> {
+        sqlx::query!(
+            r#"UPDATE trackings SET name = $1 WHERE id = $2"#,
+            name,
+            tracking_id
+        )
+        .execute(&self.pool)
+        .await?;
+        self.lookups.trackings.retain(|_, id| *id != tracking_id);
+
+        Ok(())
+    }
+
+    pub async fn tracking_timezone(&self, tracking_id: i32) -> Result<String> {
+        let rec = sqlx::query!(
+            r#"SELECT timezone FROM trackings WHERE id = $1"#,
+            tracking_id
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(rec.timezone)
+    }
+
+    pub async fn is_valid_timezone(&self, timezone: &str) -> Result<bool> {
+        let rec = sqlx::query!(
+            r#"SELECT EXISTS(SELECT 1 FROM pg_timezone_names WHERE name = $1) as "valid!""#,
+            timezone
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(rec.valid)
+    }
+
+    pub async fn set_tracking_timezone(&self, tracking_id: i32, timezone: &str) -> Result<()> {
+        sqlx::query!(
+            r#"UPDATE trackings SET timezone = $1 WHERE id = $2"#,
+            timezone,
+            tracking_id
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn tracking_cookieless(&self, tracking_id: i32) -> Result<bool> {
+        let rec = sqlx::query!(
+            r#"SELECT cookieless FROM trackings WHERE id = $1"#,
+            tracking_id
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(rec.cookieless)
+    }
+
+    pub async fn set_tracking_cookieless(&self, tracking_id: i32, cookieless: bool) -> Result<()> {
+        sqlx::query!(
+            r#"UPDATE trackings SET cookieless = $1 WHERE id = $2"#,
+            cookieless,
+            tracking_id
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn tracking_opt_out_policy(&self, tracking_id: i32) -> Result<OptOutPolicy> {
+        let rec = sqlx::query!(
+            r#"SELECT opt_out_policy FROM trackings WHERE id = $1"#,
+            tracking_id
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        let policy = match rec.opt_out_policy.as_str() {
+            "drop" => OptOutPolicy::Drop,
+            "anonymous" => OptOutPolicy::Anonymous,
+            _ => OptOutPolicy::Ignore,
+        };
+
+        Ok(policy)
+    }
+
+    pub async fn set_tracking_opt_out_policy(
+        &self,
+        tracking_id: i32,
+        policy: OptOutPolicy,
+    ) -> Result<()> {
+        sqlx::query!(
+            r#"UPDATE trackings SET opt_out_policy = $1 WHERE id = $2"#,
+            policy.as_str(),
+            tracking_id
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn set_tracking_retention(
+        &self,
+        tracking_id: i32,
+        days: Option<i32>,
+        fold: bool,
+    ) -> Result<()> {
+        sqlx::query!(
+            r#"UPDATE trackings SET retention_days = $1, retention_fold = $2 WHERE id = $3"#,
+            days,
+            fold,
+            tracking_id
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    // Hits are counted per day in the tracking's timezone. Only anonymous page
+    // views carry a pathname, everything else is counted under an empty one.
+    pub async fn record_opted_out_hits(
+        &self,
+        tracking_id: i32,
+        pathname: Option<&str>,
+        suppressed: i64,
+        page_views: i64,
+    ) -> Result<()> {
+        sqlx::query!(
+            r#"
+            INSERT INTO opted_out_hits (tracking_id, date, pathname, suppressed, page_views)
+            SELECT $1, (CURRENT_TIMESTAMP AT TIME ZONE trackings.timezone)::DATE, COALESCE($2, ''), $3, $4
+            FROM trackings WHERE trackings.id = $1
+            ON CONFLICT (tracking_id, date, pathname) DO UPDATE SET
+                suppressed = opted_out_hits.suppressed + EXCLUDED.suppressed,
+                page_views = opted_out_hits.page_views + EXCLUDED.page_views
+        "#,
+            tracking_id,
+            pathname,
+            suppressed,
+            page_views
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn opted_out_hits(
+        &self,
+        tracking_id: i32,
+        range: &DateRange,
+    ) -> Result<Vec<OptedOutHits>> {
+        let rec = sqlx::query_as!(
+            OptedOutHits,
+            r#"
+            SELECT pathname,
+                SUM(suppressed)::BIGINT as "suppressed!",
+                SUM(page_views)::BIGINT as "page_views!"
+            FROM opted_out_hits
+            WHERE tracking_id = $1
+                AND ($2::DATE IS NULL OR date >= $2)
+                AND ($3::DATE IS NULL OR date <= $3)
+            GROUP BY pathname
+            ORDER BY 3 DESC, pathname
+        "#,
+            tracking_id,
+            range.from,
+            range.to
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rec)
+    }
+}
+
+pub struct NewApiKeyData {
+    key_id: String,
+    name: String,
+    key: String,
+    tracking_id: i32,
+}
+
+impl NewApiKeyData {
+    pub fn new(name: String, tracking_id: i32) -> Self {
+        Self {
+            key_id: utils::generate_id(),
+            name,
+            key: utils::generate_api_key(),
+            tracking_id,
+        }
+    }
+}
+
+#[derive(FromRow, Serialize)]
+pub struct CreatedApiKey {
+    id: String,
+    name: String,
+    key: String,
+}
+
+#[derive(FromRow, Serialize)]
+pub struct SingleApiKey {
+    id: String,
+    name: String,
+    key_prefix: String,
+    #[serde(with = "native_date_format")]
+    created_at: NaiveDateTime,
+    #[serde(with = "optional_native_date_format")]
+    revoked_at: Option<NaiveDateTime>,
+}
+
+impl DB {
+    pub async fn create_api_key(&self, data: &NewApiKeyData) -> Result<CreatedApiKey> {
+        sqlx::query!(
+            r#"INSERT INTO api_keys (key_id, name, key_prefix, key_hash, tracking_id)
+            VALUES ($1, $2, LEFT($3, 16), ENCODE(SHA256(CONVERT_TO($3, 'UTF8')), 'hex'), $4)"#,
+            data.key_id,
+            data.name,
+            data.key,
+            data.tracking_id
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(CreatedApiKey {
+            id: data.key_id.to_owned(),
+            name: data.name.to_owned(),
+            key: data.key.to_owned(),
+        })
+    }
+
+    pub async fn list_api_keys(&self, tracking_id: i32) -> Result<Vec<SingleApiKey>> {
+        let keys = sqlx::query_as!(
+            SingleApiKey,
+            r#"
+            SELECT key_id as id, name, key_prefix, created_at, revoked_at
+            FROM api_keys
+            WHERE tracking_id = $1
+            ORDER BY created_at
+            "#,
+            tracking_id
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(keys)
+    }
+
+    // Returns whether the tracking has such a key. Revoking a key twice keeps
+    // the time it was first revoked at.
+    pub async fn revoke_api_key(&self, key_id: &str, tracking_id: i32) -> Result<bool> {
+        let rec = sqlx::query!(
+            r#"UPDATE api_keys SET revoked_at = COALESCE(revoked_at, CURRENT_TIMESTAMP)
+            WHERE key_id = $1 AND tracking_id = $2"#,
+            key_id,
+            tracking_id
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(rec.rows_affected() > 0)
+    }
+}
+
+#[derive(FromRow, Serialize, Default)]
+pub struct ExportedVisitor {
+    visitor_id: String,
+    created_at: String,
+    referer: String,
+    source: Option<String>,
+    browser: Option<String>,
+    os: Option<String>,
+    device: Option<String>,
+}
+
+#[derive(FromRow, Serialize, Default)]
+pub struct ExportedSession {
+    session_id: String,
+    visitor_id: String,
+    start_timestamp: String,
+    end_timestamp: Option<String>,
+    title: String,
+    pathname: String,
+    referral: Option<String>,
+    country_code: Option<String>,
+    country: Option<String>,
+    city: Option<String>,
+    continent_code: Option<String>,
+    source: Option<String>,
+    browser: Option<String>,
+    os: Option<String>,
+    device: Option<String>,
+}
+
+#[derive(FromRow, Serialize, Default)]
+pub struct ExportedEvent {
+    session_id: String,
+    visitor_id: String,
+    created_at: String,
+    #[serde(rename = "type")]
+    event_type: String,
+    target: String,
+    properties: Option<serde_json::Value>,
+    pathname: String,
+}
+
+// CSV fields can't hold nested values, so JSON goes there as text.
+fn json_as_text(value: Option<serde_json::Value>) -> Option<serde_json::Value> {
+    value.map(|value| serde_json::Value::String(value.to_string()))
+}
+
+impl ExportedEvent {
+    pub fn with_text_properties(mut self) -> Self {
+        self.properties = json_as_text(self.properties);
+        self
+    }
+}
+
+// Exports are streamed row by row, so they never have to fit in memory.
+// Timestamps are ISO 8601 in UTC to keep them readable in a spreadsheet.
+impl DB {
+    pub fn export_visitors(
+        &self,
+        tracking_id: i32,
+        range: &DateRange,
+    ) -> BoxStream<'_, Result<ExportedVisitor>> {
+        sqlx::query_as!(
+            ExportedVisitor,
+            r#"
+            WITH tracking AS (SELECT timezone FROM trackings WHERE id = $1)
+            SELECT visitors.visitor_id as "visitor_id!",
+                TO_CHAR(visitors.created_at, 'YYYY-MM-DD"T"HH24:MI:SS"Z"') as "created_at!",
+                visitors.referer as referer,
+                sources.name as "source?",
+                visitors.user_agent_parsed->'user_agent'->>'family' as browser,
+                visitors.user_agent_parsed->'os'->>'family' as os,
+                visitors.user_agent_parsed->'device'->>'family' as device
+            FROM visitors CROSS JOIN tracking
+                LEFT JOIN sources ON sources.id = visitors.source_id
+            WHERE visitors.tracking_id = $1
+                AND ($2::DATE IS NULL OR visitors.created_at >= ($2::DATE::TIMESTAMP AT TIME ZONE tracking.timezone) AT TIME ZONE 'UTC')
+                AND ($3::DATE IS NULL OR visitors.created_at < (($3::DATE + 1)::TIMESTAMP AT TIME ZONE tracking.timezone) AT TIME ZONE 'UTC')
+            ORDER BY visitors.id
+        "#,
+            tracking_id,
+            range.from,
+            range.to
+        )
+        .fetch(&self.pool)
+    }
+
+    pub fn export_sessions(
+        &self,
+        tracking_id: i32,
+        range: &DateRange,
+    ) -> BoxStream<'_, Result<ExportedSession>> {
+        sqlx::query_as!(
+            ExportedSession,
+            r#"
+            WITH tracking AS (SELECT timezone FROM trackings WHERE id = $1)
+            SELECT sessions.session_id as "session_id!",
+                visitors.visitor_id as "visitor_id!",
+                TO_CHAR(sessions.start_timestamp, 'YYYY-MM-DD"T"HH24:MI:SS"Z"') as "start_timestamp!",
+                TO_CHAR(sessions.end_timestamp, 'YYYY-MM-DD"T"HH24:MI:SS"Z"') as end_timestamp,
+                sessions.title as title,
+                sessions.pathname as pathname,
+                sessions.referral as referral,
+                sessions.location->'country'->>'iso_code' as country_code,
+                sessions.location->'country'->'names'->>'en' as country,
+                sessions.location->'city'->'names'->>'en' as city,
+                sessions.location->'continent'->>'code' as continent_code,
+                sources.name as "source?",
+                visitors.user_agent_parsed->'user_agent'->>'family' as browser,
+                visitors.user_agent_parsed->'os'->>'family' as os,
+                visitors.user_agent_parsed->'device'->>'family' as device
+            FROM sessions JOIN visitors ON visitors.id = sessions.visitor_id
+                CROSS JOIN tracking
+                LEFT JOIN sources ON sources.id = visitors.source_id
+            WHERE sessions.tracking_id = $1
+                AND ($2::DATE IS NULL OR sessions.start_timestamp >= ($2::DATE::TIMESTAMP AT TIME ZONE tracking.timezone) AT TIME ZONE 'UTC')
+                AND ($3::DATE IS NULL OR sessions.start_timestamp < (($3::DATE + 1)::TIMESTAMP AT TIME ZONE tracking.timezone) AT TIME ZONE 'UTC')
+            ORDER BY sessions.id
+        "#,
+            tracking_id,
+            range.from,
+            range.to
+        )
+        .fetch(&self.pool)
+    }
+
+    pub fn export_events(
+        &self,
+        tracking_id: i32,
+        range: &DateRange,
+    ) -> BoxStream<'_, Result<ExportedEvent>> {
+        sqlx::query_as!(
+            ExportedEvent,
+            r#"
+            WITH tracking AS (SELECT timezone FROM trackings WHERE id = $1)
+            SELECT sessions.session_id as "session_id!",
+                visitors.visitor_id as "visitor_id!",
+                TO_CHAR(events.created_at, 'YYYY-MM-DD"T"HH24:MI:SS"Z"') as "created_at!",
+                events.type as event_type,
+                events.target as target,
+                events.properties as properties,
+                sessions.pathname as pathname
+            FROM events JOIN sessions ON sessions.id = events.session_id
+                JOIN visitors ON visitors.id = sessions.visitor_id
+                CROSS JOIN tracking
+            WHERE events.tracking_id = $1
+                AND ($2::DATE IS NULL OR events.created_at >= ($2::DATE::TIMESTAMP AT TIME ZONE tracking.timezone) AT TIME ZONE 'UTC')
+                AND ($3::DATE IS NULL OR events.created_at < (($3::DATE + 1)::TIMESTAMP AT TIME ZONE tracking.timezone) AT TIME ZONE 'UTC')
+            ORDER BY events.id
+        "#,
+            tracking_id,
+            range.from,
+            range.to
+        )
+        .fetch(&self.pool)
+    }
+}
+
+pub struct VisitorIdentity {
+    email: Option<String>,
+    name: Option<String>,
+    traits: Option<serde_json::Value>,
+}
+
+impl VisitorIdentity {
+    pub fn new(
+        email: Option<String>,
+        name: Option<String>,
+        traits: Option<serde_json::Value>,
+    ) -> Self {
+        Self {
+            email,
+            name,
+            traits,
+        }
+    }
+}
+
+#[derive(FromRow, Serialize)]
+pub struct VisitorSummary {
+    visitor_id: String,
+    email: Option<String>,
+    name: Option<String>,
+    traits: Option<serde_json::Value>,
+    #[serde(with = "optional_native_date_format")]
+    identified_at: Option<NaiveDateTime>,
+    #[serde(with = "native_date_format")]
+    first_seen: NaiveDateTime,
+    #[serde(with = "native_date_format")]
+    last_seen: NaiveDateTime,
+    sessions: i64,
+    first_source: Option<String>,
+    first_referer: String,
+}
+
+#[derive(FromRow, Serialize, Default)]
+pub struct ExportedIdentifiedVisitor {
+    visitor_id: String,
+    email: Option<String>,
+    name: Option<String>,
+    traits: Option<serde_json::Value>,
+    identified_at: String,
+    first_seen: String,
+    first_source: Option<String>,
+    first_referer: String,
+}
+
+impl ExportedIdentifiedVisitor {
+    pub fn with_text_traits(mut self) -> Self {
+        self.traits = json_as_text(self.traits);
+        self
+    }
+}
+
+impl DB {
+    // Fields that aren't given keep their previous value and traits are merged
+    // into the existing ones, so a visitor can be identified bit by bit.
+    // Returns false when the visitor doesn't belong to the tracking.
+    pub async fn identify_visitor(
+        &self,
+        tracking_id: i32,
+        visitor_id: &str,
+        identity: &VisitorIdentity,
+    ) -> Result<bool> {
+        let rec = sqlx::query!(
+            r#"
+            UPDATE visitors
+            SET email = COALESCE($3, email),
+                name = COALESCE($4, name),
+                traits = CASE WHEN $5::JSONB IS NULL THEN traits ELSE COALESCE(traits, '{}'::JSONB) || $5::JSONB END,
+                identified_at = CURRENT_TIMESTAMP
+            WHERE visitor_id = $1 AND tracking_id = $2
+        "#,
+            visitor_id,
+            tracking_id,
+            identity.email,
+            identity.name,
+            identity.traits
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(rec.rows_affected() > 0)
+    }
+
+    // The source and referer of a visitor are the ones it first arrived with.
+    pub async fn list_visitors(
+        &self,
+        tracking_id: i32,
+        identified_only: bool,
+        search: Option<&str>,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<VisitorSummary>> {
+        let rec = sqlx::query_as!(
+            VisitorSummary,
+            r#"
+            SELECT visitors.visitor_id as "visitor_id!",
+                visitors.email as email,
+                visitors.name as name,
+                visitors.traits as traits,
+                visitors.identified_at as identified_at,
+                visitors.created_at as first_seen,
+                GREATEST(visitors.created_at, MAX(sessions.start_timestamp), MAX(sessions.end_timestamp)) as "last_seen!",
+                COUNT(sessions.id) as "sessions!",
+                sources.name as "first_source?",
+                visitors.referer as first_referer
+            FROM visitors
+                LEFT JOIN sessions ON sessions.visitor_id = visitors.id
+                LEFT JOIN sources ON sources.id = visitors.source_id
+            WHERE visitors.tracking_id = $1
+                AND (NOT $2 OR visitors.identified_at IS NOT NULL)
+                AND ($3::TEXT IS NULL
+                    OR visitors.email ILIKE '%' || $3 || '%'
+                    OR visitors.name ILIKE '%' || $3 || '%'
+                    OR visitors.visitor_id = $3)
+            GROUP BY visitors.id, sources.name
+            ORDER BY "last_seen!" DESC, visitors.id DESC
+            LIMIT $4 OFFSET $5
+        "#,
+            tracking_id,
+            identified_only,
+            search,
+            limit,
+            offset
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rec)
+    }
+
+    pub fn export_identified_visitors<'a>(
+        &'a self,
+        tracking_id: i32,
+        search: Option<&'a str>,
+    ) -> BoxStream<'a, Result<ExportedIdentifiedVisitor>> {
+        sqlx::query_as!(
+            ExportedIdentifiedVisitor,
+            r#"
+            SELECT visitors.visitor_id as "visitor_id!",
+                visitors.email as email,
+                visitors.name as name,
+                visitors.traits as traits,
+                TO_CHAR(visitors.identified_at, 'YYYY-MM-DD"T"HH24:MI:SS"Z"') as "identified_at!",
+                TO_CHAR(visitors.created_at, 'YYYY-MM-DD"T"HH24:MI:SS"Z"') as "first_seen!",
+                sources.name as "first_source?",
+                visitors.referer as first_referer
+            FROM visitors LEFT JOIN sources ON sources.id = visitors.source_id
+            WHERE visitors.tracking_id = $1
+                AND visitors.identified_at IS NOT NULL
+                AND ($2::TEXT IS NULL
+                    OR visitors.email ILIKE '%' || $2 || '%'
+                    OR visitors.name ILIKE '%' || $2 || '%'
+                    OR visitors.visitor_id = $2)
+            ORDER BY visitors.id
+        "#,
+            tracking_id,
+            search
+        )
+        .fetch(&self.pool)
+    }
+}
+
+#[derive(FromRow, Serialize)]
+pub struct VisitorProfile {
+    #[serde(skip)]
+    id: i32,
+    visitor_id: String,
+    email: Option<String>,
+    name: Option<String>,
+    traits: Option<serde_json::Value>,
+    #[serde(with = "optional_native_date_format")]
+    identified_at: Option<NaiveDateTime>,
+    user_agent: serde_json::Value,
+    first_source: Option<String>,
+    first_referer: String,
+    #[serde(with = "native_date_format")]
+    first_seen: NaiveDateTime,
+    #[serde(with = "native_date_format")]
+    last_seen: NaiveDateTime,
+    sessions: i64,
+    events: i64,
+    total_time: f64,
+}
+
+#[derive(FromRow, Serialize)]
+pub struct TimelineEntry {
+    kind: String,
+    #[serde(with = "native_date_format")]
+    at: NaiveDateTime,
+    session_id: String,
+    pathname: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    title: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    referral: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    country: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    duration: Option<f64>,
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    event_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    target: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    properties: Option<serde_json::Value>,
+}
+
+impl VisitorProfile {
+    pub fn id(&self) -> i32 {
+        self.id
+    }
+}
+
+impl DB {
+    // Total time only counts sessions that were ended.
+    pub async fn visitor_profile(
+        &self,
+        tracking_id: i32,
+        visitor_id: &str,
+    ) -> Result<Option<VisitorProfile>> {
+        let rec = sqlx::query_as!(
+            VisitorProfile,
+            r#"
+            SELECT visitors.id as id,
+                visitors.visitor_id as "visitor_id!",
+                visitors.email as email,
+                visitors.name as name,
+                visitors.traits as traits,
+                visitors.identified_at as identified_at,
+                visitors.user_agent_parsed as user_agent,
+                sources.name as "first_source?",
+                visitors.referer as first_referer,
+                visitors.created_at as first_seen,
+                GREATEST(visitors.created_at, session_stats.last_start, session_stats.last_end, event_stats.last_event) as "last_seen!",
+                COALESCE(session_stats.sessions, 0) as "sessions!",
+                COALESCE(event_stats.events, 0) as "events!",
+                COALESCE(session_stats.total_time, 0) as "total_time!"
+            FROM visitors
+                LEFT JOIN sources ON sources.id = visitors.source_id
+                LEFT JOIN LATERAL (
+                    SELECT COUNT(sessions.id) AS sessions,
+                        MAX(sessions.start_timestamp) AS last_start,
+                        MAX(sessions.end_timestamp) AS last_end,
+                        SUM(EXTRACT(EPOCH FROM (sessions.end_timestamp - sessions.start_timestamp)))::FLOAT8 AS total_time
+                    FROM sessions
+                    WHERE sessions.visitor_id = visitors.id
+                ) session_stats ON TRUE
+                LEFT JOIN LATERAL (
+                    SELECT COUNT(events.id) AS events,
+                        MAX(events.created_at) AS last_event
+                    FROM events JOIN sessions ON sessions.id = events.session_id
+                    WHERE sessions.visitor_id = visitors.id
+                ) event_stats ON TRUE
+            WHERE visitors.tracking_id = $1 AND visitors.visitor_id = $2
+        "#,
+            tracking_id,
+            visitor_id
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(rec)
+    }
+
+    // Every session start, event and session end of a visitor. Session starts
+    // and ends are taken from the client's clock but events from the server's,
+    // so entries are grouped by session, oldest first, and events are only
+    // ordered against the other events of their session.
+    pub async fn visitor_timeline(&self, visitor_id: i32) -> Result<Vec<TimelineEntry>> {
+        let rec = sqlx::query_as!(
+            TimelineEntry,
+            r#"
+            SELECT kind as "kind!",
+                at as "at!",
+                session_id as "session_id!",
+                pathname as "pathname!",
+                title,
+                referral,
+                country,
+                duration,
+                event_type,
+                target,
+                properties
+            FROM (
+                SELECT 'session_start' AS kind,
+                    sessions.start_timestamp AS at,
+                    sessions.session_id,
+                    sessions.pathname,
+                    sessions.title,
+                    sessions.referral,
+                    sessions.location->'country'->>'iso_code' AS country,
+                    NULL::FLOAT8 AS duration,
+                    NULL::TEXT AS event_type,
+                    NULL::TEXT AS target,
+                    NULL::JSONB AS properties,
+                    sessions.start_timestamp AS session_start,
+                    sessions.id AS session_key
+                FROM sessions
+                WHERE sessions.visitor_id = $1
+                UNION ALL
+                SELECT 'event',
+                    events.created_at,
+                    sessions.session_id,
+                    sessions.pathname,
+                    NULL,
+                    NULL,
+                    NULL,
+                    NULL,
+                    events.type,
+                    events.target,
+                    events.properties,
+                    sessions.start_timestamp,
+                    sessions.id
+                FROM events JOIN sessions ON sessions.id = events.session_id
+                WHERE sessions.visitor_id = $1
+                UNION ALL
+                SELECT 'session_end',
+                    sessions.end_timestamp,
+                    sessions.session_id,
+                    sessions.pathname,
+                    NULL,
+                    NULL,
+                    NULL,
+                    EXTRACT(EPOCH FROM (sessions.end_timestamp - sessions.start_timestamp))::FLOAT8,
+                    NULL,
+                    NULL,
+                    NULL,
+                    sessions.start_timestamp,
+                    sessions.id
+                FROM sessions
+                WHERE sessions.visitor_id = $1 AND sessions.end_timestamp IS NOT NULL
+            ) timeline
+            ORDER BY session_start, session_key, kind = 'session_end', kind = 'event', at
+        "#,
+            visitor_id
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rec)
+    }
+}
+
+pub enum DataSubject {
+    VisitorId(String),
+    Email(String),
+}
+
+impl DataSubject {
+    fn visitor_id(&self) -> Option<&str> {
+        match self {
+            DataSubject::VisitorId(visitor_id) => Some(visitor_id),
+            DataSubject::Email(_) => None,
+        }
+    }
+
+    fn email(&self) -> Option<&str> {
+        match self {
+            DataSubject::VisitorId(_) => None,
+            DataSubject::Email(email) => Some(email),
+        }
+    }
+
+    fn kind(&self) -> &'static str {
+        match self {
+            DataSubject::VisitorId(_) => "visitor_id",
+            DataSubject::Email(_) => "email",
+        }
+    }
+}
+
+#[derive(FromRow, Serialize)]
+pub struct DataSubjectVisitor {
+    #[serde(skip)]
+    id: i32,
+    visitor_id: String,
+    email: Option<String>,
+    name: Option<String>,
+    traits: Option<serde_json::Value>,
+    #[serde(with = "optional_native_date_format")]
+    identified_at: Option<NaiveDateTime>,
+    referer: String,
+    source: Option<String>,
+    user_agent: serde_json::Value,
+    #[serde(with = "native_date_format")]
+    created_at: NaiveDateTime,
+}
+
+#[derive(FromRow, Serialize)]
+pub struct DataSubjectSession {
+    session_id: String,
+    visitor_id: String,
+    #[serde(with = "native_date_format")]
+    start_timestamp: NaiveDateTime,
+    #[serde(with = "optional_native_date_format")]
+    end_timestamp: Option<NaiveDateTime>,
+    title: String,
+    pathname: String,
+    referral: Option<String>,
+    location: Option<serde_json::Value>,
+}
+
+#[derive(FromRow, Serialize)]
+pub struct DataSubjectEvent {
+    session_id: String,
+    #[serde(rename = "type")]
+    event_type: String,
+    target: String,
+    properties: Option<serde_json::Value>,
+    #[serde(with = "native_date_format")]
+    created_at: NaiveDateTime,
+}
+
+#[derive(Serialize)]
+pub struct DataSubjectData {
+    visitors: Vec<DataSubjectVisitor>,
+    sessions: Vec<DataSubjectSession>,
+    events: Vec<DataSubjectEvent>,
+}
+
+impl DataSubjectData {
+    pub fn is_empty(&self) -> bool {
+        self.visitors.is_empty()
+    }
+}
+
+#[derive(Serialize, Default)]
+pub struct ErasedDataSubject {
+    visitors: u64,
+    sessions: u64,
+    events: u64,
+}
+
+impl ErasedDataSubject {
+    pub fn is_empty(&self) -> bool {
+        self.visitors == 0
+    }
+}
+
+// Both operations are audited in the same transaction they run in. The audit
+// log only keeps whether a visitor id or an email was asked about, and for an
+// export the ids of the visitors it matched. An erasure only keeps counts and
+// takes the erased ids out of earlier entries, so nothing that identifies the
+// visitor is left behind.
+impl DB {
+    pub async fn export_data_subject(
+        &self,
+        tracking_id: i32,
+        user_id: i32,
+        subject: &DataSubject,
+    ) -> Result<DataSubjectData> {
+        let mut tx = self.pool.begin().await?;
+
+        let visitors = sqlx::query_as!(
+            DataSubjectVisitor,
+            r#"
+            SELECT visitors.id as id,
+                visitors.visitor_id as "visitor_id!",
+                visitors.email as email,
+                visitors.name as name,
+                visitors.traits as traits,
+                visitors.identified_at as identified_at,
+                visitors.referer as referer,
+                sources.name as "source?",
+                visitors.user_agent_parsed as user_agent,
+                visitors.created_at as created_at
+            FROM visitors LEFT JOIN sources ON sources.id = visitors.source_id
+            WHERE visitors.tracking_id = $1
+                AND (visitors.visitor_id = $2 OR LOWER(visitors.email) = LOWER($3))
+            ORDER BY visitors.id
+        "#,
+            tracking_id,
+            subject.visitor_id(),
+            subject.email()
+        )
+        .fetch_all(&mut tx)
+        .await?;
+        let ids: Vec<i32> = visitors.iter().map(|visitor| visitor.id).collect();
+
+        let sessions = sqlx::query_as!(
+            DataSubjectSession,
+            r#"
+            SELECT sessions.session_id as "session_id!",
+                visitors.visitor_id as "visitor_id!",
+                sessions.start_timestamp as start_timestamp,
+                sessions.end_timestamp as end_timestamp,
+                sessions.title as title,
+                sessions.pathname as pathname,
+                sessions.referral as referral,
+                sessions.location::JSONB as location
+            FROM sessions JOIN visitors ON visitors.id = sessions.visitor_id
+            WHERE sessions.visitor_id = ANY($1)
+            ORDER BY sessions.start_timestamp, sessions.id
+        "#,
+            &ids
+        )
+        .fetch_all(&mut tx)
+        .await?;
+
+        let events = sqlx::query_as!(
+            DataSubjectEvent,
+            r#"
+            SELECT sessions.session_id as "session_id!",
+                events.type as event_type,
+                events.target as target,
+                events.properties as properties,
+                events.created_at as created_at
+            FROM events JOIN sessions ON sessions.id = events.session_id
+            WHERE sessions.visitor_id = ANY($1)
+            ORDER BY events.created_at, events.id
+        "#,
+            &ids
+        )
+        .fetch_all(&mut tx)
+        .await?;
+
+        let details = serde_json::json!({
+            "subject": subject.kind(),
+            "visitors": visitors.len(),
+            "sessions": sessions.len(),
+            "events": events.len(),
+        });
+        let visitor_ids: Vec<String> = visitors
+            .iter()
+            .map(|visitor| visitor.visitor_id.clone())
+            .collect();
+        audit(
+            &mut tx,
+            tracking_id,
+            user_id,
+            "data_subject_export",
+            &visitor_ids,
+            details,
+        )
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(DataSubjectData {
+            visitors,
+            sessions,
+            events,
+        })
+    }
+
+    pub async fn erase_data_subject(
+        &self,
+        tracking_id: i32,
+        user_id: i32,
+        subject: &DataSubject,
+    ) -> Result<ErasedDataSubject> {
+        let mut tx = self.pool.begin().await?;
+
+        let (ids, visitor_ids): (Vec<i32>, Vec<String>) = sqlx::query!(
+            r#"
+            SELECT id, visitor_id as "visitor_id!" FROM visitors
+            WHERE tracking_id = $1 AND (visitor_id = $2 OR LOWER(email) = LOWER($3))
+            FOR UPDATE
+        "#,
+            tracking_id,
+            subject.visitor_id(),
+            subject.email()
+        )
+        .fetch_all(&mut tx)
+        .await?
+        .into_iter()
+        .map(|rec| (rec.id, rec.visitor_id))
+        .unzip();
+
+        let events = sqlx::query!(
+            r#"DELETE FROM events WHERE session_id IN (SELECT id FROM sessions WHERE visitor_id = ANY($1))"#,
+            &ids
+        )
+        .execute(&mut tx)
+        .await?
+        .rows_affected();
+        let sessions = sqlx::query!(r#"DELETE FROM sessions WHERE visitor_id = ANY($1)"#, &ids)
+            .execute(&mut tx)
+            .await?
+            .rows_affected();
+        let visitors = sqlx::query!(r#"DELETE FROM visitors WHERE id = ANY($1)"#, &ids)
+            .execute(&mut tx)
+            .await?
+            .rows_affected();
+
+        let erased = ErasedDataSubject {
+            visitors,
+            sessions,
+            events,
+        };
         sqlx::query!(
-            r#"INSERT INTO trackings (tracking_id, name, owner_id) VALUES ($1, $2, $3)"#,
-            data.tracking_id,
-            data.name,
-            data.owner_id
+            r#"
+            UPDATE audit_log
+            SET visitor_ids = ARRAY(SELECT id FROM UNNEST(visitor_ids) AS ids (id) WHERE id <> ALL($2))
+            WHERE tracking_id = $1 AND visitor_ids && $2
+        "#,
+            tracking_id,
+            &visitor_ids
         )
-        .execute(&self.pool)
+        .execute(&mut tx)
         .await?;
 
-        Ok(())
+        let mut details = serde_json::to_value(&erased).unwrap_or_default();
+        details["subject"] = subject.kind().into();
+        audit(
+            &mut tx,
+            tracking_id,
+            user_id,
+            "data_subject_erase",
+            &[],
+            details,
+        )
+        .await?;
+
+        tx.commit().await?;
+        self.lookups.visitors.retain(|_, id| !ids.contains(id));
+
+        Ok(erased)
+    }
+}
+
+async fn audit(
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    tracking_id: i32,
+    user_id: i32,
+    action: &str,
+    visitor_ids: &[String],
+    details: serde_json::Value,
+) -> Result<()> {
+    sqlx::query!(
+        r#"INSERT INTO audit_log (action, visitor_ids, details, user_id, tracking_id)
+        VALUES ($1, $2, $3, $4, $5)"#,
+        action,
+        visitor_ids,
+        details,
+        user_id,
+        tracking_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    Ok(())
+}
+
+// Lets the rest of the transaction delete sessions and visitors without taking
+// them out of `rollups`, for purges that fold what they delete.
+async fn keep_rollups(tx: &mut sqlx::Transaction<'_, sqlx::Postgres>) -> Result<()> {
+    sqlx::query!(r#"SELECT set_config('trantor.keep_rollups', 'on', true)"#)
+        .fetch_one(&mut *tx)
+        .await?;
+
+    Ok(())
+}
+
+pub struct RetentionPolicy {
+    tracking_id: i32,
+    days: i32,
+    fold: bool,
+}
+
+impl RetentionPolicy {
+    pub fn tracking_id(&self) -> i32 {
+        self.tracking_id
     }
 
-    pub async fn list_trackings(&self, owner_id: i32) -> Result<Vec<SingleTracking>> {
-        let trackings = sqlx::query_as!(
-            SingleTracking,
+    pub fn days(&self) -> i32 {
+        self.days
+    }
+
+    pub fn fold(&self) -> bool {
+        self.fold
+    }
+}
+
+#[derive(Default)]
+pub struct PurgedSessions {
+    sessions: u64,
+    events: u64,
+}
+
+impl PurgedSessions {
+    pub fn sessions(&self) -> u64 {
+        self.sessions
+    }
+
+    pub fn events(&self) -> u64 {
+        self.events
+    }
+}
+
+impl DB {
+    pub async fn retention_policies(&self) -> Result<Vec<RetentionPolicy>> {
+        let rec = sqlx::query_as!(
+            RetentionPolicy,
             r#"
-            SELECT trackings.tracking_id as id,
-                trackings.name as name,
-                trackings.created_at as created_at,
-                COUNT(DISTINCT visitors.id) as visitor_count,
-                COUNT(DISTINCT sessions.id) as sessions_count,
-                COUNT(DISTINCT events.id) as events_count,
-                COUNT(DISTINCT sources.id) as sources_count
+            SELECT id as tracking_id, retention_days as "days!", retention_fold as fold
             FROM trackings
-                LEFT JOIN visitors ON visitors.tracking_id = trackings.id
-                LEFT JOIN sessions ON sessions.tracking_id = trackings.id
-                LEFT JOIN events ON events.tracking_id = trackings.id
-                LEFT JOIN sources ON sources.tracking_id = trackings.id
-            WHERE trackings.owner_id = $1 
-            GROUP BY trackings.tracking_id, trackings.name, trackings.created_at
-        "#,
-            owner_id
+            WHERE retention_days IS NOT NULL
+            ORDER BY id
+        "#
         )
         .fetch_all(&self.pool)
         .await?;
 
-        Ok(trackings)
+        Ok(rec)
     }
 
-    pub async fn tracking_owner_and_primary_key(&self, tracking_id: &str) -> Result<(i32, i32)> {
+    // The oldest day, in the tracking's timezone, that has sessions older than
+    // the last `days` days.
+    pub async fn oldest_expired_day(
+        &self,
+        tracking_id: i32,
+        days: i32,
+    ) -> Result<Option<NaiveDate>> {
         let rec = sqlx::query!(
-            r#"SELECT id, owner_id FROM trackings WHERE tracking_id = $1"#,
-            tracking_id
+            r#"
+            WITH tracking AS (SELECT timezone FROM trackings WHERE id = $1)
+            SELECT MIN(sessions.start_timestamp AT TIME ZONE 'UTC' AT TIME ZONE tracking.timezone)::DATE as day
+            FROM sessions CROSS JOIN tracking
+            WHERE sessions.tracking_id = $1
+                AND sessions.start_timestamp < (((CURRENT_TIMESTAMP AT TIME ZONE tracking.timezone)::DATE - $2::INTEGER)::TIMESTAMP AT TIME ZONE tracking.timezone) AT TIME ZONE 'UTC'
+        "#,
+            tracking_id,
+            days
         )
         .fetch_one(&self.pool)
         .await?;
 
-        Ok((rec.id, rec.owner_id))
+        Ok(rec.day)
     }
 
-    pub async fn tracking_name(&self, tracking_id: i32) -> Result<String> {
-        let rec = sqlx::query!(r#"SELECT name FROM trackings WHERE id = $1"#, tracking_id)
-            .fetch_one(&self.pool)
-            .await?;
+    // Deletes the sessions of a day, along with their events, for up to
+    // `limit` of the visitors who had any that day. A visitor's sessions of the
+    // day always go together, so when `fold` is set each batch can be added to
+    // `imported_stats` on its own, the same way imported history is, and
+    // charts over that day keep their totals. Folded sessions also stay in
+    // `rollups`, so only what isn't summed from there is added.
+    pub async fn purge_sessions(
+        &self,
+        tracking_id: i32,
+        day: NaiveDate,
+        fold: bool,
+        limit: i64,
+    ) -> Result<PurgedSessions> {
+        let mut tx = self.pool.begin().await?;
+        if fold {
+            keep_rollups(&mut tx).await?;
+        }
 
-        Ok(rec.name)
-    }
+        let visitor_ids: Vec<i32> = sqlx::query!(
+            r#"
+            WITH tracking AS (SELECT timezone FROM trackings WHERE id = $1)
+            SELECT DISTINCT sessions.visitor_id
+            FROM sessions CROSS JOIN tracking
+            WHERE sessions.tracking_id = $1
+                AND sessions.start_timestamp >= ($2::DATE::TIMESTAMP AT TIME ZONE tracking.timezone) AT TIME ZONE 'UTC'
+                AND sessions.start_timestamp < (($2::DATE + 1)::TIMESTAMP AT TIME ZONE tracking.timezone) AT TIME ZONE 'UTC'
+            ORDER BY sessions.visitor_id
+            LIMIT $3
+        "#,
+            tracking_id,
+            day,
+            limit
+        )
+        .fetch_all(&mut tx)
+        .await?
+        .into_iter()
+        .map(|rec| rec.visitor_id)
+        .collect();
 
-    pub async fn delete_tracking(&self, tracking_id: i32) -> Result<()> {
-        sqlx::query!(r#"DELETE FROM trackings WHERE id = $1"#, tracking_id)
-            .execute(&self.pool)
-            .await?;
+        sqlx::query!(
+            r#"
+            WITH tracking AS (SELECT timezone FROM trackings WHERE id = $1),
+            day_sessions AS (
+                SELECT sessions.id, sessions.visitor_id, visitors.referer
+                FROM sessions JOIN visitors ON visitors.id = sessions.visitor_id CROSS JOIN tracking
+                WHERE sessions.tracking_id = $1
+                    AND sessions.visitor_id = ANY($4)
+                    AND sessions.start_timestamp >= ($2::DATE::TIMESTAMP AT TIME ZONE tracking.timezone) AT TIME ZONE 'UTC'
+                    AND sessions.start_timestamp < (($2::DATE + 1)::TIMESTAMP AT TIME ZONE tracking.timezone) AT TIME ZONE 'UTC'
+            )
+            INSERT INTO imported_stats (date, dimension, value, visitors, sessions, page_views, imported_from, tracking_id)
+            SELECT $2, dimension, value, visitors, sessions, page_views, 'retention', $1
+            FROM (
+                SELECT 'total' AS dimension, '' AS value,
+                    COUNT(DISTINCT visitor_id) AS visitors,
+                    COUNT(id) AS sessions,
+                    COUNT(id) AS page_views
+                FROM day_sessions
+                HAVING COUNT(id) > 0
+                UNION ALL
+                SELECT 'referer', referer, COUNT(DISTINCT visitor_id), COUNT(id), 0
+                FROM day_sessions
+                GROUP BY referer
+            ) aggregates
+            WHERE $3
+            ON CONFLICT (tracking_id, dimension, value, date) DO UPDATE
+            SET visitors = imported_stats.visitors + EXCLUDED.visitors,
+                sessions = imported_stats.sessions + EXCLUDED.sessions,
+                page_views = imported_stats.page_views + EXCLUDED.page_views
+        "#,
+            tracking_id,
+            day,
+            fold,
+            &visitor_ids
+        )
+        .execute(&mut tx)
+        .await?;
 
-        Ok(())
+        // Events are counted by type under the day they happened.
+        sqlx::query!(
+            r#"
+            WITH tracking AS (SELECT timezone FROM trackings WHERE id = $1)
+            INSERT INTO imported_stats (date, dimension, value, visitors, events, imported_from, tracking_id)
+            SELECT (events.created_at AT TIME ZONE 'UTC' AT TIME ZONE tracking.timezone)::DATE,
+                'event', events.type, COUNT(DISTINCT sessions.visitor_id), COUNT(events.id), 'retention', $1
+            FROM events JOIN sessions ON sessions.id = events.session_id CROSS JOIN tracking
+            WHERE $3
+                AND sessions.tracking_id = $1
+                AND sessions.visitor_id = ANY($4)
+                AND sessions.start_timestamp >= ($2::DATE::TIMESTAMP AT TIME ZONE tracking.timezone) AT TIME ZONE 'UTC'
+                AND sessions.start_timestamp < (($2::DATE + 1)::TIMESTAMP AT TIME ZONE tracking.timezone) AT TIME ZONE 'UTC'
+            GROUP BY 1, 3
+            ON CONFLICT (tracking_id, dimension, value, date) DO UPDATE
+            SET visitors = imported_stats.visitors + EXCLUDED.visitors,
+                events = imported_stats.events + EXCLUDED.events
+        "#,
+            tracking_id,
+            day,
+            fold,
+            &visitor_ids
+        )
+        .execute(&mut tx)
+        .await?;
+
+        let events = sqlx::query!(
+            r#"
+            WITH tracking AS (SELECT timezone FROM trackings WHERE id = $1)
+            DELETE FROM events
+            USING sessions, tracking
+            WHERE events.session_id = sessions.id
+                AND sessions.tracking_id = $1
+                AND sessions.visitor_id = ANY($3)
+                AND sessions.start_timestamp >= ($2::DATE::TIMESTAMP AT TIME ZONE tracking.timezone) AT TIME ZONE 'UTC'
+                AND sessions.start_timestamp < (($2::DATE + 1)::TIMESTAMP AT TIME ZONE tracking.timezone) AT TIME ZONE 'UTC'
+        "#,
+            tracking_id,
+            day,
+            &visitor_ids
+        )
+        .execute(&mut tx)
+        .await?;
+        let sessions = sqlx::query!(
+            r#"
+            WITH tracking AS (SELECT timezone FROM trackings WHERE id = $1)
+            DELETE FROM sessions
+            USING tracking
+            WHERE sessions.tracking_id = $1
+                AND sessions.visitor_id = ANY($3)
+                AND sessions.start_timestamp >= ($2::DATE::TIMESTAMP AT TIME ZONE tracking.timezone) AT TIME ZONE 'UTC'
+                AND sessions.start_timestamp < (($2::DATE + 1)::TIMESTAMP AT TIME ZONE tracking.timezone) AT TIME ZONE 'UTC'
+        "#,
+            tracking_id,
+            day,
+            &visitor_ids
+        )
+        .execute(&mut tx)
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(PurgedSessions {
+            sessions: sessions.rows_affected(),
+            events: events.rows_affected(),
+        })
     }
 
-    pub async fn rename_tracking(&self, tracking_id: i32, name: &str) -> Result<()> {
-        sqlx::query!(
-            r#"UPDATE trackings SET name = $1 WHERE id = $2"#,
-            name,
-            tracking_id
+    // Deletes up to `limit` visitors that first came before the last `days`
+    // days and have no sessions left. Their OS and browser are folded into
+    // `imported_stats` under the day they first came, their device and source
+    // stay in `rollups`.
+    pub async fn purge_visitors(
+        &self,
+        tracking_id: i32,
+        days: i32,
+        fold: bool,
+        limit: i64,
+    ) -> Result<u64> {
+        let mut tx = self.pool.begin().await?;
+        if fold {
+            keep_rollups(&mut tx).await?;
+        }
+
+        let rec = sqlx::query!(
+            r#"
+            WITH tracking AS (SELECT timezone FROM trackings WHERE id = $1),
+            expired AS (
+                SELECT visitors.id,
+                    (visitors.created_at AT TIME ZONE 'UTC' AT TIME ZONE tracking.timezone)::DATE AS date,
+                    visitors.user_agent_parsed
+                FROM visitors CROSS JOIN tracking
+                WHERE visitors.tracking_id = $1
+                    AND visitors.created_at < (((CURRENT_TIMESTAMP AT TIME ZONE tracking.timezone)::DATE - $2::INTEGER)::TIMESTAMP AT TIME ZONE tracking.timezone) AT TIME ZONE 'UTC'
+                    AND NOT EXISTS (SELECT 1 FROM sessions WHERE sessions.visitor_id = visitors.id)
+                ORDER BY visitors.id
+                LIMIT $4
+            ),
+            folded AS (
+                INSERT INTO imported_stats (date, dimension, value, visitors, sessions, page_views, imported_from, tracking_id)
+                SELECT date, dimension, value, COUNT(*), 0, 0, 'retention', $1
+                FROM expired, LATERAL (VALUES
+                    ('os', user_agent_parsed->'os'->>'family'),
+                    ('browser', user_agent_parsed->'user_agent'->>'family')
+                ) AS dimensions (dimension, value)
+                WHERE $3 AND value IS NOT NULL
+                GROUP BY date, dimension, value
+                ON CONFLICT (tracking_id, dimension, value, date) DO UPDATE
+                SET visitors = imported_stats.visitors + EXCLUDED.visitors
+            )
+            DELETE FROM visitors WHERE id IN (SELECT id FROM expired)
+        "#,
+            tracking_id,
+            days,
+            fold,
+            limit
         )
-        .execute(&self.pool)
+        .execute(&mut tx)
         .await?;
+        tx.commit().await?;
+
+        if rec.rows_affected() > 0 {
+            self.forget_visitors(tracking_id);
+        }
+
+        Ok(rec.rows_affected())
+    }
+}
+
+// What to do with hits sent with a `DNT: 1` or `Sec-GPC: 1` header.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OptOutPolicy {
+    #[default]
+    Ignore,
+    Drop,
+    Anonymous,
+}
+
+impl OptOutPolicy {
+    fn as_str(&self) -> &'static str {
+        match self {
+            OptOutPolicy::Ignore => "ignore",
+            OptOutPolicy::Drop => "drop",
+            OptOutPolicy::Anonymous => "anonymous",
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct OptedOutHits {
+    pathname: String,
+    suppressed: i64,
+    page_views: i64,
+}
+
+impl OptedOutHits {
+    pub fn suppressed(&self) -> i64 {
+        self.suppressed
+    }
+
+    pub fn page_views(&self) -> i64 {
+        self.page_views
+    }
+}
+
+// Daily aggregates imported from other analytics tools. They're kept apart
+// from sessions and only merged in when querying.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ImportedDimension {
+    Total,
+    Pathname,
+    Country,
+    Referer,
+    Browser,
+    Os,
+    Device,
+}
+
+impl ImportedDimension {
+    fn as_str(&self) -> &'static str {
+        match self {
+            ImportedDimension::Total => "total",
+            ImportedDimension::Pathname => "pathname",
+            ImportedDimension::Country => "country",
+            ImportedDimension::Referer => "referer",
+            ImportedDimension::Browser => "browser",
+            ImportedDimension::Os => "os",
+            ImportedDimension::Device => "device",
+        }
+    }
+}
+
+pub struct ImportedStat {
+    date: NaiveDate,
+    dimension: ImportedDimension,
+    value: String,
+    visitors: i64,
+    sessions: i64,
+    page_views: i64,
+}
+
+impl ImportedStat {
+    pub fn new(
+        date: NaiveDate,
+        dimension: ImportedDimension,
+        value: String,
+        visitors: i64,
+        sessions: i64,
+        page_views: i64,
+    ) -> Self {
+        Self {
+            date,
+            dimension,
+            value,
+            visitors,
+            sessions,
+            page_views,
+        }
+    }
+}
+
+impl DB {
+    // Importing the same day twice replaces it, so a failed import can simply
+    // be run again.
+    pub async fn import_stats(
+        &self,
+        tracking_id: i32,
+        imported_from: &str,
+        stats: &[ImportedStat],
+    ) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+
+        for chunk in stats.chunks(1000) {
+            let dates: Vec<NaiveDate> = chunk.iter().map(|stat| stat.date).collect();
+            let dimensions: Vec<&str> = chunk.iter().map(|stat| stat.dimension.as_str()).collect();
+            let values: Vec<&str> = chunk.iter().map(|stat| stat.value.as_str()).collect();
+            let visitors: Vec<i64> = chunk.iter().map(|stat| stat.visitors).collect();
+            let sessions: Vec<i64> = chunk.iter().map(|stat| stat.sessions).collect();
+            let page_views: Vec<i64> = chunk.iter().map(|stat| stat.page_views).collect();
+
+            sqlx::query!(
+                r#"
+                INSERT INTO imported_stats (date, dimension, value, visitors, sessions, page_views, imported_from, tracking_id)
+                SELECT *, $7, $8 FROM UNNEST($1::DATE[], $2::VARCHAR[], $3::TEXT[], $4::BIGINT[], $5::BIGINT[], $6::BIGINT[])
+                ON CONFLICT (tracking_id, dimension, value, date) DO UPDATE
+                SET visitors = EXCLUDED.visitors,
+                    sessions = EXCLUDED.sessions,
+                    page_views = EXCLUDED.page_views,
+                    imported_from = EXCLUDED.imported_from,
+                    imported_at = CURRENT_TIMESTAMP
+                "#,
+                &dates,
+                &dimensions as &[&str],
+                &values as &[&str],
+                &visitors,
+                &sessions,
+                &page_views,
+                imported_from,
+                tracking_id
+            )
+            .execute(&mut tx)
+            .await?;
+        }
+
+        tx.commit().await?;
 
         Ok(())
     }
@@ -771,6 +4255,36 @@ mod native_date_format {
     }
 }
 
+mod optional_native_date_format {
+    use serde::{self, Serializer};
+    use sqlx::types::chrono::NaiveDateTime;
+
+    pub fn serialize<S>(date: &Option<NaiveDateTime>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match date {
+            Some(date) => serializer.serialize_some(&date.timestamp_millis()),
+            None => serializer.serialize_none(),
+        }
+    }
+}
+
+mod optional_date_format {
+    use serde::{self, Deserialize, Deserializer};
+    use sqlx::types::chrono::NaiveDate;
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Option<NaiveDate>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Option::<String>::deserialize(deserializer)?
+            .map(|date| NaiveDate::parse_from_str(&date, "%Y-%m-%d"))
+            .transpose()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
 mod big_decimal_to_u8 {
     use num_traits::cast::ToPrimitive;
     use serde::{self, Serializer};
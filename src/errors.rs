@@ -19,6 +19,54 @@ impl reject::Reject for InvalidBase64 {}
 pub struct InvalidToken;
 impl reject::Reject for InvalidToken {}
 
+#[derive(Debug)]
+pub struct BatchTooLarge;
+impl reject::Reject for BatchTooLarge {}
+
+#[derive(Debug)]
+pub struct InvalidEventProperties;
+impl reject::Reject for InvalidEventProperties {}
+
+#[derive(Debug)]
+pub struct MissingVisitorId;
+impl reject::Reject for MissingVisitorId {}
+
+#[derive(Debug)]
+pub struct UnknownVisitor;
+impl reject::Reject for UnknownVisitor {}
+
+#[derive(Debug)]
+pub struct UnknownApiKey;
+impl reject::Reject for UnknownApiKey {}
+
+#[derive(Debug)]
+pub struct InvalidIdentity;
+impl reject::Reject for InvalidIdentity {}
+
+#[derive(Debug)]
+pub struct InvalidDataSubject;
+impl reject::Reject for InvalidDataSubject {}
+
+#[derive(Debug)]
+pub struct InvalidTimezone;
+impl reject::Reject for InvalidTimezone {}
+
+#[derive(Debug)]
+pub struct InvalidComparisonRange;
+impl reject::Reject for InvalidComparisonRange {}
+
+#[derive(Debug)]
+pub struct InvalidRetention;
+impl reject::Reject for InvalidRetention {}
+
+#[derive(Debug)]
+pub struct IngestQueueFull;
+impl reject::Reject for IngestQueueFull {}
+
+#[derive(Debug)]
+pub struct TooManyBuckets;
+impl reject::Reject for TooManyBuckets {}
+
 #[derive(Serialize)]
 struct ErrorMessage {
     code: u16,
@@ -44,6 +92,42 @@ pub async fn handle_rejection(err: Rejection) -> Result<impl Reply, Infallible>
     } else if let Some(InvalidToken) = err.find() {
         code = StatusCode::BAD_REQUEST;
         message = "INVALID_TOKEN";
+    } else if let Some(BatchTooLarge) = err.find() {
+        code = StatusCode::PAYLOAD_TOO_LARGE;
+        message = "BATCH_TOO_LARGE";
+    } else if let Some(InvalidEventProperties) = err.find() {
+        code = StatusCode::BAD_REQUEST;
+        message = "INVALID_EVENT_PROPERTIES";
+    } else if let Some(MissingVisitorId) = err.find() {
+        code = StatusCode::BAD_REQUEST;
+        message = "MISSING_VISITOR_ID";
+    } else if let Some(UnknownVisitor) = err.find() {
+        code = StatusCode::NOT_FOUND;
+        message = "UNKNOWN_VISITOR";
+    } else if let Some(UnknownApiKey) = err.find() {
+        code = StatusCode::NOT_FOUND;
+        message = "UNKNOWN_API_KEY";
+    } else if let Some(InvalidIdentity) = err.find() {
+        code = StatusCode::BAD_REQUEST;
+        message = "INVALID_IDENTITY";
+    } else if let Some(InvalidDataSubject) = err.find() {
+        code = StatusCode::BAD_REQUEST;
+        message = "INVALID_DATA_SUBJECT";
+    } else if let Some(InvalidTimezone) = err.find() {
+        code = StatusCode::BAD_REQUEST;
+        message = "INVALID_TIMEZONE";
+    } else if let Some(InvalidComparisonRange) = err.find() {
+        code = StatusCode::BAD_REQUEST;
+        message = "INVALID_COMPARISON_RANGE";
+    } else if let Some(InvalidRetention) = err.find() {
+        code = StatusCode::BAD_REQUEST;
+        message = "INVALID_RETENTION";
+    } else if let Some(TooManyBuckets) = err.find() {
+        code = StatusCode::BAD_REQUEST;
+        message = "TOO_MANY_BUCKETS";
+    } else if let Some(IngestQueueFull) = err.find() {
+        code = StatusCode::SERVICE_UNAVAILABLE;
+        message = "INGEST_QUEUE_FULL";
     } else if err
         .find::<warp::filters::body::BodyDeserializeError>()
         .is_some()
@@ -52,6 +136,9 @@ pub async fn handle_rejection(err: Rejection) -> Result<impl Reply, Infallible>
         // We can use the cause to analyze the error and customize the error message
         code = StatusCode::BAD_REQUEST;
         message = "Failed to deserialize body";
+    } else if err.find::<warp::reject::InvalidQuery>().is_some() {
+        code = StatusCode::BAD_REQUEST;
+        message = "INVALID_QUERY";
     } else if err.find::<warp::reject::MethodNotAllowed>().is_some() {
         // We can handle a specific error, here METHOD_NOT_ALLOWED,
         // and render it however we want
@@ -0,0 +1,55 @@
+use std::net::{IpAddr, SocketAddr};
+
+use domain::{
+    async_trait::async_trait, AnonymizedGeoIpReader, GeoIpReader, GeoIpReaderError, GeoLocation,
+};
+use maxminddb::geoip2;
+
+// The GeoLite2 database, behind the same wrapper as the workspace binary uses
+// to only process as much of a visitor's IP and location as we're allowed to.
+pub type GeoIp = AnonymizedGeoIpReader<MaxmindCityReader>;
+
+pub struct MaxmindCityReader {
+    reader: maxminddb::Reader<Vec<u8>>,
+}
+
+impl MaxmindCityReader {
+    pub fn new(reader: maxminddb::Reader<Vec<u8>>) -> Self {
+        Self { reader }
+    }
+}
+
+// The whole GeoLite2 City record, as stored with each session.
+pub struct CityRecord(serde_json::Value);
+
+impl GeoLocation for CityRecord {
+    fn without_city(mut self) -> Self {
+        if let Some(record) = self.0.as_object_mut() {
+            for key in ["city", "location", "postal", "subdivisions"] {
+                record.remove(key);
+            }
+        }
+        self
+    }
+}
+
+#[async_trait]
+impl GeoIpReader for MaxmindCityReader {
+    type Location = CityRecord;
+
+    async fn parse(&self, ip_addr: IpAddr) -> Result<CityRecord, GeoIpReaderError> {
+        // Private and unknown addresses aren't in the database, which isn't
+        // worth logging.
+        let city = self
+            .reader
+            .lookup::<geoip2::City>(ip_addr)
+            .map_err(|_| GeoIpReaderError::Other)?;
+
+        Ok(CityRecord(serde_json::to_value(city).unwrap()))
+    }
+}
+
+pub async fn locate(geoip: &GeoIp, remote_addr: Option<SocketAddr>) -> Option<serde_json::Value> {
+    let record = geoip.parse(remote_addr?.ip()).await.ok()?;
+    Some(record.0)
+}
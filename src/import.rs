@@ -0,0 +1,439 @@
+use std::{collections::HashMap, fs::File, io::Read, path::Path, str::FromStr};
+
+use color_eyre::{
+    eyre::{eyre, Context},
+    Report, Result,
+};
+use sqlx::{types::chrono::NaiveDate, PgPool};
+
+use crate::db::{ImportedDimension, ImportedStat, DB};
+
+#[derive(Debug, Clone, Copy)]
+pub enum ImportFormat {
+    Plausible,
+    Ga4,
+}
+
+impl FromStr for ImportFormat {
+    type Err = Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "plausible" => Ok(ImportFormat::Plausible),
+            "ga4" => Ok(ImportFormat::Ga4),
+            _ => Err(eyre!(
+                "Unknown import format: {} (expected plausible or ga4)",
+                s
+            )),
+        }
+    }
+}
+
+impl ImportFormat {
+    fn name(self) -> &'static str {
+        match self {
+            ImportFormat::Plausible => "plausible",
+            ImportFormat::Ga4 => "ga4",
+        }
+    }
+}
+
+// Where a dimension and its metrics live in an exported CSV. Each field lists
+// the headers it may appear under, the first one present wins.
+struct Layout {
+    dimension: ImportedDimension,
+    value: &'static [&'static str],
+    visitors: &'static [&'static str],
+    sessions: &'static [&'static str],
+    page_views: &'static [&'static str],
+}
+
+// Plausible names every file in its export after the table it came from,
+// e.g. `imported_pages_20220101_20221231.csv`.
+const PLAUSIBLE_TABLES: &[(&str, Layout)] = &[
+    (
+        "imported_visitors",
+        plausible_layout(ImportedDimension::Total, &[]),
+    ),
+    (
+        "imported_pages",
+        plausible_layout(ImportedDimension::Pathname, &["page"]),
+    ),
+    (
+        "imported_locations",
+        plausible_layout(ImportedDimension::Country, &["country"]),
+    ),
+    (
+        "imported_sources",
+        plausible_layout(ImportedDimension::Referer, &["referrer"]),
+    ),
+    (
+        "imported_browsers",
+        plausible_layout(ImportedDimension::Browser, &["browser"]),
+    ),
+    (
+        "imported_operating_systems",
+        plausible_layout(ImportedDimension::Os, &["operating_system"]),
+    ),
+    (
+        "imported_devices",
+        plausible_layout(ImportedDimension::Device, &["device"]),
+    ),
+];
+
+const fn plausible_layout(dimension: ImportedDimension, value: &'static [&'static str]) -> Layout {
+    Layout {
+        dimension,
+        value,
+        visitors: &["visitors"],
+        sessions: &["visits"],
+        page_views: &["pageviews"],
+    }
+}
+
+// GA4 reports are exported one dimension at a time, so the dimension is
+// picked from whichever of these headers the report has.
+const GA4_DIMENSIONS: &[(&str, ImportedDimension)] = &[
+    ("Page path and screen class", ImportedDimension::Pathname),
+    ("Page path", ImportedDimension::Pathname),
+    ("Country ID", ImportedDimension::Country),
+    ("Page referrer", ImportedDimension::Referer),
+    ("Browser", ImportedDimension::Browser),
+    ("Operating system", ImportedDimension::Os),
+    ("Device category", ImportedDimension::Device),
+];
+
+const GA4_VISITORS: &[&str] = &["Total users", "Active users"];
+const GA4_SESSIONS: &[&str] = &["Sessions"];
+const GA4_PAGE_VIEWS: &[&str] = &["Views", "Screen page views"];
+
+type Totals = HashMap<(NaiveDate, ImportedDimension, String), (i64, i64, i64)>;
+
+// Imports Plausible CSV zips or GA4 daily CSV reports into a tracking.
+// Returns the number of daily aggregates written.
+pub async fn run(
+    pool: PgPool,
+    format: ImportFormat,
+    tracking_id: &str,
+    paths: &[String],
+) -> Result<usize> {
+    sqlx::migrate!().run(&pool).await?;
+
+    let db = DB::new(pool);
+    let id = db
+        .id_from_tracking_id(tracking_id)
+        .await
+        .wrap_err_with(|| format!("couldn't find tracking: {}", tracking_id))?;
+
+    let mut totals = Totals::new();
+    for path in paths {
+        for (name, contents) in read_files(path)? {
+            match format {
+                ImportFormat::Plausible => read_plausible(&name, &contents, &mut totals)?,
+                ImportFormat::Ga4 => read_ga4(&name, &contents, &mut totals)?,
+            }
+        }
+    }
+
+    let stats: Vec<ImportedStat> = totals
+        .into_iter()
+        .map(
+            |((date, dimension, value), (visitors, sessions, page_views))| {
+                ImportedStat::new(date, dimension, value, visitors, sessions, page_views)
+            },
+        )
+        .collect();
+
+    db.import_stats(id, format.name(), &stats)
+        .await
+        .wrap_err("couldn't save imported stats")?;
+
+    Ok(stats.len())
+}
+
+// A zip is read as every CSV inside it, anything else as a single CSV.
+fn read_files(path: &str) -> Result<Vec<(String, String)>> {
+    let is_zip = Path::new(path)
+        .extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("zip"));
+
+    if !is_zip {
+        let contents = std::fs::read_to_string(path)
+            .wrap_err_with(|| format!("couldn't read import file: {}", path))?;
+        return Ok(vec![(file_name(path), contents)]);
+    }
+
+    let file = File::open(path).wrap_err_with(|| format!("couldn't open import file: {}", path))?;
+    let mut archive = zip::ZipArchive::new(file)
+        .wrap_err_with(|| format!("couldn't read zip archive: {}", path))?;
+
+    let mut files = Vec::new();
+    for index in 0..archive.len() {
+        let mut entry = archive.by_index(index)?;
+        if !entry.is_file() || !entry.name().ends_with(".csv") {
+            continue;
+        }
+
+        let name = file_name(entry.name());
+        let mut contents = String::new();
+        entry
+            .read_to_string(&mut contents)
+            .wrap_err_with(|| format!("couldn't read {} from {}", name, path))?;
+        files.push((name, contents));
+    }
+
+    Ok(files)
+}
+
+fn file_name(path: &str) -> String {
+    Path::new(path)
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| path.to_owned())
+}
+
+fn read_plausible(name: &str, contents: &str, totals: &mut Totals) -> Result<()> {
+    let layout = PLAUSIBLE_TABLES
+        .iter()
+        .find(|(table, _)| name.starts_with(&format!("{}_", table)))
+        .map(|(_, layout)| layout);
+
+    match layout {
+        Some(layout) => read_csv(name, contents, "date", "%Y-%m-%d", layout, totals),
+        None => {
+            tracing::info!("Skipping {}, it has nothing trantor can show", name);
+            Ok(())
+        }
+    }
+}
+
+fn read_ga4(name: &str, contents: &str, totals: &mut Totals) -> Result<()> {
+    let headers = csv_reader(contents).headers()?.clone();
+    let found = GA4_DIMENSIONS
+        .iter()
+        .find(|(header, _)| headers.iter().any(|h| h == *header));
+    let (dimension, value): (_, &'static [&'static str]) = match found {
+        Some((header, dimension)) => (*dimension, std::slice::from_ref(header)),
+        None => (ImportedDimension::Total, &[]),
+    };
+
+    let layout = Layout {
+        dimension,
+        value,
+        visitors: GA4_VISITORS,
+        sessions: GA4_SESSIONS,
+        page_views: GA4_PAGE_VIEWS,
+    };
+
+    read_csv(name, contents, "Date", "%Y%m%d", &layout, totals)
+}
+
+// GA4 puts a few lines of `#` comments above the header row.
+fn csv_reader(contents: &str) -> csv::Reader<&[u8]> {
+    csv::ReaderBuilder::new()
+        .comment(Some(b'#'))
+        .flexible(true)
+        .from_reader(contents.as_bytes())
+}
+
+fn read_csv(
+    name: &str,
+    contents: &str,
+    date_header: &str,
+    date_format: &str,
+    layout: &Layout,
+    totals: &mut Totals,
+) -> Result<()> {
+    let mut reader = csv_reader(contents);
+    let headers = reader.headers()?.clone();
+    let column = |candidates: &[&str]| {
+        candidates
+            .iter()
+            .find_map(|candidate| headers.iter().position(|header| header == *candidate))
+    };
+
+    let date_column =
+        column(&[date_header]).ok_or_else(|| eyre!("{} has no {} column", name, date_header))?;
+    let value_column = match layout.value {
+        [] => None,
+        candidates => Some(column(candidates).ok_or_else(|| {
+            eyre!(
+                "{} has none of the columns: {}",
+                name,
+                candidates.join(", ")
+            )
+        })?),
+    };
+    let visitors_column = column(layout.visitors);
+    let sessions_column = column(layout.sessions);
+    let page_views_column = column(layout.page_views);
+
+    for (index, record) in reader.records().enumerate() {
+        let record = record.wrap_err_with(|| format!("couldn't read {}", name))?;
+        let line = index + 2;
+
+        // Reports end with a totals row that has no date.
+        let date = record.get(date_column).unwrap_or_default().trim();
+        if date.is_empty() {
+            continue;
+        }
+        let date = NaiveDate::parse_from_str(date, date_format)
+            .wrap_err_with(|| format!("invalid date on line {} of {}", line, name))?;
+
+        let value = match value_column {
+            Some(column) => record.get(column).unwrap_or_default().trim(),
+            None => "",
+        };
+        // Rows without a value are direct visits or visitors who couldn't be
+        // located, which trantor doesn't break down either.
+        if value_column.is_some() && (value.is_empty() || value == "(not set)") {
+            continue;
+        }
+
+        let metric = |column: Option<usize>| -> Result<i64> {
+            let Some(column) = column else {
+                return Ok(0);
+            };
+            let metric = record.get(column).unwrap_or_default().trim();
+            if metric.is_empty() {
+                return Ok(0);
+            }
+            metric
+                .parse::<i64>()
+                .or_else(|_| metric.parse::<f64>().map(|metric| metric.round() as i64))
+                .wrap_err_with(|| format!("invalid number on line {} of {}", line, name))
+        };
+
+        let total = totals
+            .entry((date, layout.dimension, value.to_owned()))
+            .or_default();
+        total.0 += metric(visitors_column)?;
+        total.1 += metric(sessions_column)?;
+        total.2 += metric(page_views_column)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    macro_rules! fixture {
+        ($path:literal) => {
+            (
+                file_name($path),
+                include_str!(concat!("../tests/fixtures/import/", $path)),
+            )
+        };
+    }
+
+    fn total(
+        totals: &Totals,
+        date: &str,
+        dimension: ImportedDimension,
+        value: &str,
+    ) -> Option<(i64, i64, i64)> {
+        let date = NaiveDate::parse_from_str(date, "%Y-%m-%d").unwrap();
+        totals.get(&(date, dimension, value.to_owned())).copied()
+    }
+
+    #[test]
+    fn plausible_tables_are_picked_by_file_name() {
+        let files = [
+            fixture!("plausible/imported_visitors_20230101_20230102.csv"),
+            fixture!("plausible/imported_pages_20230101_20230102.csv"),
+            fixture!("plausible/imported_locations_20230101_20230102.csv"),
+            fixture!("plausible/imported_entry_pages_20230101_20230102.csv"),
+        ];
+
+        let mut totals = Totals::new();
+        for (name, contents) in files {
+            read_plausible(&name, contents, &mut totals).unwrap();
+        }
+
+        assert_eq!(totals.len(), 6);
+        assert_eq!(
+            total(&totals, "2023-01-01", ImportedDimension::Total, ""),
+            Some((12, 15, 30))
+        );
+        assert_eq!(
+            total(&totals, "2023-01-02", ImportedDimension::Total, ""),
+            Some((8, 10, 20))
+        );
+        assert_eq!(
+            total(&totals, "2023-01-01", ImportedDimension::Pathname, "/about"),
+            Some((3, 3, 4))
+        );
+        assert_eq!(
+            total(&totals, "2023-01-02", ImportedDimension::Pathname, "/"),
+            Some((5, 6, 8))
+        );
+        // Regions and cities of a country add up, unlocated visitors are left out.
+        assert_eq!(
+            total(&totals, "2023-01-01", ImportedDimension::Country, "DE"),
+            Some((6, 7, 10))
+        );
+        assert_eq!(
+            total(&totals, "2023-01-01", ImportedDimension::Country, ""),
+            None
+        );
+    }
+
+    #[test]
+    fn ga4_reports_are_broken_down_by_their_dimension() {
+        let files = [fixture!("ga4/pages.csv"), fixture!("ga4/overview.csv")];
+
+        let mut totals = Totals::new();
+        for (name, contents) in files {
+            read_ga4(&name, contents, &mut totals).unwrap();
+        }
+
+        assert_eq!(totals.len(), 4);
+        assert_eq!(
+            total(&totals, "2023-01-01", ImportedDimension::Pathname, "/"),
+            Some((9, 10, 14))
+        );
+        assert_eq!(
+            total(
+                &totals,
+                "2023-01-02",
+                ImportedDimension::Pathname,
+                "/pricing"
+            ),
+            Some((4, 4, 5))
+        );
+        assert_eq!(
+            total(
+                &totals,
+                "2023-01-01",
+                ImportedDimension::Pathname,
+                "(not set)"
+            ),
+            None
+        );
+        assert_eq!(
+            total(&totals, "2023-01-02", ImportedDimension::Total, ""),
+            Some((8, 10, 20))
+        );
+    }
+
+    #[test]
+    fn invalid_rows_name_their_line() {
+        let contents = "Date,Browser,Active users,Sessions,Views\n20230101,Firefox,1,1,1\n2023-01-02,Chrome,2,2,2\n";
+
+        let e = read_ga4("browsers.csv", contents, &mut Totals::new()).unwrap_err();
+        assert_eq!(e.to_string(), "invalid date on line 3 of browsers.csv");
+
+        let e = read_plausible(
+            "imported_pages_x.csv",
+            "date,visitors\n",
+            &mut Totals::new(),
+        )
+        .unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "imported_pages_x.csv has none of the columns: page"
+        );
+    }
+}
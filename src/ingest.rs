@@ -0,0 +1,220 @@
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::{
+    sync::mpsc::{self, error::TrySendError},
+    task::JoinHandle,
+    time::Instant,
+};
+use warp::{reject, Filter};
+
+use crate::{
+    db::{Write, DB},
+    errors::IngestQueueFull,
+};
+
+// How many requests can be waiting to be written before new ones are turned
+// away with a 503.
+const QUEUE_CAPACITY: usize = 10_000;
+const MAX_BATCH_SIZE: usize = 1000;
+const FLUSH_INTERVAL: Duration = Duration::from_millis(250);
+
+// Visitors and sessions that are queued but not written yet, so that the
+// requests that follow them can already refer to them.
+#[derive(Default)]
+struct Pending {
+    visitors: HashMap<(i32, String), i32>,
+    sessions: HashMap<String, i32>,
+    latest_sessions: HashMap<i32, String>,
+}
+
+impl Pending {
+    fn add(&mut self, writes: &[Write]) {
+        for write in writes {
+            match write {
+                Write::Visitor { id, data } => {
+                    self.visitors
+                        .insert((data.tracking_id(), data.visitor_id()), *id);
+                }
+                Write::Session(data) => {
+                    self.sessions
+                        .insert(data.session_id().to_owned(), data.tracking_id());
+                    self.latest_sessions
+                        .insert(data.visitor_id(), data.session_id().to_owned());
+                }
+                Write::End { .. } | Write::Event { .. } | Write::Identify { .. } => {}
+            }
+        }
+    }
+
+    fn remove<'a>(&mut self, writes: impl Iterator<Item = &'a Write>) {
+        for write in writes {
+            match write {
+                Write::Visitor { data, .. } => {
+                    self.visitors
+                        .remove(&(data.tracking_id(), data.visitor_id()));
+                }
+                Write::Session(data) => {
+                    self.sessions.remove(data.session_id());
+                    if self
+                        .latest_sessions
+                        .get(&data.visitor_id())
+                        .map(String::as_str)
+                        == Some(data.session_id())
+                    {
+                        self.latest_sessions.remove(&data.visitor_id());
+                    }
+                }
+                Write::End { .. } | Write::Event { .. } | Write::Identify { .. } => {}
+            }
+        }
+    }
+}
+
+// Session hits are answered as soon as they are queued. A single task writes
+// them in batches, every `FLUSH_INTERVAL` or once `MAX_BATCH_SIZE` rows are
+// waiting, and flushes whatever is left once every sender is dropped. Delivery
+// is at most once: rows that can't be written are counted and dropped.
+#[derive(Clone)]
+pub struct Ingest {
+    sender: mpsc::Sender<Vec<Write>>,
+    pending: Arc<Mutex<Pending>>,
+}
+
+impl Ingest {
+    pub fn new(db: DB) -> (Self, JoinHandle<()>) {
+        let (sender, receiver) = mpsc::channel(QUEUE_CAPACITY);
+        let pending = Arc::new(Mutex::new(Pending::default()));
+
+        let writer = tokio::spawn(run_writer(db, receiver, pending.clone()));
+
+        (Self { sender, pending }, writer)
+    }
+
+    // The writes of a request are queued together, so that they are written
+    // in the same transaction.
+    pub fn write(&self, writes: Vec<Write>) -> Result<(), reject::Rejection> {
+        // Registered before sending, or the writer could be done with them
+        // before they are.
+        let mut pending = self.pending.lock().unwrap();
+        pending.add(&writes);
+
+        match self.sender.try_send(writes) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(writes)) | Err(TrySendError::Closed(writes)) => {
+                pending.remove(writes.iter());
+                tracing::warn!("Ingestion queue is full, rejecting {} rows", writes.len());
+                Err(reject::custom(IngestQueueFull))
+            }
+        }
+    }
+
+    pub fn pending_visitor_id(&self, tracking_id: i32, visitor_id: &str) -> Option<i32> {
+        let pending = self.pending.lock().unwrap();
+
+        pending
+            .visitors
+            .get(&(tracking_id, visitor_id.to_owned()))
+            .copied()
+    }
+
+    pub fn is_pending_session(&self, tracking_id: i32, session_id: &str) -> bool {
+        let pending = self.pending.lock().unwrap();
+
+        pending.sessions.get(session_id) == Some(&tracking_id)
+    }
+
+    pub fn pending_latest_session_id(&self, visitor_id: i32) -> Option<String> {
+        let pending = self.pending.lock().unwrap();
+
+        pending.latest_sessions.get(&visitor_id).cloned()
+    }
+}
+
+async fn run_writer(
+    db: DB,
+    mut receiver: mpsc::Receiver<Vec<Write>>,
+    pending: Arc<Mutex<Pending>>,
+) {
+    let mut lost = Lost::default();
+
+    while let Some(first) = receiver.recv().await {
+        let deadline = Instant::now() + FLUSH_INTERVAL;
+        let mut size = first.len();
+        let mut batch = vec![first];
+
+        while size < MAX_BATCH_SIZE {
+            match tokio::time::timeout_at(deadline, receiver.recv()).await {
+                Ok(Some(writes)) => {
+                    size += writes.len();
+                    batch.push(writes);
+                }
+                Ok(None) | Err(_) => break,
+            }
+        }
+
+        flush(&db, &batch, &mut lost).await;
+        pending.lock().unwrap().remove(batch.iter().flatten());
+    }
+
+    tracing::info!(
+        "Ingestion queue flushed, {} rows dropped and {} events of unknown sessions skipped",
+        lost.dropped,
+        lost.skipped
+    );
+}
+
+// Running totals of the rows the writer could not write.
+#[derive(Default)]
+struct Lost {
+    dropped: u64,
+    skipped: u64,
+}
+
+impl Lost {
+    fn skip(&mut self, skipped: u64) {
+        if skipped > 0 {
+            self.skipped += skipped;
+            tracing::warn!(
+                "Skipped {} events of unknown sessions, {} so far",
+                skipped,
+                self.skipped
+            );
+        }
+    }
+}
+
+// A batch that fails is written again one request at a time, so that a
+// single bad request only loses its own rows.
+async fn flush(db: &DB, batch: &[Vec<Write>], lost: &mut Lost) {
+    let writes = batch.iter().flatten().collect::<Vec<_>>();
+    let e = match db.write_many(&writes).await {
+        Ok(skipped) => return lost.skip(skipped),
+        Err(e) => e,
+    };
+    tracing::error!("Error writing {} ingested rows: {}", writes.len(), e);
+
+    for writes in batch {
+        let writes = writes.iter().collect::<Vec<_>>();
+        match db.write_many(&writes).await {
+            Ok(skipped) => lost.skip(skipped),
+            Err(e) => {
+                lost.dropped += writes.len() as u64;
+                tracing::error!(
+                    "Dropping {} ingested rows, {} so far: {}",
+                    writes.len(),
+                    lost.dropped,
+                    e
+                );
+            }
+        }
+    }
+}
+
+pub fn with_ingest(ingest: Ingest) -> impl Filter<Extract = (Ingest,), Error = Infallible> + Clone {
+    warp::any().map(move || ingest.clone())
+}
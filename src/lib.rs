@@ -1,7 +1,13 @@
 pub mod admin;
+pub mod cache;
 pub mod db;
 pub mod errors;
+pub mod geoip;
+pub mod import;
+pub mod ingest;
 pub mod middleware;
+pub mod realtime;
+pub mod retention;
 pub mod session;
 pub mod utils;
 
@@ -10,31 +16,57 @@ pub use sqlx;
 use std::{path::Path, sync::Arc};
 
 use db::DB;
+use geoip::GeoIp;
 use include_dir::{include_dir, Dir, File};
+use ingest::Ingest;
+use realtime::Realtime;
 use sqlx::{
     types::chrono::{self, Utc},
     PgPool,
 };
+use tokio::{sync::watch, task::JoinHandle};
 use uaparser::UserAgentParser;
 use warp::{filters::compression, http::Response, path::Tail, Filter};
 
 const REGEXES: &[u8; 205550] = include_bytes!("../data/ua-regexes.yml");
 const LAUNCH_CONTROL_JS: &str = include_str!("../data/launch-control.js");
 
+// Along with the routes comes the ingestion writer task, which finishes once
+// the routes are dropped and everything queued is written. Sending `true` on
+// `shutdown` ends the live streams, which would otherwise keep the routes
+// alive.
 pub async fn server(
     pool: PgPool,
-    maxmind_reader: Arc<maxminddb::Reader<Vec<u8>>>,
+    maxmind_reader: Arc<GeoIp>,
+    shutdown: watch::Receiver<bool>,
 ) -> Result<
-    impl Filter<Extract = (impl warp::Reply + Send,), Error = warp::Rejection> + Clone,
+    (
+        impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone,
+        JoinHandle<()>,
+    ),
     sqlx::Error,
 > {
     sqlx::migrate!().run(&pool).await?;
 
     let db = DB::new(pool);
     let ua_parser = UserAgentParser::from_bytes(REGEXES).expect("Failed to make user agent parser");
+    let ua_parser = Arc::new(ua_parser);
 
-    let admin_routes = admin::make_admin_routes(db.clone());
-    let session_routes = session::make_session_routes(db, ua_parser, maxmind_reader);
+    let realtime = Realtime::new(shutdown);
+
+    retention::spawn_purge_task(db.clone());
+    let (ingest, ingest_writer) = Ingest::new(db.clone());
+
+    let admin_routes = admin::make_admin_routes(db.clone(), realtime.clone());
+    let pixel_route = session::make_pixel_route(
+        db.clone(),
+        ingest.clone(),
+        realtime.clone(),
+        ua_parser.clone(),
+        maxmind_reader.clone(),
+    );
+    let session_routes =
+        session::make_session_routes(db, ingest, realtime, ua_parser, maxmind_reader);
 
     let cors = warp::cors()
         .allow_any_origin()
@@ -70,13 +102,14 @@ pub async fn server(
 
     let routes = admin_routes
         .or(session_routes)
+        .or(pixel_route)
         .or(launch_control_script)
         .or(fronted_routes)
         .or(index_page)
         .recover(errors::handle_rejection)
         .with(cors);
 
-    Ok(routes)
+    Ok((routes, ingest_writer))
 }
 
 static FRONTEND_BUILD_DIR: Dir = include_dir!("client/build");
@@ -4,10 +4,11 @@ use color_eyre::{
     eyre::{eyre, Context},
     Result,
 };
+use domain::AnonymizedGeoIpReader;
 use serde::Deserialize;
 use sqlx::PgPool;
-use tokio::fs;
-use trantor::server;
+use tokio::{fs, sync::watch};
+use trantor::{geoip::MaxmindCityReader, import, server};
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -27,6 +28,27 @@ async fn main() -> Result<()> {
         .await
         .wrap_err_with(|| format!("couldn't connect to database with url: {}", config.database))?;
 
+    if args.get(2).map(String::as_str) == Some("import") {
+        let format = args
+            .get(3)
+            .ok_or_else(|| eyre!("Missing import format"))?
+            .parse()?;
+        let tracking_id = args.get(4).ok_or_else(|| eyre!("Missing tracking id"))?;
+        let paths = args
+            .get(5..)
+            .filter(|paths| !paths.is_empty())
+            .ok_or_else(|| eyre!("Missing files to import"))?;
+
+        let imported = import::run(pool, format, tracking_id, paths).await?;
+        tracing::info!(
+            "Imported {} daily aggregates into {}",
+            imported,
+            tracking_id
+        );
+
+        return Ok(());
+    }
+
     let maxmind_reader =
         maxminddb::Reader::open_readfile(&config.geolite2_city).wrap_err_with(|| {
             format!(
@@ -34,10 +56,19 @@ async fn main() -> Result<()> {
                 config.geolite2_city
             )
         })?;
-    let maxmind_reader = Arc::new(maxmind_reader);
+    let maxmind_reader = Arc::new(AnonymizedGeoIpReader::new(
+        MaxmindCityReader::new(maxmind_reader),
+        config.anonymize_ip,
+        config.skip_city,
+    ));
 
-    let routes = server(pool, maxmind_reader).await?;
+    let (shutdown, shutdown_receiver) = watch::channel(false);
+    let (routes, ingest_writer) = server(pool, maxmind_reader, shutdown_receiver).await?;
     let addr: SocketAddr = config.address.parse()?;
+    let signal = async move {
+        shutdown_signal().await;
+        let _ = shutdown.send(true);
+    };
 
     if let Some(https) = config.https {
         let key = fs::read(&https.key_path).await.wrap_err_with(|| {
@@ -53,24 +84,57 @@ async fn main() -> Result<()> {
             )
         })?;
 
-        warp::serve(routes)
+        let (_, server) = warp::serve(routes)
             .tls()
             .key(key)
             .cert(cert)
-            .run(addr)
-            .await;
+            .bind_with_graceful_shutdown(addr, signal);
+        server.await;
     } else {
-        warp::serve(routes).run(addr).await;
+        let (_, server) = warp::serve(routes).bind_with_graceful_shutdown(addr, signal);
+        server.await;
     }
 
+    // The server is gone, so is every sender of the ingestion queue.
+    ingest_writer.await?;
+
     Ok(())
 }
 
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Failed to listen for ctrl-c");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Failed to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+
+    tracing::info!("Shutting down, flushing the ingestion queue");
+}
+
 #[derive(Debug, Deserialize)]
 struct Config {
     address: String,
     database: String,
     geolite2_city: String,
+    #[serde(default)]
+    anonymize_ip: bool,
+    #[serde(default)]
+    skip_city: bool,
     https: Option<Https>,
 }
 
@@ -0,0 +1,161 @@
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+use tokio::sync::{broadcast, watch};
+use warp::Filter;
+
+use crate::db::NewSessionData;
+
+const UPDATES_CAPACITY: usize = 1024;
+pub const MAX_ACTIVE_WINDOW: Duration = Duration::from_secs(60 * 60);
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Serialize)]
+pub struct LiveSession {
+    pathname: String,
+    country: Option<String>,
+    source: Option<String>,
+}
+
+#[derive(Clone)]
+pub enum LiveUpdate {
+    SessionStarted {
+        tracking_id: i32,
+        session: LiveSession,
+    },
+    SessionEnded {
+        tracking_id: i32,
+    },
+}
+
+struct ActiveSessions {
+    sessions: HashMap<String, (i32, Instant)>,
+    last_pruned: Instant,
+}
+
+impl ActiveSessions {
+    // Sessions that are never ended would otherwise stay around forever.
+    fn prune(&mut self, now: Instant) {
+        if now.duration_since(self.last_pruned) < PRUNE_INTERVAL {
+            return;
+        }
+
+        self.sessions
+            .retain(|_, (_, started_at)| now.duration_since(*started_at) < MAX_ACTIVE_WINDOW);
+        self.last_pruned = now;
+    }
+}
+
+// Keeps the sessions started by this process in memory so the live view
+// never has to poll Postgres. Every handle holds on to the sender of the
+// updates, so streams watching them are ended by `shutdown` instead.
+#[derive(Clone)]
+pub struct Realtime {
+    updates: broadcast::Sender<LiveUpdate>,
+    active: Arc<Mutex<ActiveSessions>>,
+    shutdown: watch::Receiver<bool>,
+}
+
+impl Realtime {
+    pub fn new(shutdown: watch::Receiver<bool>) -> Self {
+        let (updates, _) = broadcast::channel(UPDATES_CAPACITY);
+
+        Self {
+            updates,
+            shutdown,
+            active: Arc::new(Mutex::new(ActiveSessions {
+                sessions: HashMap::new(),
+                last_pruned: Instant::now(),
+            })),
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<LiveUpdate> {
+        self.updates.subscribe()
+    }
+
+    // Resolves once the server starts shutting down, or is already gone.
+    pub async fn shutting_down(&self) {
+        let mut shutdown = self.shutdown.clone();
+        let _ = shutdown.wait_for(|shutdown| *shutdown).await;
+    }
+
+    pub fn session_started(&self, session: &NewSessionData, source: Option<String>) {
+        let now = Instant::now();
+        {
+            let mut active = self.active.lock().unwrap();
+            active.prune(now);
+            active.sessions.insert(
+                session.session_id().to_owned(),
+                (session.tracking_id(), now),
+            );
+        }
+
+        // Sending only fails when nobody is watching.
+        let _ = self.updates.send(LiveUpdate::SessionStarted {
+            tracking_id: session.tracking_id(),
+            session: LiveSession {
+                pathname: session.pathname().to_owned(),
+                country: session.country(),
+                source,
+            },
+        });
+    }
+
+    pub fn session_ended(&self, session_id: &str) {
+        let removed = self.active.lock().unwrap().sessions.remove(session_id);
+
+        if let Some((tracking_id, _)) = removed {
+            let _ = self.updates.send(LiveUpdate::SessionEnded { tracking_id });
+        }
+    }
+
+    pub fn active_sessions(&self, tracking_id: i32, window: Duration) -> usize {
+        let now = Instant::now();
+        let active = self.active.lock().unwrap();
+
+        active
+            .sessions
+            .values()
+            .filter(|(id, started_at)| {
+                *id == tracking_id && now.duration_since(*started_at) < window
+            })
+            .count()
+    }
+}
+
+pub fn with_realtime(
+    realtime: Realtime,
+) -> impl Filter<Extract = (Realtime,), Error = Infallible> + Clone {
+    warp::any().map(move || realtime.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::{sync::watch, time::timeout};
+
+    use super::Realtime;
+
+    #[tokio::test]
+    async fn shutting_down_resolves_once_shutdown_is_sent() {
+        let (shutdown, receiver) = watch::channel(false);
+        let realtime = Realtime::new(receiver);
+
+        assert!(timeout(Duration::from_millis(10), realtime.shutting_down())
+            .await
+            .is_err());
+
+        shutdown.send(true).unwrap();
+
+        assert!(timeout(Duration::from_millis(10), realtime.shutting_down())
+            .await
+            .is_ok());
+    }
+}
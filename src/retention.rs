@@ -0,0 +1,92 @@
+use std::time::Duration;
+
+use crate::db::{RetentionPolicy, DB};
+
+const PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const SESSION_VISITORS_BATCH_SIZE: i64 = 100;
+const VISITORS_BATCH_SIZE: i64 = 1000;
+
+// Purges sessions, events and visitors that are older than their tracking's
+// retention policy, once at startup and then every hour. The cookieless salts
+// of previous days go too, whether or not anyone came by since.
+pub fn spawn_purge_task(db: DB) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(PURGE_INTERVAL);
+        loop {
+            interval.tick().await;
+
+            if let Err(e) = db.delete_expired_salts().await {
+                tracing::error!("Error deleting expired daily salts: {}", e);
+            }
+
+            let policies = match db.retention_policies().await {
+                Ok(policies) => policies,
+                Err(e) => {
+                    tracing::error!("Error getting retention policies: {}", e);
+                    continue;
+                }
+            };
+
+            for policy in policies {
+                if let Err(e) = purge(&db, &policy).await {
+                    tracing::error!(
+                        "Error purging expired data of tracking {}: {}",
+                        policy.tracking_id(),
+                        e
+                    );
+                }
+            }
+        }
+    });
+}
+
+// Sessions are purged a day at a time, in batches of visitors whose sessions
+// of that day are folded together, and visitors in batches once none of their
+// sessions are left.
+async fn purge(db: &DB, policy: &RetentionPolicy) -> Result<(), sqlx::Error> {
+    let (mut sessions, mut events, mut visitors) = (0, 0, 0);
+
+    while let Some(day) = db
+        .oldest_expired_day(policy.tracking_id(), policy.days())
+        .await?
+    {
+        let purged = db
+            .purge_sessions(
+                policy.tracking_id(),
+                day,
+                policy.fold(),
+                SESSION_VISITORS_BATCH_SIZE,
+            )
+            .await?;
+        sessions += purged.sessions();
+        events += purged.events();
+    }
+
+    loop {
+        let purged = db
+            .purge_visitors(
+                policy.tracking_id(),
+                policy.days(),
+                policy.fold(),
+                VISITORS_BATCH_SIZE,
+            )
+            .await?;
+        visitors += purged;
+
+        if purged < VISITORS_BATCH_SIZE as u64 {
+            break;
+        }
+    }
+
+    if sessions > 0 || events > 0 || visitors > 0 {
+        tracing::info!(
+            "Purged {} sessions, {} events and {} visitors from tracking {}",
+            sessions,
+            events,
+            visitors,
+            policy.tracking_id()
+        );
+    }
+
+    Ok(())
+}
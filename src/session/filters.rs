@@ -2,15 +2,21 @@ use std::sync::Arc;
 
 use warp::Filter;
 
-use super::handlers::{self, Event, SessionEnd, SessionStart};
-use crate::db::{with_db, DB};
+use super::handlers::{self, BatchItem, Event, Identify, PixelQuery, SessionEnd, SessionStart};
+use crate::{
+    db::{with_db, DB},
+    geoip::GeoIp,
+    ingest::{with_ingest, Ingest},
+    realtime::{with_realtime, Realtime},
+};
 
 pub fn make_session_routes(
     db: DB,
-    ua_parser: uaparser::UserAgentParser,
-    maxmind_reader: Arc<maxminddb::Reader<Vec<u8>>>,
+    ingest: Ingest,
+    realtime: Realtime,
+    ua_parser: Arc<uaparser::UserAgentParser>,
+    maxmind_reader: Arc<GeoIp>,
 ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
-    let ua_parser = Arc::new(ua_parser);
     let ua_parser_filter = warp::any().map(move || ua_parser.clone());
 
     let visitor_id = with_db(db.clone())
@@ -21,19 +27,29 @@ pub fn make_session_routes(
             let (db, source_id) = handlers::extract_source_id(db, tracking_id, source_name).await?;
             Ok::<_, warp::Rejection>((db, source_id, tracking_id))
         })
+        .and(with_ingest(ingest.clone()))
         .and(warp::cookie::optional("visitorId"))
         .and(warp::header("user-agent"))
         .and(warp::header("referer"))
-        .and(ua_parser_filter)
+        .and(warp::addr::remote())
+        .and(ua_parser_filter.clone())
         .and_then(
-            |(db, source_id, tracking_id), visitor_id, user_agent, referer, ua_parser| async move {
+            |(db, source_id, tracking_id),
+             ingest,
+             visitor_id,
+             user_agent,
+             referer,
+             remote_addr,
+             ua_parser| async move {
                 let visitor_id = handlers::extract_visitor_id(
                     db,
+                    ingest,
                     source_id,
                     tracking_id,
                     visitor_id,
                     user_agent,
                     referer,
+                    remote_addr,
                     ua_parser,
                 )
                 .await?;
@@ -41,6 +57,36 @@ pub fn make_session_routes(
             },
         );
 
+    let maxmind_reader_filter = warp::any().map(move || maxmind_reader.clone());
+
+    // Hits sent with `DNT` or `Sec-GPC` are handled by the routes below when
+    // the tracking's opt-out policy says so, and fall through otherwise.
+    let opted_out = warp::header::optional::<String>("dnt")
+        .and(warp::header::optional::<String>("sec-gpc"))
+        .and(with_db(db.clone()))
+        .and(warp::header::optional::<String>("x-tracking-id"))
+        .and_then(handlers::extract_opted_out);
+
+    let opted_out_start = warp::path!("start")
+        .and(warp::post())
+        .and(opted_out.clone())
+        .and(warp::body::json::<SessionStart>())
+        .and_then(handlers::opted_out_session_start);
+    let opted_out_end = warp::path!("end")
+        .and(warp::post())
+        .and(opted_out.clone())
+        .and_then(handlers::opted_out_hit);
+    let opted_out_event = warp::path!("event")
+        .and(warp::post())
+        .and(opted_out.clone())
+        .and_then(handlers::opted_out_hit);
+    let opted_out_batch = warp::path!("batch")
+        .and(warp::post())
+        .and(opted_out)
+        .and(warp::body::content_length_limit(64 * 1024))
+        .and(warp::body::json::<Vec<BatchItem>>())
+        .and_then(handlers::opted_out_batch);
+
     let session_start = warp::path!("start")
         .and(warp::post())
         .and(with_db(db.clone()))
@@ -50,15 +96,27 @@ pub fn make_session_routes(
             Ok::<_, warp::Rejection>((db, tracking_id))
         })
         .and(visitor_id)
+        .and(warp::header::optional::<String>("x-source-name"))
         .and(warp::body::json::<SessionStart>())
         .and(warp::addr::remote())
-        .and(warp::any().map(move || maxmind_reader.clone()))
+        .and(maxmind_reader_filter.clone())
+        .and(with_ingest(ingest.clone()))
+        .and(with_realtime(realtime.clone()))
         .and_then(
-            |(db, tracking_id), visitor_id, session_start, remote_addr, maxmind_reader| async move {
+            |(_, tracking_id),
+             visitor_id,
+             source_name,
+             session_start,
+             remote_addr,
+             maxmind_reader,
+             ingest,
+             realtime| async move {
                 let reply = handlers::session_start(
-                    db,
+                    ingest,
+                    realtime,
                     tracking_id,
                     visitor_id,
+                    source_name,
                     session_start,
                     remote_addr,
                     maxmind_reader,
@@ -68,16 +126,108 @@ pub fn make_session_routes(
             },
         );
 
-    let session_id =
-        warp::cookie::optional::<String>("sessionId").and_then(handlers::extract_session_id);
+    let session_id = warp::cookie::optional::<String>("sessionId")
+        .and(with_db(db.clone()))
+        .and(with_ingest(ingest.clone()))
+        .and(warp::header::optional::<String>("x-tracking-id"))
+        .and(warp::header::optional::<String>("user-agent"))
+        .and(warp::addr::remote())
+        .and_then(handlers::extract_session_id);
 
     let session_end = warp::path!("end")
         .and(warp::post())
-        .and(with_db(db.clone()))
-        .and(session_id)
+        .and(with_ingest(ingest.clone()))
+        .and(with_realtime(realtime.clone()))
+        .and(session_id.clone())
         .and(warp::body::json::<SessionEnd>())
         .and_then(handlers::session_end);
 
+    let session_batch = warp::path!("batch")
+        .and(warp::post())
+        .and(with_db(db.clone()))
+        .and(warp::header("x-tracking-id"))
+        .and_then(|db, tracking_id| async move {
+            let (db, tracking_id) = handlers::extract_tracking_id(db, tracking_id).await?;
+            Ok::<_, warp::Rejection>((db, tracking_id))
+        })
+        .and(
+            warp::cookie::optional::<String>("visitorId")
+                .and(warp::header::<String>("user-agent"))
+                .and(warp::header::<String>("referer"))
+                .map(|visitor_id, user_agent, referer| (visitor_id, user_agent, referer)),
+        )
+        .and(warp::header::optional::<String>("x-source-name"))
+        .and(warp::cookie::optional::<String>("sessionId"))
+        .and(warp::body::content_length_limit(64 * 1024))
+        .and(warp::body::json::<Vec<BatchItem>>())
+        .and(warp::addr::remote())
+        .and(ua_parser_filter.clone())
+        .and(maxmind_reader_filter)
+        .and(with_ingest(ingest.clone()))
+        .and(with_realtime(realtime))
+        .and_then(
+            |(db, tracking_id),
+             visitor,
+             source_name,
+             session_id,
+             items,
+             remote_addr,
+             ua_parser,
+             maxmind_reader,
+             ingest,
+             realtime| async move {
+                let reply = handlers::session_batch(
+                    db,
+                    ingest,
+                    realtime,
+                    tracking_id,
+                    visitor,
+                    source_name,
+                    session_id,
+                    items,
+                    remote_addr,
+                    ua_parser,
+                    maxmind_reader,
+                )
+                .await?;
+                Ok::<_, warp::Rejection>(reply)
+            },
+        );
+
+    let identify = warp::path!("identify")
+        .and(warp::post())
+        .and(with_db(db.clone()))
+        .and(warp::header("x-tracking-id"))
+        .and_then(|db, tracking_id| async move {
+            let (db, tracking_id) = handlers::extract_tracking_id(db, tracking_id).await?;
+            Ok::<_, warp::Rejection>((db, tracking_id))
+        })
+        .and(warp::cookie::optional::<String>("visitorId"))
+        .and(warp::header::optional::<String>("user-agent"))
+        .and(warp::addr::remote())
+        .and_then(
+            |(db, tracking_id), visitor_id, user_agent, remote_addr| async move {
+                let visitor_id = handlers::extract_identified_visitor_id(
+                    &db,
+                    tracking_id,
+                    visitor_id,
+                    user_agent,
+                    remote_addr,
+                )
+                .await?;
+                Ok::<_, warp::Rejection>((db, tracking_id, visitor_id))
+            },
+        )
+        .and(warp::body::json::<Identify>())
+        .and(with_ingest(ingest.clone()))
+        .and_then(
+            |(db, tracking_id, visitor_id), identify, ingest| async move {
+                let reply =
+                    handlers::identify(db, ingest, tracking_id, visitor_id, identify).await?;
+                Ok::<_, warp::Rejection>(reply)
+            },
+        );
+
     let session_event = warp::path!("event")
         .and(warp::post())
         .and(with_db(db))
@@ -88,10 +238,50 @@ pub fn make_session_routes(
         })
         .and(session_id)
         .and(warp::body::json::<Event>())
-        .and_then(|(db, tracking_id), session_id, event| async move {
-            let reply = handlers::session_event(db, session_id, event, tracking_id).await?;
+        .and(with_ingest(ingest))
+        .and_then(|(_, tracking_id), session_id, event, ingest| async move {
+            let reply = handlers::session_event(ingest, session_id, event, tracking_id).await?;
             Ok::<_, warp::Rejection>(reply)
         });
 
-    warp::path("session").and(session_start.or(session_end).or(session_event))
+    let opted_out = opted_out_start
+        .or(opted_out_end)
+        .or(opted_out_event)
+        .or(opted_out_batch);
+
+    warp::path("session").and(
+        opted_out
+            .or(session_start)
+            .or(session_end)
+            .or(session_event)
+            .or(session_batch)
+            .or(identify),
+    )
+}
+
+pub fn make_pixel_route(
+    db: DB,
+    ingest: Ingest,
+    realtime: Realtime,
+    ua_parser: Arc<uaparser::UserAgentParser>,
+    maxmind_reader: Arc<GeoIp>,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("pixel.gif")
+        .and(warp::get())
+        .and(with_db(db))
+        .and(with_ingest(ingest))
+        .and(with_realtime(realtime))
+        .and(warp::query::<PixelQuery>())
+        .and(
+            warp::header::optional("dnt")
+                .and(warp::header::optional("sec-gpc"))
+                .map(|dnt, gpc| (dnt, gpc)),
+        )
+        .and(warp::cookie::optional("visitorId"))
+        .and(warp::header::optional("user-agent"))
+        .and(warp::header::optional("referer"))
+        .and(warp::addr::remote())
+        .and(warp::any().map(move || ua_parser.clone()))
+        .and(warp::any().map(move || maxmind_reader.clone()))
+        .and_then(handlers::pixel)
 }
@@ -1,14 +1,22 @@
-use std::{net::SocketAddr, sync::Arc};
+use std::{collections::HashMap, net::SocketAddr, sync::Arc};
 
-use serde::Deserialize;
+use domain::{valid_event_properties, EventProperties};
+use serde::{Deserialize, Serialize};
+use sqlx::types::chrono::Utc;
 use warp::{
     hyper::{Body, Response, StatusCode},
     reject,
 };
 
 use crate::{
-    db::{NewSessionData, NewVisitorData, DB},
-    errors::{DatabaseError, MissingSessionId},
+    db::{NewSessionData, NewVisitorData, OptOutPolicy, VisitorIdentity, Write, DB},
+    errors::{
+        BatchTooLarge, DatabaseError, InvalidEventProperties, InvalidIdentity, MissingSessionId,
+        MissingVisitorId, UnknownVisitor,
+    },
+    geoip::{locate, GeoIp},
+    ingest::Ingest,
+    realtime::Realtime,
 };
 
 pub async fn extract_source_id(
@@ -44,39 +52,142 @@ pub async fn extract_tracking_id(
     Ok((db, tracking_id))
 }
 
+pub struct Visitor {
+    id: i32,
+    public_id: String,
+    cookieless: bool,
+}
+
+impl Visitor {
+    // Cookieless trackings never hand out cookies, visitors are recognised by
+    // their daily hash instead.
+    fn cookie(&self) -> Option<String> {
+        (!self.cookieless).then(|| format!("visitorId={}; HttpOnly", self.public_id))
+    }
+}
+
+async fn tracking_cookieless(db: &DB, tracking_id: i32) -> Result<bool, reject::Rejection> {
+    db.tracking_cookieless(tracking_id).await.map_err(|e| {
+        tracing::error!("Error getting tracking mode: {}", e);
+        reject::custom(DatabaseError)
+    })
+}
+
+async fn cookieless_visitor_id(
+    db: &DB,
+    tracking_id: i32,
+    user_agent: &str,
+    remote_addr: Option<SocketAddr>,
+) -> Result<String, reject::Rejection> {
+    let ip = remote_addr
+        .map(|addr| addr.ip().to_string())
+        .unwrap_or_default();
+
+    db.cookieless_visitor_id(tracking_id, &ip, user_agent)
+        .await
+        .map_err(|e| {
+            tracing::error!("Error hashing cookieless visitor: {}", e);
+            reject::custom(DatabaseError)
+        })
+}
+
+#[allow(clippy::too_many_arguments)]
 pub async fn extract_visitor_id(
     db: DB,
+    ingest: Ingest,
     source_id: Option<i32>,
     tracking_id: i32,
     visitor_id: Option<String>,
     user_agent: String,
     referer: String,
+    remote_addr: Option<SocketAddr>,
     ua_parser: Arc<uaparser::UserAgentParser>,
-) -> Result<(i32, String), reject::Rejection> {
-    match visitor_id {
-        Some(visitor_id) => {
-            let id = db.id_from_visitor_id(&visitor_id).await.map_err(|e| {
-                tracing::error!("Error getting visitor id: {}", e);
-                reject::custom(DatabaseError)
-            })?;
+) -> Result<Visitor, reject::Rejection> {
+    let cookieless = tracking_cookieless(&db, tracking_id).await?;
 
-            Ok((id, visitor_id))
-        }
-        None => {
-            let new_visitor =
+    // Visitors can be gone by the time they come back, after being erased or
+    // purged by the retention policy, in which case they're created again.
+    let visitor_id = if cookieless {
+        Some(cookieless_visitor_id(&db, tracking_id, &user_agent, remote_addr).await?)
+    } else {
+        visitor_id
+    };
+    let id = match &visitor_id {
+        Some(visitor_id) => find_visitor_id(&db, &ingest, tracking_id, visitor_id).await?,
+        None => None,
+    };
+
+    let (id, public_id) = match (id, visitor_id) {
+        (Some(id), Some(visitor_id)) => (id, visitor_id),
+        (_, visitor_id) => {
+            let mut new_visitor =
                 NewVisitorData::new(user_agent, referer, source_id, ua_parser, tracking_id);
+            if let Some(visitor_id) = visitor_id {
+                new_visitor = new_visitor.with_visitor_id(visitor_id);
+            }
 
-            let id = db.create_visitor(&new_visitor).await.map_err(|e| {
+            let id = db.next_visitor_id().await.map_err(|e| {
                 tracing::error!("Error creating visitor: {}", e);
                 reject::custom(DatabaseError)
             })?;
+            let public_id = new_visitor.visitor_id();
+            ingest.write(vec![Write::Visitor {
+                id,
+                data: new_visitor,
+            }])?;
 
-            Ok((id, new_visitor.visitor_id()))
+            (id, public_id)
         }
+    };
+
+    Ok(Visitor {
+        id,
+        public_id,
+        cookieless,
+    })
+}
+
+// Visitors that are still queued are found before they are written.
+async fn find_visitor_id(
+    db: &DB,
+    ingest: &Ingest,
+    tracking_id: i32,
+    visitor_id: &str,
+) -> Result<Option<i32>, reject::Rejection> {
+    if let Some(id) = ingest.pending_visitor_id(tracking_id, visitor_id) {
+        return Ok(Some(id));
     }
+
+    db.find_visitor_id(tracking_id, visitor_id)
+        .await
+        .map_err(|e| {
+            tracing::error!("Error getting visitor id: {}", e);
+            reject::custom(DatabaseError)
+        })
 }
 
-pub async fn extract_session_id(session_id: Option<String>) -> Result<String, reject::Rejection> {
+// Without a `sessionId` cookie, a cookieless visitor's session is the latest
+// one started by their daily hash.
+pub async fn extract_session_id(
+    session_id: Option<String>,
+    db: DB,
+    ingest: Ingest,
+    tracking_id: Option<String>,
+    user_agent: Option<String>,
+    remote_addr: Option<SocketAddr>,
+) -> Result<String, reject::Rejection> {
+    if let Some(session_id) = session_id {
+        return Ok(session_id);
+    }
+
+    let session_id = match tracking_id {
+        Some(tracking_id) => {
+            let (db, tracking_id) = extract_tracking_id(db, tracking_id).await?;
+            cookieless_session_id(&db, &ingest, tracking_id, user_agent, remote_addr).await?
+        }
+        None => None,
+    };
+
     let session_id = session_id.ok_or_else(|| {
         tracing::error!("Missing session id");
         reject::custom(MissingSessionId)
@@ -85,6 +196,128 @@ pub async fn extract_session_id(session_id: Option<String>) -> Result<String, re
     Ok(session_id)
 }
 
+async fn cookieless_session_id(
+    db: &DB,
+    ingest: &Ingest,
+    tracking_id: i32,
+    user_agent: Option<String>,
+    remote_addr: Option<SocketAddr>,
+) -> Result<Option<String>, reject::Rejection> {
+    if !tracking_cookieless(db, tracking_id).await? {
+        return Ok(None);
+    }
+
+    let visitor_id = cookieless_visitor_id(
+        db,
+        tracking_id,
+        &user_agent.unwrap_or_default(),
+        remote_addr,
+    )
+    .await?;
+    let id = find_visitor_id(db, ingest, tracking_id, &visitor_id).await?;
+
+    match id {
+        Some(id) => latest_session_id(db, ingest, id).await,
+        None => Ok(None),
+    }
+}
+
+// A session that is still queued is always more recent than the written ones.
+async fn latest_session_id(
+    db: &DB,
+    ingest: &Ingest,
+    visitor_id: i32,
+) -> Result<Option<String>, reject::Rejection> {
+    if let Some(session_id) = ingest.pending_latest_session_id(visitor_id) {
+        return Ok(Some(session_id));
+    }
+
+    db.latest_session_id(visitor_id).await.map_err(|e| {
+        tracing::error!("Error getting latest session: {}", e);
+        reject::custom(DatabaseError)
+    })
+}
+
+// Cookieless visitors have no `visitorId` cookie, so they're found by the
+// same hash their sessions were started with.
+pub async fn extract_identified_visitor_id(
+    db: &DB,
+    tracking_id: i32,
+    visitor_id: Option<String>,
+    user_agent: Option<String>,
+    remote_addr: Option<SocketAddr>,
+) -> Result<String, reject::Rejection> {
+    if tracking_cookieless(db, tracking_id).await? {
+        return cookieless_visitor_id(
+            db,
+            tracking_id,
+            &user_agent.unwrap_or_default(),
+            remote_addr,
+        )
+        .await;
+    }
+
+    let visitor_id = visitor_id.ok_or_else(|| {
+        tracing::error!("Missing visitor id");
+        reject::custom(MissingVisitorId)
+    })?;
+
+    Ok(visitor_id)
+}
+
+fn opt_out_signal(dnt: Option<&str>, gpc: Option<&str>) -> bool {
+    dnt.is_some_and(|dnt| dnt.trim() == "1") || gpc.is_some_and(|gpc| gpc.trim() == "1")
+}
+
+// Only succeeds for hits that should be suppressed, so that everything else
+// falls through to the regular routes.
+pub async fn extract_opted_out(
+    dnt: Option<String>,
+    gpc: Option<String>,
+    db: DB,
+    tracking_id: Option<String>,
+) -> Result<(DB, i32, OptOutPolicy), reject::Rejection> {
+    let Some(tracking_id) = tracking_id else {
+        return Err(reject::not_found());
+    };
+    if !opt_out_signal(dnt.as_deref(), gpc.as_deref()) {
+        return Err(reject::not_found());
+    }
+
+    let (db, tracking_id) = extract_tracking_id(db, tracking_id).await?;
+    let policy = tracking_opt_out_policy(&db, tracking_id).await?;
+    if policy == OptOutPolicy::Ignore {
+        return Err(reject::not_found());
+    }
+
+    Ok((db, tracking_id, policy))
+}
+
+async fn tracking_opt_out_policy(
+    db: &DB,
+    tracking_id: i32,
+) -> Result<OptOutPolicy, reject::Rejection> {
+    db.tracking_opt_out_policy(tracking_id).await.map_err(|e| {
+        tracing::error!("Error getting opt-out policy: {}", e);
+        reject::custom(DatabaseError)
+    })
+}
+
+async fn record_opted_out_hits(
+    db: &DB,
+    tracking_id: i32,
+    pathname: Option<&str>,
+    suppressed: i64,
+    page_views: i64,
+) -> Result<(), reject::Rejection> {
+    db.record_opted_out_hits(tracking_id, pathname, suppressed, page_views)
+        .await
+        .map_err(|e| {
+            tracing::error!("Error recording opted out hits: {}", e);
+            reject::custom(DatabaseError)
+        })
+}
+
 #[derive(Deserialize)]
 pub struct SessionStart {
     timestamp: f64,
@@ -93,10 +326,13 @@ pub struct SessionStart {
     referral: Option<String>,
 }
 
+#[allow(clippy::too_many_arguments)]
 pub async fn session_start(
-    db: DB,
+    ingest: Ingest,
+    realtime: Realtime,
     tracking_id: i32,
-    (visitor_id, visitor_id_public): (i32, String),
+    visitor: Visitor,
+    source_name: Option<String>,
     SessionStart {
         timestamp,
         title,
@@ -104,49 +340,71 @@ pub async fn session_start(
         referral,
     }: SessionStart,
     remote_addr: Option<SocketAddr>,
-    maxmind_reader: Arc<maxminddb::Reader<Vec<u8>>>,
+    maxmind_reader: Arc<GeoIp>,
 ) -> Result<impl warp::Reply, reject::Rejection> {
     tracing::info!("session-start");
 
     let new_session = NewSessionData::new(
-        visitor_id,
+        visitor.id,
         timestamp,
         title,
         pathname,
         referral,
         tracking_id,
-        remote_addr,
-        maxmind_reader,
+        locate(&maxmind_reader, remote_addr).await,
     );
 
-    db.create_session(&new_session).await.map_err(|e| {
-        tracing::error!("Error creating session: {}", e);
-        reject::custom(DatabaseError)
-    })?;
+    ingest.write(vec![Write::Session(new_session.clone())])?;
+    realtime.session_started(&new_session, source_name);
 
-    let resp = Response::builder()
-        .status(StatusCode::OK)
-        .header(
-            "Set-Cookie",
-            format!("visitorId={}; HttpOnly", &visitor_id_public,),
-        )
-        .header(
+    let mut resp = Response::builder().status(StatusCode::OK);
+    if let Some(cookie) = visitor.cookie() {
+        resp = resp.header("Set-Cookie", cookie).header(
             "Set-Cookie",
             format!("sessionId={}; HttpOnly", new_session.session_id()),
-        )
-        .body(Body::empty())
-        .unwrap();
+        );
+    }
+    let resp = resp.body(Body::empty()).unwrap();
 
     Ok(resp)
 }
 
+// With the anonymous policy a session start still counts as a page view, but
+// no visitor, session or cookie is created for it.
+pub async fn opted_out_session_start(
+    (db, tracking_id, policy): (DB, i32, OptOutPolicy),
+    session_start: SessionStart,
+) -> Result<impl warp::Reply, reject::Rejection> {
+    tracing::info!("session-start: opted out");
+
+    match policy {
+        OptOutPolicy::Anonymous => {
+            record_opted_out_hits(&db, tracking_id, Some(&session_start.pathname), 1, 1).await?
+        }
+        _ => record_opted_out_hits(&db, tracking_id, None, 1, 0).await?,
+    }
+
+    Ok(warp::reply())
+}
+
+pub async fn opted_out_hit(
+    (db, tracking_id, _): (DB, i32, OptOutPolicy),
+) -> Result<impl warp::Reply, reject::Rejection> {
+    tracing::info!("session hit: opted out");
+
+    record_opted_out_hits(&db, tracking_id, None, 1, 0).await?;
+
+    Ok(warp::reply())
+}
+
 #[derive(Deserialize)]
 pub struct SessionEnd {
     timestamp: f64,
 }
 
 pub async fn session_end(
-    db: DB,
+    ingest: Ingest,
+    realtime: Realtime,
     session_id: String,
     SessionEnd { timestamp }: SessionEnd,
 ) -> Result<impl warp::Reply, reject::Rejection> {
@@ -154,10 +412,12 @@ pub async fn session_end(
     tracing::info!("session_id: {}", session_id);
     tracing::info!("timestamp: {}", timestamp);
 
-    db.end_session(&session_id, timestamp).await.map_err(|e| {
-        tracing::error!("Error ending session: {}", e);
-        reject::custom(DatabaseError)
-    })?;
+    ingest.write(vec![Write::End {
+        session_id: session_id.clone(),
+        timestamp,
+        tracking_id: None,
+    }])?;
+    realtime.session_ended(&session_id);
 
     Ok(warp::reply::with_header(
         warp::reply(),
@@ -171,10 +431,21 @@ pub struct Event {
     #[serde(rename = "type")]
     _type: String,
     target: String,
+    properties: Option<EventProperties>,
+}
+
+// The same limits as events sent through the server ingestion API.
+fn validate_event_properties(
+    properties: Option<EventProperties>,
+) -> Result<Option<serde_json::Value>, InvalidEventProperties> {
+    match properties {
+        Some(properties) if !valid_event_properties(&properties) => Err(InvalidEventProperties),
+        properties => Ok(properties.map(serde_json::Value::Object)),
+    }
 }
 
 pub async fn session_event(
-    db: DB,
+    ingest: Ingest,
     session_id: String,
     event: Event,
     tracking_id: i32,
@@ -182,12 +453,497 @@ pub async fn session_event(
     tracing::info!("session-event");
     tracing::info!("session_id: {}", session_id);
 
-    db.create_event(&session_id, &event._type, &event.target, tracking_id)
+    let properties = validate_event_properties(event.properties).map_err(|e| {
+        tracing::error!("Invalid event properties");
+        reject::custom(e)
+    })?;
+
+    ingest.write(vec![Write::Event {
+        session_id,
+        event_type: event._type,
+        event_target: event.target,
+        properties,
+        tracking_id,
+    }])?;
+
+    Ok(warp::reply())
+}
+
+#[derive(Deserialize)]
+pub struct Identify {
+    email: Option<String>,
+    name: Option<String>,
+    traits: Option<EventProperties>,
+}
+
+const MAX_EMAIL_LENGTH: usize = 320;
+const MAX_NAME_LENGTH: usize = 255;
+
+// Traits follow the same rules as event properties.
+fn validate_identity(identify: Identify) -> Result<VisitorIdentity, InvalidIdentity> {
+    let email = identify.email.map(|email| email.trim().to_owned());
+    let name = identify.name.map(|name| name.trim().to_owned());
+
+    if let Some(email) = &email {
+        let has_parts = email
+            .split_once('@')
+            .is_some_and(|(local, domain)| !local.is_empty() && !domain.is_empty());
+        let valid = has_parts
+            && email.chars().count() <= MAX_EMAIL_LENGTH
+            && !email.contains(char::is_whitespace);
+        if !valid {
+            return Err(InvalidIdentity);
+        }
+    }
+
+    if let Some(name) = &name {
+        if name.is_empty() || name.chars().count() > MAX_NAME_LENGTH {
+            return Err(InvalidIdentity);
+        }
+    }
+
+    let traits = validate_event_properties(identify.traits).map_err(|_| InvalidIdentity)?;
+
+    if email.is_none() && name.is_none() && traits.is_none() {
+        return Err(InvalidIdentity);
+    }
+
+    Ok(VisitorIdentity::new(email, name, traits))
+}
+
+// A visitor that is still queued can't be updated yet, so its identity is
+// queued after it instead.
+pub async fn identify(
+    db: DB,
+    ingest: Ingest,
+    tracking_id: i32,
+    visitor_id: String,
+    identify: Identify,
+) -> Result<impl warp::Reply, reject::Rejection> {
+    tracing::info!("identify");
+    tracing::info!("visitor_id: {}", visitor_id);
+
+    let identity = validate_identity(identify).map_err(|e| {
+        tracing::error!("Invalid visitor identity");
+        reject::custom(e)
+    })?;
+
+    if let Some(id) = ingest.pending_visitor_id(tracking_id, &visitor_id) {
+        ingest.write(vec![Write::Identify { id, identity }])?;
+        return Ok(warp::reply());
+    }
+
+    let identified = db
+        .identify_visitor(tracking_id, &visitor_id, &identity)
         .await
         .map_err(|e| {
-            tracing::error!("Error creating event: {}", e);
+            tracing::error!("Error identifying visitor: {}", e);
             reject::custom(DatabaseError)
         })?;
 
+    if !identified {
+        tracing::error!(
+            "Visitor {} not found in tracking {}",
+            visitor_id,
+            tracking_id
+        );
+        return Err(reject::custom(UnknownVisitor));
+    }
+
     Ok(warp::reply())
 }
+
+// A 1x1 transparent GIF.
+const PIXEL_GIF: &[u8] = &[
+    0x47, 0x49, 0x46, 0x38, 0x39, 0x61, 0x01, 0x00, 0x01, 0x00, 0x80, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0xff, 0xff, 0xff, 0x21, 0xf9, 0x04, 0x01, 0x00, 0x00, 0x00, 0x00, 0x2c, 0x00, 0x00, 0x00, 0x00,
+    0x01, 0x00, 0x01, 0x00, 0x00, 0x02, 0x02, 0x44, 0x01, 0x00, 0x3b,
+];
+
+#[derive(Deserialize)]
+pub struct PixelQuery {
+    t: String,
+    p: Option<String>,
+    src: Option<String>,
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn pixel(
+    db: DB,
+    ingest: Ingest,
+    realtime: Realtime,
+    PixelQuery { t, p, src }: PixelQuery,
+    (dnt, gpc): (Option<String>, Option<String>),
+    visitor_id: Option<String>,
+    user_agent: Option<String>,
+    referer: Option<String>,
+    remote_addr: Option<SocketAddr>,
+    ua_parser: Arc<uaparser::UserAgentParser>,
+    maxmind_reader: Arc<GeoIp>,
+) -> Result<impl warp::Reply, reject::Rejection> {
+    tracing::info!("pixel");
+
+    let (db, tracking_id) = extract_tracking_id(db, t).await?;
+
+    if opt_out_signal(dnt.as_deref(), gpc.as_deref()) {
+        match tracking_opt_out_policy(&db, tracking_id).await? {
+            OptOutPolicy::Ignore => {}
+            OptOutPolicy::Drop => {
+                record_opted_out_hits(&db, tracking_id, None, 1, 0).await?;
+                return Ok(pixel_response(None));
+            }
+            OptOutPolicy::Anonymous => {
+                let pathname = p.as_deref().unwrap_or("/");
+                record_opted_out_hits(&db, tracking_id, Some(pathname), 1, 1).await?;
+                return Ok(pixel_response(None));
+            }
+        }
+    }
+
+    // An unknown `src` shouldn't cost us the hit, so it is recorded as direct traffic.
+    let source_id = match &src {
+        Some(src) => match db.id_from_source_name(tracking_id, src).await {
+            Ok(id) => Some(id),
+            Err(sqlx::Error::RowNotFound) => None,
+            Err(e) => {
+                tracing::error!("Error getting source id: {}", e);
+                return Err(reject::custom(DatabaseError));
+            }
+        },
+        None => None,
+    };
+
+    let visitor = extract_visitor_id(
+        db,
+        ingest.clone(),
+        source_id,
+        tracking_id,
+        visitor_id,
+        user_agent.unwrap_or_default(),
+        referer.unwrap_or_default(),
+        remote_addr,
+        ua_parser,
+    )
+    .await?;
+
+    let timestamp = Utc::now().timestamp_millis() as f64 / 1000.0;
+    let new_session = NewSessionData::new(
+        visitor.id,
+        timestamp,
+        String::new(),
+        p.unwrap_or_else(|| "/".to_owned()),
+        None,
+        tracking_id,
+        locate(&maxmind_reader, remote_addr).await,
+    );
+
+    ingest.write(vec![
+        Write::Session(new_session.clone()),
+        Write::End {
+            session_id: new_session.session_id().to_owned(),
+            timestamp,
+            tracking_id: Some(tracking_id),
+        },
+    ])?;
+    realtime.session_started(&new_session, src.filter(|_| source_id.is_some()));
+    realtime.session_ended(new_session.session_id());
+
+    Ok(pixel_response(visitor.cookie()))
+}
+
+fn pixel_response(cookie: Option<String>) -> Response<Body> {
+    let mut resp = Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "image/gif")
+        .header("Content-Length", PIXEL_GIF.len())
+        .header("Cache-Control", "no-cache, no-store, must-revalidate")
+        .header("Pragma", "no-cache")
+        .header("Expires", "0");
+    if let Some(cookie) = cookie {
+        resp = resp.header("Set-Cookie", cookie);
+    }
+
+    resp.body(Body::from(PIXEL_GIF)).unwrap()
+}
+
+const MAX_BATCH_ITEMS: usize = 100;
+
+#[derive(Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum BatchItem {
+    Start(SessionStart),
+    End {
+        timestamp: f64,
+        session_id: Option<String>,
+    },
+    Event {
+        #[serde(rename = "type")]
+        _type: String,
+        target: String,
+        properties: Option<EventProperties>,
+        session_id: Option<String>,
+    },
+}
+
+#[derive(Serialize)]
+#[serde(tag = "status", rename_all = "lowercase")]
+pub enum BatchItemResult {
+    Ok { session_id: String },
+    Error { message: &'static str },
+    Suppressed,
+}
+
+#[derive(Serialize)]
+struct BatchResponse {
+    results: Vec<BatchItemResult>,
+}
+
+pub async fn opted_out_batch(
+    (db, tracking_id, policy): (DB, i32, OptOutPolicy),
+    items: Vec<BatchItem>,
+) -> Result<impl warp::Reply, reject::Rejection> {
+    tracing::info!("session-batch: {} items opted out", items.len());
+
+    if items.len() > MAX_BATCH_ITEMS {
+        tracing::error!("Batch has too many items: {}", items.len());
+        return Err(reject::custom(BatchTooLarge));
+    }
+
+    let mut page_views = HashMap::<String, i64>::new();
+    let mut suppressed = 0;
+    for item in &items {
+        match item {
+            BatchItem::Start(start) if policy == OptOutPolicy::Anonymous => {
+                *page_views.entry(start.pathname.to_owned()).or_default() += 1;
+            }
+            _ => suppressed += 1,
+        }
+    }
+
+    for (pathname, page_views) in &page_views {
+        record_opted_out_hits(&db, tracking_id, Some(pathname), *page_views, *page_views).await?;
+    }
+    if suppressed > 0 {
+        record_opted_out_hits(&db, tracking_id, None, suppressed, 0).await?;
+    }
+
+    let results = items.iter().map(|_| BatchItemResult::Suppressed).collect();
+
+    Ok(warp::reply::json(&BatchResponse { results }))
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn session_batch(
+    db: DB,
+    ingest: Ingest,
+    realtime: Realtime,
+    tracking_id: i32,
+    (visitor_id, user_agent, referer): (Option<String>, String, String),
+    source_name: Option<String>,
+    cookie_session_id: Option<String>,
+    items: Vec<BatchItem>,
+    remote_addr: Option<SocketAddr>,
+    ua_parser: Arc<uaparser::UserAgentParser>,
+    maxmind_reader: Arc<GeoIp>,
+) -> Result<impl warp::Reply, reject::Rejection> {
+    tracing::info!("session-batch: {} items", items.len());
+
+    if items.len() > MAX_BATCH_ITEMS {
+        tracing::error!("Batch has too many items: {}", items.len());
+        return Err(reject::custom(BatchTooLarge));
+    }
+
+    // Only batches that start a session need a visitor, so none is looked up
+    // or created for batches of ends and events.
+    let starts_session = items.iter().any(|item| matches!(item, BatchItem::Start(_)));
+    let visitor = if starts_session {
+        let (db, source_id) =
+            extract_source_id(db.clone(), tracking_id, source_name.clone()).await?;
+        let visitor = extract_visitor_id(
+            db,
+            ingest.clone(),
+            source_id,
+            tracking_id,
+            visitor_id,
+            user_agent.clone(),
+            referer,
+            remote_addr,
+            ua_parser,
+        )
+        .await?;
+        Some(visitor)
+    } else {
+        None
+    };
+    let cookieless = match &visitor {
+        Some(visitor) => visitor.cookieless,
+        None => tracking_cookieless(&db, tracking_id).await?,
+    };
+
+    // End and event items that don't name a session refer to the last session
+    // started earlier in the batch, or to the one in the `sessionId` cookie.
+    let mut current_session_id = match (cookie_session_id, &visitor) {
+        (Some(session_id), _) => Some(session_id),
+        (None, Some(visitor)) if cookieless => latest_session_id(&db, &ingest, visitor.id).await?,
+        (None, None) if cookieless => {
+            cookieless_session_id(&db, &ingest, tracking_id, Some(user_agent), remote_addr).await?
+        }
+        (None, _) => None,
+    };
+    let mut started_in_batch = Vec::new();
+    let mut planned = Vec::with_capacity(items.len());
+
+    for item in items {
+        let operation = match item {
+            BatchItem::Start(SessionStart {
+                timestamp,
+                title,
+                pathname,
+                referral,
+            }) => match &visitor {
+                Some(visitor) => {
+                    let new_session = NewSessionData::new(
+                        visitor.id,
+                        timestamp,
+                        title,
+                        pathname,
+                        referral,
+                        tracking_id,
+                        locate(&maxmind_reader, remote_addr).await,
+                    );
+                    current_session_id = Some(new_session.session_id().to_owned());
+                    started_in_batch.push(new_session.session_id().to_owned());
+                    Ok(Write::Session(new_session))
+                }
+                None => Err("MISSING_VISITOR_ID"),
+            },
+            BatchItem::End {
+                timestamp,
+                session_id,
+            } => session_id
+                .or_else(|| current_session_id.clone())
+                .map(|session_id| {
+                    if current_session_id.as_deref() == Some(session_id.as_str()) {
+                        current_session_id = None;
+                    }
+                    Write::End {
+                        session_id,
+                        timestamp,
+                        tracking_id: Some(tracking_id),
+                    }
+                })
+                .ok_or("MISSING_SESSION_ID"),
+            BatchItem::Event {
+                _type,
+                target,
+                properties,
+                session_id,
+            } => match validate_event_properties(properties) {
+                Ok(properties) => session_id
+                    .or_else(|| current_session_id.clone())
+                    .map(|session_id| Write::Event {
+                        session_id,
+                        event_type: _type,
+                        event_target: target,
+                        properties,
+                        tracking_id,
+                    })
+                    .ok_or("MISSING_SESSION_ID"),
+                Err(_) => Err("INVALID_EVENT_PROPERTIES"),
+            },
+        };
+        planned.push(operation);
+    }
+
+    let referenced = planned
+        .iter()
+        .filter_map(|operation| match operation {
+            Ok(Write::End { session_id, .. }) | Ok(Write::Event { session_id, .. })
+                if !started_in_batch.contains(session_id)
+                    && !ingest.is_pending_session(tracking_id, session_id) =>
+            {
+                Some(session_id.to_owned())
+            }
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    let known = db
+        .existing_session_ids(tracking_id, &referenced)
+        .await
+        .map_err(|e| {
+            tracing::error!("Error checking batch sessions: {}", e);
+            reject::custom(DatabaseError)
+        })?;
+
+    let mut results = Vec::with_capacity(planned.len());
+    let mut operations = Vec::with_capacity(planned.len());
+
+    for operation in planned {
+        let result = match &operation {
+            Err(message) => BatchItemResult::Error { message },
+            Ok(Write::Session(data)) => BatchItemResult::Ok {
+                session_id: data.session_id().to_owned(),
+            },
+            Ok(Write::End { session_id, .. }) | Ok(Write::Event { session_id, .. }) => {
+                if started_in_batch.contains(session_id)
+                    || known.contains(session_id)
+                    || ingest.is_pending_session(tracking_id, session_id)
+                {
+                    BatchItemResult::Ok {
+                        session_id: session_id.to_owned(),
+                    }
+                } else {
+                    BatchItemResult::Error {
+                        message: "UNKNOWN_SESSION_ID",
+                    }
+                }
+            }
+            Ok(Write::Visitor { .. }) | Ok(Write::Identify { .. }) => unreachable!(),
+        };
+
+        if let (BatchItemResult::Ok { .. }, Ok(operation)) = (&result, operation) {
+            operations.push(operation);
+        }
+        results.push(result);
+    }
+
+    // Sessions are always started before they are ended, so the live view is
+    // told about every start first.
+    let mut started = Vec::new();
+    let mut ended = Vec::new();
+    for operation in &operations {
+        match operation {
+            Write::Session(data) => started.push(data.clone()),
+            Write::End { session_id, .. } => ended.push(session_id.to_owned()),
+            Write::Visitor { .. } | Write::Event { .. } | Write::Identify { .. } => {}
+        }
+    }
+
+    ingest.write(operations)?;
+
+    for data in &started {
+        realtime.session_started(data, source_name.clone());
+    }
+    for session_id in &ended {
+        realtime.session_ended(session_id);
+    }
+
+    let session_cookie = match current_session_id {
+        Some(session_id) => format!("sessionId={}; HttpOnly", session_id),
+        None => "sessionId=; HttpOnly; Max-Age=0".to_owned(),
+    };
+    let body = serde_json::to_string(&BatchResponse { results }).unwrap();
+
+    let mut resp = Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/json");
+    if let Some(cookie) = visitor.as_ref().and_then(Visitor::cookie) {
+        resp = resp.header("Set-Cookie", cookie);
+    }
+    if !cookieless {
+        resp = resp.header("Set-Cookie", session_cookie);
+    }
+    let resp = resp.body(Body::from(body)).unwrap();
+
+    Ok(resp)
+}
@@ -1,4 +1,4 @@
 mod filters;
 pub mod handlers;
 
-pub use filters::make_session_routes;
+pub use filters::{make_pixel_route, make_session_routes};
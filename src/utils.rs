@@ -3,3 +3,13 @@ use ulid::Ulid;
 pub fn generate_id() -> String {
     Ulid::new().to_string()
 }
+
+// Only the random part of the ULIDs is used, so that the prefix kept to tell
+// keys apart gives away neither the key nor when it was created.
+pub fn generate_api_key() -> String {
+    format!(
+        "trantor_{:020x}{:020x}",
+        Ulid::new().random(),
+        Ulid::new().random()
+    )
+}
@@ -0,0 +1,23 @@
+-- A tracking with two sources, three visitors and their sessions and events,
+-- all far enough in the past to be purged by any retention policy.
+INSERT INTO users (id, user_id, secret_code) VALUES (1, '01H4Y0000000000000000USER1', 'secret');
+
+INSERT INTO trackings (id, tracking_id, name, owner_id) VALUES (1, '01H4Y00000000000000TRACK1', 'Blog', 1);
+
+INSERT INTO sources (id, name, tracking_id) VALUES (1, 'twitter', 1), (2, 'telegram', 1);
+
+INSERT INTO visitors (id, visitor_id, referer, user_agent_parsed, source_id, tracking_id, created_at) VALUES
+  (1, '01H4Y000000000000VISITOR1', 'https://t.co/', '{"device": {"family": "iPhone"}, "os": {"family": "iOS"}, "user_agent": {"family": "Mobile Safari"}}', 1, 1, '2023-06-01 09:15:00'),
+  (2, '01H4Y000000000000VISITOR2', '', '{"device": {"family": "Other"}, "os": {"family": "Linux"}, "user_agent": {"family": "Firefox"}}', 2, 1, '2023-06-01 10:05:00'),
+  (3, '01H4Y000000000000VISITOR3', 'https://www.google.com/', '{"device": {"family": "Other"}, "os": {"family": "Windows"}, "user_agent": {"family": "Chrome"}}', NULL, 1, '2023-06-02 18:30:00');
+
+INSERT INTO sessions (id, session_id, visitor_id, start_timestamp, end_timestamp, title, pathname, referral, location, tracking_id) VALUES
+  (1, '01H4Y000000000000SESSION1', 1, '2023-06-01 09:15:00', '2023-06-01 09:17:00', 'Home', '/', 'twitter.com', '{"country": {"iso_code": "DE", "names": {"en": "Germany"}}}', 1),
+  (2, '01H4Y000000000000SESSION2', 1, '2023-06-02 11:00:00', NULL, 'Pricing', '/pricing', NULL, '{"country": {"iso_code": "DE", "names": {"en": "Germany"}}}', 1),
+  (3, '01H4Y000000000000SESSION3', 2, '2023-06-01 10:05:00', '2023-06-01 10:05:30', 'Home', '/', NULL, NULL, 1),
+  (4, '01H4Y000000000000SESSION4', 3, '2023-06-02 18:30:00', '2023-06-02 18:45:00', 'Home', '/', 'google.com', '{"country": {"iso_code": "FR", "names": {"en": "France"}}}', 1);
+
+INSERT INTO events (session_id, type, target, created_at, tracking_id) VALUES
+  (1, 'click', 'signup', '2023-06-01 09:16:00', 1),
+  (2, 'click', 'plans', '2023-06-02 11:01:00', 1),
+  (4, 'submit', 'newsletter', '2023-06-02 18:40:00', 1);
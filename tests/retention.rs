@@ -0,0 +1,90 @@
+use serde::Serialize;
+use serde_json::{json, Value};
+use sqlx::PgPool;
+use trantor::db::{DateRange, Interval, Segment, DB};
+
+// Rows are compared as JSON, in a stable order.
+fn sorted<T: Serialize>(rows: Vec<T>) -> Vec<Value> {
+    let mut rows: Vec<Value> = rows
+        .into_iter()
+        .map(|row| serde_json::to_value(row).unwrap())
+        .collect();
+    rows.sort_by_key(|row| row.to_string());
+    rows
+}
+
+async fn breakdowns(db: &DB, day: &str) -> Value {
+    let range: DateRange = serde_json::from_value(json!({ "from": day, "to": day })).unwrap();
+    let segment = Segment::default();
+
+    json!({
+        "pathnames": sorted(db.count_sessions_by_pathname(1, &range, &segment).await.unwrap()),
+        "countries": sorted(db.count_sessions_by_country(1, &range, &segment).await.unwrap()),
+        "referrals": sorted(db.count_sessions_by_referral(1, &range, &segment).await.unwrap()),
+        "refers": sorted(db.list_refers(1, &range, &segment).await.unwrap()),
+        "sources": sorted(db.list_sources(1, &range, &segment).await.unwrap()),
+        "no_source": db.visitors_and_sessions_no_source(1, &range, &segment).await.unwrap(),
+        "devices": sorted(db.count_visitors_by_device(1, &range, &segment).await.unwrap()),
+        "oses": sorted(db.count_visitors_by_os(1, &range, &segment).await.unwrap()),
+        "browsers": sorted(db.count_visitors_by_browser(1, &range, &segment).await.unwrap()),
+        "visitors_by_hour": sorted(db.count_visitors_by_hour(1, &range, &segment).await.unwrap()),
+        "visitors_by_weekday": sorted(db.count_visitors_by_weekday(1, &range, &segment).await.unwrap()),
+        "sessions_by_hour": sorted(db.count_sessions_by_hour(1, &range, &segment).await.unwrap()),
+        "events_by_type": sorted(db.count_events_by_type(1, &range, &segment).await.unwrap()),
+        "events_by_day": sorted(db.count_events_by_day(1, &range, &segment).await.unwrap()),
+        "time_series": db.time_series(1, &range, &segment, Interval::Day).await.unwrap(),
+    })
+}
+
+// The same steps as the hourly purge task.
+async fn purge(db: &DB, days: i32, fold: bool) {
+    while let Some(day) = db.oldest_expired_day(1, days).await.unwrap() {
+        db.purge_sessions(1, day, fold, 100).await.unwrap();
+    }
+    while db.purge_visitors(1, days, fold, 1000).await.unwrap() > 0 {}
+}
+
+async fn count(pool: &PgPool, table: &str) -> i64 {
+    let (count,): (i64,) = sqlx::query_as(&format!("SELECT COUNT(*) FROM {}", table))
+        .fetch_one(pool)
+        .await
+        .unwrap();
+    count
+}
+
+// Folded visitors are counted per day, so the days are compared one at a time.
+// Over both days the first visitor, who came on each, would be counted twice.
+#[sqlx::test(fixtures("tracking"))]
+async fn folding_a_purge_keeps_the_breakdowns(pool: PgPool) {
+    let db = DB::new(pool.clone());
+    let days = ["2023-06-01", "2023-06-02"];
+    let mut before = Vec::new();
+    for day in days {
+        before.push(breakdowns(&db, day).await);
+    }
+
+    purge(&db, 30, true).await;
+
+    assert_eq!(count(&pool, "sessions").await, 0);
+    assert_eq!(count(&pool, "visitors").await, 0);
+    assert_eq!(count(&pool, "events").await, 0);
+    for (day, before) in days.into_iter().zip(before) {
+        assert_eq!(breakdowns(&db, day).await, before, "{}", day);
+    }
+}
+
+#[sqlx::test(fixtures("tracking"))]
+async fn folded_history_survives_a_timezone_change(pool: PgPool) {
+    let db = DB::new(pool.clone());
+    purge(&db, 30, true).await;
+
+    db.set_tracking_timezone(1, "Asia/Tokyo").await.unwrap();
+
+    let (sessions,): (i64,) = sqlx::query_as(
+        "SELECT SUM(sessions)::BIGINT FROM rollups WHERE tracking_id = 1 AND dimension = 'path'",
+    )
+    .fetch_one(&pool)
+    .await
+    .unwrap();
+    assert_eq!(sessions, 4);
+}
@@ -0,0 +1,42 @@
+use sqlx::PgPool;
+use trantor::db::DB;
+
+async fn source_rollups(pool: &PgPool) -> Vec<(String, i64, i64)> {
+    sqlx::query_as(
+        r#"
+        SELECT value, SUM(visitors)::BIGINT, SUM(sessions)::BIGINT FROM rollups
+        WHERE tracking_id = 1 AND dimension = 'source'
+        GROUP BY value
+        ORDER BY value
+    "#,
+    )
+    .fetch_all(pool)
+    .await
+    .unwrap()
+}
+
+#[sqlx::test(fixtures("tracking"))]
+async fn deleting_a_source_clears_its_rollups(pool: PgPool) {
+    assert_eq!(
+        source_rollups(&pool).await,
+        vec![
+            ("".to_owned(), 1, 1),
+            ("1".to_owned(), 1, 2),
+            ("2".to_owned(), 1, 1),
+        ]
+    );
+
+    DB::new(pool.clone())
+        .delete_source("twitter", 1)
+        .await
+        .unwrap();
+
+    assert_eq!(
+        source_rollups(&pool).await,
+        vec![
+            ("".to_owned(), 1, 1),
+            ("1".to_owned(), 0, 0),
+            ("2".to_owned(), 1, 1),
+        ]
+    );
+}